@@ -0,0 +1,269 @@
+//! Running an application without writing the event loop.
+//!
+//! Implement `App` and pass it to `run`, which creates the window and the
+//! renderer, keeps `Input` and `time` up to date, resizes the renderer and
+//! the camera with the window, paces frames to the frame rate cap and calls
+//! the hooks. Alt+Enter toggles fullscreen, Escape releases the cursor and
+//! clicking into the window grabs it again. `run_headless` renders a number
+//! of frames offscreen instead, for tests.
+use crate::camera::{Camera, Perspective};
+use crate::render::{Render, RenderBuilder, RenderSettings};
+use crate::time;
+use crate::transform::Transform;
+use crate::window::{self, FullscreenMode, WindowConfig};
+use crate::Input;
+use anyhow::Context as _;
+use glam::{Vec2, Vec3};
+use std::sync::{Arc, Once};
+use std::time::Instant;
+use winit::event::{DeviceEvent, ElementState, Event, MouseButton, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::keyboard::{KeyCode, PhysicalKey};
+use winit::window::CursorGrabMode;
+
+/// The hooks `run` calls. Only `render` is required.
+pub trait App {
+    /// Called once before the first frame, to add materials and load
+    /// meshes. An error ends `run` before the event loop starts.
+    fn init(&mut self, _ctx: &mut Context) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called once per frame before `render`. Keys pressed since the last
+    /// update are `just_pressed` in `ctx.input`.
+    fn update(&mut self, _ctx: &mut Context) {}
+
+    /// Draws the frame, usually with `ctx.render.render_views`. Lost and
+    /// outdated surfaces skip the frame and running out of memory exits.
+    fn render(&mut self, ctx: &mut Context) -> Result<(), wgpu::SurfaceError>;
+
+    /// Every winit event, before the runner handles it. Never called by
+    /// `run_headless`.
+    fn on_event(&mut self, _ctx: &mut Context, _event: &Event<()>) {}
+}
+
+/// What the hooks get to work with, all owned by the runner
+pub struct Context<'a> {
+    pub render: &'a mut Render,
+    /// Follows the aspect ratio of the window
+    pub camera: &'a mut Camera<Perspective>,
+    pub input: &'a Input,
+    exit: &'a mut bool,
+}
+
+impl Context<'_> {
+    /// Ends the event loop, or the headless run, after the current hook
+    pub fn exit(&mut self) {
+        *self.exit = true;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AppConfig {
+    pub window: WindowConfig,
+    pub render: RenderSettings,
+    /// Vertical field of view of the camera
+    pub fov_degrees: f32,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            window: WindowConfig::default(),
+            render: RenderSettings::default(),
+            fov_degrees: 90.0,
+        }
+    }
+}
+
+/// `time::startup` may only run once, while a test process may make
+/// several headless runs
+fn start_time() {
+    static STARTED: Once = Once::new();
+    STARTED.call_once(time::startup);
+}
+
+/// The state `run` and `run_headless` share between the hooks
+struct Runner {
+    render: Render,
+    camera: Camera<Perspective>,
+    input: Input,
+    exit: bool,
+}
+
+impl Runner {
+    fn new(render: Render, fov_degrees: f32) -> Self {
+        let size = render.size();
+        let camera = Camera::new(
+            fov_degrees,
+            size.width.max(1) as f32 / size.height.max(1) as f32,
+            Perspective,
+            Transform::from_translation(Vec3::ZERO),
+        );
+        Self {
+            render,
+            camera,
+            input: Input::default(),
+            exit: false,
+        }
+    }
+
+    fn context(&mut self) -> Context<'_> {
+        Context {
+            render: &mut self.render,
+            camera: &mut self.camera,
+            input: &self.input,
+            exit: &mut self.exit,
+        }
+    }
+
+    /// Updates the app and starts the next frame of `time`. Input is
+    /// collected between updates, so it is cleared afterwards.
+    fn update(&mut self, app: &mut impl App) {
+        app.update(&mut self.context());
+        time::update();
+        self.input.keyboard.clear();
+        self.input.mouse_motion = Vec2::ZERO;
+    }
+
+    fn render(&mut self, app: &mut impl App) {
+        match app.render(&mut self.context()) {
+            Ok(()) => {}
+            // the surface has already been reconfigured
+            Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                log::debug!("surface was lost or outdated, skipped a frame")
+            }
+            Err(wgpu::SurfaceError::OutOfMemory) => self.exit = true,
+            Err(e) => log::warn!("surface error: {e:?}"),
+        }
+    }
+}
+
+/// Opens the window described by `config` and runs `app` until the window
+/// is closed or the app exits. Must be called on the main thread.
+pub fn run(mut app: impl App, config: AppConfig) -> anyhow::Result<()> {
+    start_time();
+    let event_loop = EventLoop::new().context("Could not create the event loop")?;
+    let window = Arc::new(config.window.build(&event_loop)?);
+    let render = pollster::block_on(
+        RenderBuilder::with_settings(window.clone(), config.render.clone()).build(),
+    )?;
+    let mut runner = Runner::new(render, config.fov_degrees);
+    app.init(&mut runner.context())?;
+    let mut cursor_grabbed = config.window.cursor_grab != CursorGrabMode::None;
+
+    event_loop.set_control_flow(ControlFlow::Poll);
+    event_loop.run(move |event, target| {
+        app.on_event(&mut runner.context(), &event);
+
+        match event {
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { delta },
+                ..
+            } if cursor_grabbed => {
+                runner.input.mouse_motion += Vec2::new(delta.0 as f32, delta.1 as f32);
+            }
+            Event::WindowEvent { window_id, event } => {
+                if runner.render.handle_window_event(window_id, &event)
+                    && runner.render.is_drawable()
+                {
+                    let size = runner.render.size();
+                    runner.camera.aspect_ratio = size.width as f32 / size.height as f32;
+                }
+                match event {
+                    WindowEvent::RedrawRequested => runner.render(&mut app),
+                    WindowEvent::KeyboardInput { event, .. } => {
+                        // keys winit can not identify are ignored
+                        if let PhysicalKey::Code(key) = event.physical_key {
+                            if event.state == ElementState::Released {
+                                runner.input.keyboard.release(key);
+                            } else {
+                                runner.input.keyboard.press(key);
+                            }
+                            let pressed =
+                                event.state == ElementState::Pressed && !event.repeat;
+                            let alt = runner.input.keyboard.pressed(KeyCode::AltLeft)
+                                || runner.input.keyboard.pressed(KeyCode::AltRight);
+                            if key == KeyCode::Enter && alt && pressed {
+                                let mode = config
+                                    .window
+                                    .fullscreen
+                                    .unwrap_or(FullscreenMode::Borderless);
+                                window::toggle_fullscreen(&window, mode);
+                            }
+                            if key == KeyCode::Escape && pressed && cursor_grabbed {
+                                window::release_cursor(&window);
+                                cursor_grabbed = false;
+                            }
+                        }
+                    }
+                    WindowEvent::MouseInput {
+                        state: ElementState::Pressed,
+                        button: MouseButton::Left,
+                        ..
+                    } if !cursor_grabbed
+                        && config.window.cursor_grab != CursorGrabMode::None =>
+                    {
+                        match window::grab_cursor(&window, config.window.cursor_grab) {
+                            Ok(()) => cursor_grabbed = true,
+                            Err(e) => log::warn!("{e:#}"),
+                        }
+                        window.set_cursor_visible(config.window.cursor_visible);
+                    }
+                    WindowEvent::CloseRequested => runner.exit = true,
+                    _ => {}
+                }
+            }
+            Event::AboutToWait => match time::next_frame_time() {
+                Some(next) if Instant::now() < next => {
+                    target.set_control_flow(ControlFlow::WaitUntil(next));
+                }
+                _ => {
+                    time::mark_frame();
+                    target.set_control_flow(ControlFlow::Poll);
+                    runner.update(&mut app);
+                    window.request_redraw();
+                }
+            },
+            _ => {}
+        }
+
+        if runner.exit {
+            target.exit();
+        }
+    })?;
+
+    Ok(())
+}
+
+/// Runs `app` for `frames` frames into an offscreen texture the size of the
+/// configured window at a scale factor of 1, without events, and returns it
+/// to be inspected. Frames are read back with `ctx.render.capture_frame`.
+pub fn run_headless<A: App>(
+    mut app: A,
+    config: AppConfig,
+    frames: u32,
+) -> anyhow::Result<A> {
+    start_time();
+    let size = config.window.size.to_physical::<u32>(1.0);
+    let render = pollster::block_on(
+        RenderBuilder::headless(size.width, size.height)
+            .settings(config.render)
+            .build(),
+    )?;
+    let mut runner = Runner::new(render, config.fov_degrees);
+    app.init(&mut runner.context())?;
+
+    for _ in 0..frames {
+        if runner.exit {
+            break;
+        }
+        runner.update(&mut app);
+        if runner.exit {
+            break;
+        }
+        app.render(&mut runner.context())
+            .context("Could not render a headless frame")?;
+    }
+    Ok(app)
+}
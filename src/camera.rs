@@ -64,9 +64,77 @@ impl<P: Projection> Camera<P> {
         self.transform.translation += translation;
     }
 
+    pub fn z_near(&self) -> f32 {
+        self.z_near
+    }
+
+    pub fn z_far(&self) -> f32 {
+        self.z_far
+    }
+
+    /// World space corners of the part of the view frustum between the
+    /// `near` and `far` distances. The first four corners lie on the near
+    /// plane.
+    pub fn frustum_corners(&self, near: f32, far: f32) -> [glam::Vec3; 8] {
+        self.frustum_corners_with(self.aspect_ratio, near, far)
+    }
+
+    fn frustum_corners_with(
+        &self,
+        aspect_ratio: f32,
+        near: f32,
+        far: f32,
+    ) -> [glam::Vec3; 8] {
+        let inverse = P::generate_view_projection_matrix(
+            aspect_ratio,
+            self.transform.translation,
+            glam::Vec3::Y,
+            self.fov,
+            self.forward(),
+            near,
+            far,
+        )
+        .inverse();
+
+        let mut corners = [glam::Vec3::ZERO; 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            let ndc = glam::Vec4::new(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i < 4 { 0.0 } else { 1.0 },
+                1.0,
+            );
+            let world = inverse * ndc;
+            *corner = world.truncate() / world.w;
+        }
+        corners
+    }
+
+    /// View projection matrix with the camera at the origin, for drawing
+    /// things that are infinitely far away like the sky
+    pub fn rotation_projection_matrix(&self) -> glam::Mat4 {
+        self.rotation_projection_matrix_with(self.aspect_ratio)
+    }
+
+    fn rotation_projection_matrix_with(&self, aspect_ratio: f32) -> glam::Mat4 {
+        P::generate_view_projection_matrix(
+            aspect_ratio,
+            glam::Vec3::ZERO,
+            glam::Vec3::Y,
+            self.fov,
+            self.forward(),
+            self.z_near,
+            self.z_far,
+        )
+    }
+
     pub fn projection_matrix(&self) -> glam::Mat4 {
+        self.projection_matrix_with(self.aspect_ratio)
+    }
+
+    fn projection_matrix_with(&self, aspect_ratio: f32) -> glam::Mat4 {
         P::generate_view_projection_matrix(
-            self.aspect_ratio,
+            aspect_ratio,
             self.transform.translation,
             glam::Vec3::Y,
             self.fov,
@@ -89,11 +157,12 @@ pub trait Projection {
     ) -> glam::Mat4;
 }
 
+/// Uses the field of view in radians as half the height of the view volume
 pub struct Orthographic;
 
 impl Projection for Orthographic {
     fn generate_view_projection_matrix(
-        _aspect_ratio: f32,
+        aspect_ratio: f32,
         eye: glam::Vec3,
         up: glam::Vec3,
         fov: f32,
@@ -101,10 +170,17 @@ impl Projection for Orthographic {
         z_near: f32,
         z_far: f32,
     ) -> glam::Mat4 {
-        let projection =
-            glam::Mat4::orthographic_rh(-fov, fov, -fov, fov, z_near, z_far);
+        let half_width = fov * aspect_ratio;
+        let projection = glam::Mat4::orthographic_rh(
+            -half_width,
+            half_width,
+            -fov,
+            fov,
+            z_near,
+            z_far,
+        );
         let view = glam::Mat4::look_to_rh(eye, target, up);
-        return projection * view;
+        projection * view
     }
 }
 
@@ -122,7 +198,76 @@ impl Projection for Perspective {
     ) -> glam::Mat4 {
         let projection = glam::Mat4::perspective_rh(fov, aspect_ratio, z_near, z_far);
         let view = glam::Mat4::look_to_rh(eye, target, up);
-        return projection * view;
+        projection * view
+    }
+}
+
+/// What the renderer needs to know about a camera, so cameras with different
+/// projections can be rendered in the same frame. The projections take the
+/// aspect ratio to draw with, which is the viewport's unless the view keeps
+/// the camera's own.
+pub trait CameraView {
+    fn position(&self) -> glam::Vec3;
+
+    fn forward(&self) -> glam::Vec3;
+
+    fn z_near(&self) -> f32;
+
+    fn z_far(&self) -> f32;
+
+    fn aspect_ratio(&self) -> f32;
+
+    /// See `Camera::frustum_corners`
+    fn frustum_corners(
+        &self,
+        aspect_ratio: f32,
+        near: f32,
+        far: f32,
+    ) -> [glam::Vec3; 8];
+
+    /// See `Camera::rotation_projection_matrix`
+    fn rotation_projection_matrix(&self, aspect_ratio: f32) -> glam::Mat4;
+
+    /// The view projection matrix
+    fn projection_matrix(&self, aspect_ratio: f32) -> glam::Mat4;
+}
+
+impl<P: Projection> CameraView for Camera<P> {
+    fn position(&self) -> glam::Vec3 {
+        self.transform.translation
+    }
+
+    fn forward(&self) -> glam::Vec3 {
+        Camera::forward(self)
+    }
+
+    fn z_near(&self) -> f32 {
+        Camera::z_near(self)
+    }
+
+    fn z_far(&self) -> f32 {
+        Camera::z_far(self)
+    }
+
+    fn aspect_ratio(&self) -> f32 {
+        self.aspect_ratio
+    }
+
+    fn frustum_corners(
+        &self,
+        aspect_ratio: f32,
+        near: f32,
+        far: f32,
+    ) -> [glam::Vec3; 8] {
+        self.frustum_corners_with(aspect_ratio, near, far)
+    }
+
+    fn rotation_projection_matrix(&self, aspect_ratio: f32) -> glam::Mat4 {
+        self.rotation_projection_matrix_with(aspect_ratio)
+    }
+
+    fn projection_matrix(&self, aspect_ratio: f32) -> glam::Mat4 {
+        self.projection_matrix_with(aspect_ratio)
     }
 }
 
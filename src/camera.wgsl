@@ -0,0 +1,9 @@
+struct CameraUniform {
+    view_proj: mat4x4<f32>,
+    position: vec4<f32>,
+    forward: vec4<f32>,
+    inv_rotation_view_proj: mat4x4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
@@ -0,0 +1,382 @@
+//! Reading rendered frames back to the CPU.
+//!
+//! `Render::capture_frame` asks for a copy of the next frame, which
+//! `Render::take_capture` returns as an RGBA image once it is rendered.
+//! `Recorder` writes a sequence of captured frames to disk.
+use crate::time;
+use anyhow::Context;
+use image::RgbaImage;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// A frame copied into a mappable buffer, not yet read back
+pub(crate) struct PendingCapture {
+    buffer: wgpu::Buffer,
+    width: u32,
+    height: u32,
+    /// Bytes per row in `buffer`, padded to `COPY_BYTES_PER_ROW_ALIGNMENT`
+    padded_bytes_per_row: u32,
+    format: wgpu::TextureFormat,
+}
+
+impl PendingCapture {
+    /// Records a copy of `texture` into a new buffer. Only 8 bit RGBA and
+    /// BGRA formats can be captured.
+    pub fn copy(
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+    ) -> anyhow::Result<Self> {
+        use wgpu::TextureFormat as F;
+        let format = texture.format();
+        if !matches!(
+            format,
+            F::Rgba8Unorm | F::Rgba8UnormSrgb | F::Bgra8Unorm | F::Bgra8UnormSrgb
+        ) {
+            anyhow::bail!("Capturing {format:?} frames is not supported");
+        }
+        if !texture.usage().contains(wgpu::TextureUsages::COPY_SRC) {
+            anyhow::bail!("The frame texture can not be copied from");
+        }
+
+        let (width, height) = (texture.width(), texture.height());
+        let padded_bytes_per_row =
+            (width * 4).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("capture_buffer"),
+            size: padded_bytes_per_row as wgpu::BufferAddress
+                * height as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            texture.size(),
+        );
+
+        Ok(Self {
+            buffer,
+            width,
+            height,
+            padded_bytes_per_row,
+            format,
+        })
+    }
+
+    /// Waits for the copy to finish and converts the rows to tightly packed
+    /// RGBA. The bytes are kept as they are, so sRGB frames stay sRGB
+    /// encoded like PNGs expect.
+    pub fn read(self, device: &wgpu::Device) -> anyhow::Result<RgbaImage> {
+        let slice = self.buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver
+            .recv()
+            .context("The capture buffer was never mapped")?
+            .context("Could not map the capture buffer")?;
+
+        let row_bytes = self.width as usize * 4;
+        let mut pixels = Vec::with_capacity(row_bytes * self.height as usize);
+        {
+            let mapped = slice.get_mapped_range();
+            for row in mapped.chunks(self.padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..row_bytes]);
+            }
+        }
+        self.buffer.unmap();
+
+        if matches!(
+            self.format,
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb
+        ) {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        RgbaImage::from_raw(self.width, self.height, pixels)
+            .context("Captured frame has the wrong size")
+    }
+}
+
+/// Writes `image` as a PNG, creating the parent directories
+pub fn save_png(image: &RgbaImage, path: impl AsRef<Path>) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Could not create {}", parent.display()))?;
+    }
+    image
+        .save_with_format(path, image::ImageFormat::Png)
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// `dir/screenshot-<unix time in milliseconds>.png`
+pub fn screenshot_path(dir: impl AsRef<Path>) -> PathBuf {
+    let millis = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis());
+    dir.as_ref().join(format!("screenshot-{millis}.png"))
+}
+
+/// Where a `Recorder` writes its frames
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordingFormat {
+    /// Numbered PNGs in a directory, `frame_00000.png` onwards
+    PngSequence,
+    /// One uncompressed YUV 4:4:4 stream, e.g. for
+    /// `ffmpeg -i recording.y4m recording.mp4`
+    Y4m,
+}
+
+/// Records captured frames at a fixed time step.
+///
+/// While a recorder exists `time::delta_time` returns `1 / frames_per_second`,
+/// so animations advance the same amount every frame no matter how long
+/// rendering and writing take. Capture every frame with
+/// `Render::capture_frame` and pass the results to `write_frame`.
+pub struct Recorder {
+    format: RecordingFormat,
+    path: PathBuf,
+    frames_per_second: u32,
+    frames: u32,
+    y4m: Option<Y4mWriter>,
+}
+
+struct Y4mWriter {
+    file: BufWriter<File>,
+    width: u32,
+    height: u32,
+}
+
+impl Recorder {
+    /// Starts a recording into `path`, a directory for PNG sequences or a
+    /// file for Y4M
+    pub fn new(
+        format: RecordingFormat,
+        path: impl Into<PathBuf>,
+        frames_per_second: u32,
+    ) -> anyhow::Result<Self> {
+        if frames_per_second == 0 {
+            anyhow::bail!("Recordings need at least one frame per second");
+        }
+        let path = path.into();
+        let dir = match format {
+            RecordingFormat::PngSequence => Some(path.as_path()),
+            RecordingFormat::Y4m => path.parent(),
+        };
+        if let Some(dir) = dir {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Could not create {}", dir.display()))?;
+        }
+        time::set_fixed_delta_time(Some(1.0 / frames_per_second as f32));
+
+        Ok(Self {
+            format,
+            path,
+            frames_per_second,
+            frames: 0,
+            y4m: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Frames written so far
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Appends one frame. Every frame of a Y4M stream must have the size of
+    /// the first.
+    pub fn write_frame(&mut self, image: &RgbaImage) -> anyhow::Result<()> {
+        match self.format {
+            RecordingFormat::PngSequence => {
+                let path = self.path.join(format!("frame_{:05}.png", self.frames));
+                save_png(image, path)?;
+            }
+            RecordingFormat::Y4m => {
+                let writer = match &mut self.y4m {
+                    Some(writer) => writer,
+                    None => self.y4m.insert(Y4mWriter::create(
+                        &self.path,
+                        image.width(),
+                        image.height(),
+                        self.frames_per_second,
+                    )?),
+                };
+                writer.write_frame(image).with_context(|| {
+                    format!("Could not write to {}", self.path.display())
+                })?;
+            }
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Flushes the output and returns the number of frames written
+    pub fn finish(mut self) -> anyhow::Result<u32> {
+        if let Some(writer) = &mut self.y4m {
+            writer.file.flush().with_context(|| {
+                format!("Could not write to {}", self.path.display())
+            })?;
+        }
+        Ok(self.frames)
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        time::set_fixed_delta_time(None);
+    }
+}
+
+impl Y4mWriter {
+    fn create(path: &Path, width: u32, height: u32, fps: u32) -> anyhow::Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Could not create {}", path.display()))?;
+        let mut file = BufWriter::new(file);
+        writeln!(file, "YUV4MPEG2 W{width} H{height} F{fps}:1 Ip A1:1 C444")?;
+        Ok(Self {
+            file,
+            width,
+            height,
+        })
+    }
+
+    /// Writes the Y, Cb and Cr planes in limited range BT.601
+    fn write_frame(&mut self, image: &RgbaImage) -> anyhow::Result<()> {
+        if image.dimensions() != (self.width, self.height) {
+            anyhow::bail!(
+                "Frame is {:?} but the recording is {}x{}",
+                image.dimensions(),
+                self.width,
+                self.height
+            );
+        }
+        let plane_size = (self.width * self.height) as usize;
+        let mut planes = vec![0u8; plane_size * 3];
+        let (y, chroma) = planes.split_at_mut(plane_size);
+        let (cb, cr) = chroma.split_at_mut(plane_size);
+        for (i, pixel) in image.pixels().enumerate() {
+            let [r, g, b, _] = pixel.0.map(|c| c as f32 / 255.0);
+            y[i] = (16.0 + 65.481 * r + 128.553 * g + 24.966 * b).round() as u8;
+            cb[i] = (128.0 - 37.797 * r - 74.203 * g + 112.0 * b).round() as u8;
+            cr[i] = (128.0 + 112.0 * r - 93.786 * g - 18.214 * b).round() as u8;
+        }
+        self.file.write_all(b"FRAME\n")?;
+        self.file.write_all(&planes)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+    use std::sync::Mutex;
+
+    /// Recorders set the fixed time step for the whole process
+    static TIME_STEP: Mutex<()> = Mutex::new(());
+
+    fn recorder(format: RecordingFormat, name: &str, fps: u32) -> Recorder {
+        let path = std::env::temp_dir()
+            .join(format!("rust_graphics-{}", std::process::id()))
+            .join(name);
+        let _ = std::fs::remove_dir_all(&path);
+        Recorder::new(format, path, fps).unwrap()
+    }
+
+    /// White, black and red next to each other
+    fn frame() -> RgbaImage {
+        let mut image = RgbaImage::new(3, 1);
+        image.put_pixel(0, 0, Rgba([255, 255, 255, 255]));
+        image.put_pixel(1, 0, Rgba([0, 0, 0, 255]));
+        image.put_pixel(2, 0, Rgba([255, 0, 0, 255]));
+        image
+    }
+
+    #[test]
+    fn y4m_streams_hold_limited_range_bt601_planes() {
+        let _time_step = TIME_STEP.lock().unwrap();
+        let mut recorder = recorder(RecordingFormat::Y4m, "stream.y4m", 25);
+        recorder.write_frame(&frame()).unwrap();
+        recorder.write_frame(&frame()).unwrap();
+        let path = recorder.path().to_owned();
+        assert_eq!(recorder.finish().unwrap(), 2);
+
+        let bytes = std::fs::read(path).unwrap();
+        let header = b"YUV4MPEG2 W3 H1 F25:1 Ip A1:1 C444\n";
+        assert!(bytes.starts_with(header));
+        let frames = &bytes[header.len()..];
+        // a Y, Cb and Cr byte per pixel after every frame marker
+        assert_eq!(frames.len(), 2 * (b"FRAME\n".len() + 3 * 3));
+        #[rustfmt::skip]
+        let planes = [
+            235, 16, 81,
+            128, 128, 90,
+            128, 128, 240,
+        ];
+        for frame in frames.chunks(frames.len() / 2) {
+            assert_eq!(&frame[..6], b"FRAME\n");
+            assert_eq!(frame[6..], planes);
+        }
+    }
+
+    #[test]
+    fn y4m_frames_keep_the_first_size() {
+        let _time_step = TIME_STEP.lock().unwrap();
+        let mut recorder = recorder(RecordingFormat::Y4m, "resized.y4m", 30);
+        recorder.write_frame(&frame()).unwrap();
+        let error = recorder.write_frame(&RgbaImage::new(2, 2)).unwrap_err();
+        assert!(
+            format!("{error:#}").contains("Frame is (2, 2) but the recording is 3x1"),
+            "{error:#}"
+        );
+        assert_eq!(recorder.frames(), 1);
+    }
+
+    #[test]
+    fn png_sequences_number_their_frames() {
+        let _time_step = TIME_STEP.lock().unwrap();
+        let mut recorder = recorder(RecordingFormat::PngSequence, "sequence", 30);
+        for _ in 0..2 {
+            recorder.write_frame(&frame()).unwrap();
+        }
+        let dir = recorder.path().to_owned();
+        assert_eq!(recorder.finish().unwrap(), 2);
+
+        let mut names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["frame_00000.png", "frame_00001.png"]);
+        let image = image::open(dir.join("frame_00001.png")).unwrap();
+        assert_eq!(image.to_rgba8(), frame());
+    }
+
+    #[test]
+    fn recorders_fix_the_time_step_while_they_exist() {
+        let _time_step = TIME_STEP.lock().unwrap();
+        let recorder = recorder(RecordingFormat::PngSequence, "time_step", 25);
+        assert_eq!(time::fixed_delta_time(), Some(0.04));
+        assert_eq!(time::delta_time(), 0.04);
+        drop(recorder);
+        assert_eq!(time::fixed_delta_time(), None);
+    }
+}
@@ -0,0 +1,14 @@
+// Clears the viewport of a view, see `ClearPipelines` in view.rs. The color
+// comes from the blend constant.
+
+@vertex
+fn vs_clear(@builtin(vertex_index) index: u32) -> @builtin(position) @invariant vec4<f32> {
+    // on the far plane like the sky, so it matches a cleared depth exactly
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    return vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 1.0, 1.0);
+}
+
+@fragment
+fn fs_clear() -> @location(0) vec4<f32> {
+    return vec4<f32>(1.0);
+}
@@ -0,0 +1,268 @@
+//! Immediate-mode debug drawing.
+//!
+//! Shapes can be queued from anywhere (e.g. `Entity::update`) and are drawn by
+//! `Render` in a separate line pass after the meshes.
+use crate::vertex::Vertex;
+use glam::{Quat, Vec3, Vec4};
+use std::sync::Mutex;
+
+const CIRCLE_SEGMENTS: usize = 24;
+
+static LINES: Mutex<Vec<DebugLine>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug)]
+pub struct DrawOptions {
+    pub color: Vec4,
+    /// When false the shape is drawn on top of all geometry
+    pub depth_test: bool,
+    /// Seconds the shape stays queued. Zero draws it for a single frame.
+    pub duration: f32,
+}
+
+impl DrawOptions {
+    pub fn new(color: Vec4) -> Self {
+        Self {
+            color,
+            depth_test: true,
+            duration: 0.0,
+        }
+    }
+
+    pub fn overlay(mut self) -> Self {
+        self.depth_test = false;
+        self
+    }
+
+    pub fn duration(mut self, seconds: f32) -> Self {
+        self.duration = seconds;
+        self
+    }
+}
+
+impl Default for DrawOptions {
+    fn default() -> Self {
+        Self::new(Vec4::ONE)
+    }
+}
+
+impl From<Vec4> for DrawOptions {
+    fn from(color: Vec4) -> Self {
+        Self::new(color)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct DebugLine {
+    start: Vec3,
+    end: Vec3,
+    color: Vec4,
+    depth_test: bool,
+    remaining: f32,
+}
+
+fn push(lines: impl IntoIterator<Item = (Vec3, Vec3)>, options: DrawOptions) {
+    let Ok(mut queue) = LINES.lock() else {
+        log::error!("Could not lock the debug draw queue");
+        return;
+    };
+    queue.extend(lines.into_iter().map(|(start, end)| DebugLine {
+        start,
+        end,
+        color: options.color,
+        depth_test: options.depth_test,
+        remaining: options.duration,
+    }));
+}
+
+pub fn line(start: Vec3, end: Vec3, options: impl Into<DrawOptions>) {
+    push([(start, end)], options.into());
+}
+
+/// Draws a line from `start` to `end` with an arrow head at `end`
+pub fn arrow(start: Vec3, end: Vec3, options: impl Into<DrawOptions>) {
+    let dir = end - start;
+    let length = dir.length();
+    if length <= f32::EPSILON {
+        return;
+    }
+    let dir = dir / length;
+    let side = dir.any_orthonormal_vector();
+    let up = dir.cross(side);
+    let head = length * 0.2;
+    let base = end - dir * head;
+
+    push(
+        [
+            (start, end),
+            (end, base + side * head * 0.5),
+            (end, base - side * head * 0.5),
+            (end, base + up * head * 0.5),
+            (end, base - up * head * 0.5),
+        ],
+        options.into(),
+    );
+}
+
+/// Draws an axis-aligned box between `min` and `max`
+pub fn aabb(min: Vec3, max: Vec3, options: impl Into<DrawOptions>) {
+    let center = (min + max) * 0.5;
+    cuboid(center, (max - min) * 0.5, Quat::IDENTITY, options);
+}
+
+/// Draws an oriented box with the given half extents
+pub fn cuboid(
+    center: Vec3,
+    half_extents: Vec3,
+    rotation: Quat,
+    options: impl Into<DrawOptions>,
+) {
+    let corner = |x: f32, y: f32, z: f32| {
+        center + rotation * (half_extents * Vec3::new(x, y, z))
+    };
+    let c = [
+        corner(-1.0, -1.0, -1.0),
+        corner(1.0, -1.0, -1.0),
+        corner(1.0, 1.0, -1.0),
+        corner(-1.0, 1.0, -1.0),
+        corner(-1.0, -1.0, 1.0),
+        corner(1.0, -1.0, 1.0),
+        corner(1.0, 1.0, 1.0),
+        corner(-1.0, 1.0, 1.0),
+    ];
+    let edges = [
+        (0, 1),
+        (1, 2),
+        (2, 3),
+        (3, 0),
+        (4, 5),
+        (5, 6),
+        (6, 7),
+        (7, 4),
+        (0, 4),
+        (1, 5),
+        (2, 6),
+        (3, 7),
+    ];
+
+    push(edges.iter().map(|&(a, b)| (c[a], c[b])), options.into());
+}
+
+/// Draws a circle around `normal`
+pub fn circle(
+    center: Vec3,
+    normal: Vec3,
+    radius: f32,
+    options: impl Into<DrawOptions>,
+) {
+    let normal = normal.normalize_or_zero();
+    if normal == Vec3::ZERO {
+        return;
+    }
+    let u = normal.any_orthonormal_vector() * radius;
+    let v = normal.cross(u);
+    let point = |i: usize| {
+        let angle = i as f32 / CIRCLE_SEGMENTS as f32 * std::f32::consts::TAU;
+        center + u * angle.cos() + v * angle.sin()
+    };
+
+    push(
+        (0..CIRCLE_SEGMENTS).map(|i| (point(i), point(i + 1))),
+        options.into(),
+    );
+}
+
+/// Draws a wireframe sphere as three great circles
+pub fn sphere(center: Vec3, radius: f32, options: impl Into<DrawOptions>) {
+    let options = options.into();
+    circle(center, Vec3::X, radius, options);
+    circle(center, Vec3::Y, radius, options);
+    circle(center, Vec3::Z, radius, options);
+}
+
+/// Draws a square grid on the XZ plane with `cells` cells per side
+pub fn grid(center: Vec3, cell_size: f32, cells: u32, options: impl Into<DrawOptions>) {
+    let half = cell_size * cells as f32 * 0.5;
+    let lines = (0..=cells).flat_map(|i| {
+        let offset = -half + i as f32 * cell_size;
+        [
+            (
+                center + Vec3::new(offset, 0.0, -half),
+                center + Vec3::new(offset, 0.0, half),
+            ),
+            (
+                center + Vec3::new(-half, 0.0, offset),
+                center + Vec3::new(half, 0.0, offset),
+            ),
+        ]
+    });
+
+    push(lines, options.into());
+}
+
+/// Draws an RGB axis triad. The color of `options` is ignored.
+pub fn axes(
+    origin: Vec3,
+    rotation: Quat,
+    length: f32,
+    options: impl Into<DrawOptions>,
+) {
+    let options = options.into();
+    for (axis, color) in [
+        (Vec3::X, Vec4::new(1.0, 0.0, 0.0, 1.0)),
+        (Vec3::Y, Vec4::new(0.0, 1.0, 0.0, 1.0)),
+        (Vec3::Z, Vec4::new(0.0, 0.0, 1.0, 1.0)),
+    ] {
+        arrow(
+            origin,
+            origin + rotation * axis * length,
+            DrawOptions { color, ..options },
+        );
+    }
+}
+
+/// Removes every queued shape, including ones with a remaining duration
+pub fn clear() {
+    if let Ok(mut queue) = LINES.lock() {
+        queue.clear();
+    }
+}
+
+/// Line list vertices for a single frame, split by depth testing
+#[derive(Default)]
+pub(crate) struct DebugVertices {
+    pub depth_tested: Vec<Vertex>,
+    pub overlay: Vec<Vertex>,
+}
+
+/// Drains the queue into vertices for this frame, keeping shapes whose
+/// duration has not run out yet
+pub(crate) fn take_vertices(delta_time: f32) -> DebugVertices {
+    let mut out = DebugVertices::default();
+    let Ok(mut queue) = LINES.lock() else {
+        log::error!("Could not lock the debug draw queue");
+        return out;
+    };
+
+    for line in queue.iter() {
+        let target = if line.depth_test {
+            &mut out.depth_tested
+        } else {
+            &mut out.overlay
+        };
+        for point in [line.start, line.end] {
+            target.push(Vertex {
+                position: point.extend(1.0).into(),
+                color: line.color.into(),
+                normal: [0.0; 3],
+                tex_coords: [0.0; 2],
+            });
+        }
+    }
+
+    queue.retain_mut(|line| {
+        line.remaining -= delta_time;
+        line.remaining > 0.0
+    });
+
+    out
+}
@@ -0,0 +1,689 @@
+//! Environment cube maps and the image based lighting maps derived from them.
+//!
+//! The irradiance map, the prefiltered specular map and the BRDF lookup
+//! table are generated on the GPU with render passes, so they also work on
+//! WebGL2 where compute shaders are unavailable.
+use crate::texture::{self, Texture};
+use glam::Vec3;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use wgpu::util::DeviceExt;
+
+/// Format of every cube map the environment renders
+pub const ENVIRONMENT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+const GRADIENT_SIZE: u32 = 256;
+const IRRADIANCE_SIZE: u32 = 32;
+const PREFILTERED_SIZE: u32 = 128;
+/// Roughness 0 to 1 is spread over this many mip levels
+const PREFILTERED_MIP_LEVELS: u32 = 5;
+const BRDF_LUT_SIZE: u32 = 256;
+
+/// A procedural sky blending from the ground over the horizon to the zenith
+#[derive(Clone, Copy, Debug)]
+pub struct GradientSky {
+    pub zenith: Vec3,
+    pub horizon: Vec3,
+    pub ground: Vec3,
+}
+
+impl Default for GradientSky {
+    fn default() -> Self {
+        Self {
+            zenith: Vec3::new(0.18, 0.32, 0.6),
+            horizon: Vec3::new(0.7, 0.75, 0.8),
+            ground: Vec3::new(0.2, 0.18, 0.16),
+        }
+    }
+}
+
+/// What an `EnvironmentMap` was created from, kept to recreate it on a new
+/// device
+#[derive(Clone, Debug)]
+pub enum EnvironmentSource {
+    Gradient(GradientSky),
+    Faces([PathBuf; 6]),
+    Equirectangular(PathBuf),
+    /// A cube map passed to `from_cube`, which can't be recreated
+    Cube,
+}
+
+impl Default for EnvironmentSource {
+    fn default() -> Self {
+        Self::Gradient(GradientSky::default())
+    }
+}
+
+/// An environment cube map ready to light PBR materials with
+pub struct EnvironmentMap {
+    pub cube: Texture,
+    pub irradiance: Texture,
+    pub prefiltered: Texture,
+    pub brdf_lut: Texture,
+    source: EnvironmentSource,
+}
+
+/// Mirrors `Params` in ibl.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct IblParams {
+    zenith: [f32; 4],
+    horizon: [f32; 4],
+    ground: [f32; 4],
+    face: u32,
+    roughness: f32,
+    _padding: [u32; 2],
+}
+
+unsafe impl bytemuck::Pod for IblParams {}
+unsafe impl bytemuck::Zeroable for IblParams {}
+
+impl EnvironmentMap {
+    /// Renders `sky` into a cube map and derives the lighting maps from it
+    pub fn from_gradient(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sky: &GradientSky,
+    ) -> Self {
+        let generator = IblGenerator::new(device);
+        let cube = texture::create_cube_texture(
+            device,
+            GRADIENT_SIZE,
+            1,
+            ENVIRONMENT_FORMAT,
+            "gradient_sky",
+        );
+        let params = IblParams {
+            zenith: sky.zenith.extend(1.0).into(),
+            horizon: sky.horizon.extend(1.0).into(),
+            ground: sky.ground.extend(1.0).into(),
+            ..Default::default()
+        };
+
+        let mut encoder = device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        for face in 0..6 {
+            generator.draw(
+                device,
+                &mut encoder,
+                &generator.gradient,
+                Some(IblParams { face, ..params }),
+                None,
+                &face_view(&cube, face, 0),
+            );
+        }
+        queue.submit(Some(encoder.finish()));
+
+        let source = EnvironmentSource::Gradient(*sky);
+        Self::from_cube_with(device, queue, &generator, cube, source)
+    }
+
+    /// Loads six square images of the same size, in the order +X, -X, +Y,
+    /// -Y, +Z, -Z
+    pub fn from_faces(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        paths: [impl AsRef<Path>; 6],
+    ) -> anyhow::Result<Self> {
+        let mut faces = Vec::with_capacity(6);
+        for path in &paths {
+            let path = path.as_ref();
+            let face = image::open(path)
+                .map_err(|e| anyhow::anyhow!("Could not load cube face {path:?}: {e}"))?
+                .to_rgba8();
+            faces.push(face);
+        }
+        let size = faces[0].width();
+        if faces
+            .iter()
+            .any(|face| face.width() != size || face.height() != size)
+        {
+            anyhow::bail!("Cube map faces must be square and all the same size");
+        }
+
+        let cube = texture::create_cube_texture(
+            device,
+            size,
+            1,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            "environment_faces",
+        );
+        for (layer, face) in faces.iter().enumerate() {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &cube.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: layer as u32,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                face,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * size),
+                    rows_per_image: Some(size),
+                },
+                wgpu::Extent3d {
+                    width: size,
+                    height: size,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        let generator = IblGenerator::new(device);
+        let source = EnvironmentSource::Faces(paths.map(|path| path.as_ref().into()));
+        Ok(Self::from_cube_with(
+            device, queue, &generator, cube, source,
+        ))
+    }
+
+    /// Loads an equirectangular (latitude/longitude) image, usually a `.hdr`
+    /// file, and projects it onto a cube map on the GPU
+    pub fn from_equirectangular(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: impl AsRef<Path>,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let image = image::open(path)
+            .map_err(|e| anyhow::anyhow!("Could not load environment {path:?}: {e}"))?
+            .to_rgba32f();
+        let (width, height) = image.dimensions();
+        let texels: Vec<u16> = image.as_raw().iter().map(|&c| f32_to_f16(c)).collect();
+
+        let equirect = device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                label: Some("equirectangular_environment"),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: ENVIRONMENT_FORMAT,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            wgpu::util::TextureDataOrder::LayerMajor,
+            bytemuck::cast_slice(&texels),
+        );
+        let equirect = Texture {
+            view: equirect.create_view(&wgpu::TextureViewDescriptor::default()),
+            sampler: device.create_sampler(&wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::Repeat,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                ..Default::default()
+            }),
+            texture: equirect,
+        };
+
+        let size = (width / 4)
+            .next_power_of_two()
+            .clamp(64, device.limits().max_texture_dimension_2d.min(2048));
+        let cube = texture::create_cube_texture(
+            device,
+            size,
+            1,
+            ENVIRONMENT_FORMAT,
+            "equirectangular_cube",
+        );
+        let generator = IblGenerator::new(device);
+        let mut encoder = device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        for face in 0..6 {
+            generator.draw(
+                device,
+                &mut encoder,
+                &generator.equirect,
+                Some(IblParams {
+                    face,
+                    ..Default::default()
+                }),
+                Some((3, &equirect)),
+                &face_view(&cube, face, 0),
+            );
+        }
+        queue.submit(Some(encoder.finish()));
+
+        let source = EnvironmentSource::Equirectangular(path.into());
+        Ok(Self::from_cube_with(
+            device, queue, &generator, cube, source,
+        ))
+    }
+
+    /// Derives the lighting maps from `cube`, which must be a filterable
+    /// cube map (see `texture::create_cube_texture`)
+    pub fn from_cube(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        cube: Texture,
+    ) -> Self {
+        let generator = IblGenerator::new(device);
+        Self::from_cube_with(device, queue, &generator, cube, EnvironmentSource::Cube)
+    }
+
+    /// Creates the environment `source` describes again, which fails for
+    /// `EnvironmentSource::Cube` and files that can no longer be loaded
+    pub fn from_source(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        source: &EnvironmentSource,
+    ) -> anyhow::Result<Self> {
+        match source {
+            EnvironmentSource::Gradient(sky) => {
+                Ok(Self::from_gradient(device, queue, sky))
+            }
+            EnvironmentSource::Faces(paths) => {
+                Self::from_faces(device, queue, paths.clone())
+            }
+            EnvironmentSource::Equirectangular(path) => {
+                Self::from_equirectangular(device, queue, path)
+            }
+            EnvironmentSource::Cube => {
+                anyhow::bail!("Environments created from a cube map can't be recreated")
+            }
+        }
+    }
+
+    pub fn source(&self) -> &EnvironmentSource {
+        &self.source
+    }
+
+    fn from_cube_with(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        generator: &IblGenerator,
+        cube: Texture,
+        source: EnvironmentSource,
+    ) -> Self {
+        let irradiance = texture::create_cube_texture(
+            device,
+            IRRADIANCE_SIZE,
+            1,
+            ENVIRONMENT_FORMAT,
+            "irradiance_map",
+        );
+        let prefiltered = texture::create_cube_texture(
+            device,
+            PREFILTERED_SIZE,
+            PREFILTERED_MIP_LEVELS,
+            ENVIRONMENT_FORMAT,
+            "prefiltered_map",
+        );
+        let brdf_lut = create_brdf_lut(device);
+
+        let mut encoder = device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        for face in 0..6 {
+            generator.draw(
+                device,
+                &mut encoder,
+                &generator.irradiance,
+                Some(IblParams {
+                    face,
+                    ..Default::default()
+                }),
+                Some((1, &cube)),
+                &face_view(&irradiance, face, 0),
+            );
+            for mip in 0..PREFILTERED_MIP_LEVELS {
+                let roughness = mip as f32 / (PREFILTERED_MIP_LEVELS - 1) as f32;
+                generator.draw(
+                    device,
+                    &mut encoder,
+                    &generator.prefilter,
+                    Some(IblParams {
+                        face,
+                        roughness,
+                        ..Default::default()
+                    }),
+                    Some((1, &cube)),
+                    &face_view(&prefiltered, face, mip),
+                );
+            }
+        }
+        generator.draw(
+            device,
+            &mut encoder,
+            &generator.brdf_lut,
+            None,
+            None,
+            &brdf_lut.view,
+        );
+        queue.submit(Some(encoder.finish()));
+
+        Self {
+            cube,
+            irradiance,
+            prefiltered,
+            brdf_lut,
+            source,
+        }
+    }
+}
+
+/// View of a single face and mip level, for rendering into
+fn face_view(cube: &Texture, face: u32, mip: u32) -> wgpu::TextureView {
+    cube.texture.create_view(&wgpu::TextureViewDescriptor {
+        label: Some("cube_face"),
+        dimension: Some(wgpu::TextureViewDimension::D2),
+        base_mip_level: mip,
+        mip_level_count: Some(1),
+        base_array_layer: face,
+        array_layer_count: Some(1),
+        ..Default::default()
+    })
+}
+
+fn create_brdf_lut(device: &wgpu::Device) -> Texture {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("brdf_lut"),
+        size: wgpu::Extent3d {
+            width: BRDF_LUT_SIZE,
+            height: BRDF_LUT_SIZE,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: ENVIRONMENT_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+            | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some("brdf_lut_sampler"),
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        ..Default::default()
+    });
+
+    Texture {
+        texture,
+        view,
+        sampler,
+    }
+}
+
+/// Pipelines for the fullscreen passes in ibl.wgsl
+struct IblGenerator {
+    gradient: wgpu::RenderPipeline,
+    equirect: wgpu::RenderPipeline,
+    irradiance: wgpu::RenderPipeline,
+    prefilter: wgpu::RenderPipeline,
+    brdf_lut: wgpu::RenderPipeline,
+}
+
+impl IblGenerator {
+    fn new(device: &wgpu::Device) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("ibl_shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("ibl.wgsl"))),
+        });
+        let pipeline = |entry_point: &str| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(entry_point),
+                // each entry point uses a different subset of the bindings
+                layout: None,
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_fullscreen",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point,
+                    targets: &[Some(ENVIRONMENT_FORMAT.into())],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        };
+
+        Self {
+            gradient: pipeline("fs_gradient"),
+            equirect: pipeline("fs_equirect"),
+            irradiance: pipeline("fs_irradiance"),
+            prefilter: pipeline("fs_prefilter"),
+            brdf_lut: pipeline("fs_brdf_lut"),
+        }
+    }
+
+    /// Records one fullscreen pass. Every pass gets its own small uniform
+    /// buffer, as they are all submitted at once. Passes without `params`
+    /// use no bindings at all, `source` is a texture and the binding it is
+    /// read from.
+    fn draw(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        params: Option<IblParams>,
+        source: Option<(u32, &Texture)>,
+        target: &wgpu::TextureView,
+    ) {
+        let bind_group = params.map(|params| {
+            let uniform =
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("ibl_params"),
+                    contents: bytemuck::cast_slice(&[params]),
+                    usage: wgpu::BufferUsages::UNIFORM,
+                });
+            let mut entries = vec![wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform.as_entire_binding(),
+            }];
+            if let Some((binding, source)) = source {
+                entries.push(wgpu::BindGroupEntry {
+                    binding,
+                    resource: wgpu::BindingResource::TextureView(&source.view),
+                });
+                entries.push(wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&source.sampler),
+                });
+            }
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("ibl_bind_group"),
+                layout: &pipeline.get_bind_group_layout(0),
+                entries: &entries,
+            })
+        });
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("IBL Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        pass.set_pipeline(pipeline);
+        if let Some(bind_group) = &bind_group {
+            pass.set_bind_group(0, bind_group, &[]);
+        }
+        pass.draw(0..3, 0..1);
+    }
+}
+
+pub(crate) fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    let cube = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            view_dimension: wgpu::TextureViewDimension::Cube,
+            multisampled: false,
+        },
+        count: None,
+    };
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("environment_bind_group_layout"),
+        entries: &[
+            cube(0),
+            cube(1),
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+        ],
+    })
+}
+
+pub(crate) fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    environment: &EnvironmentMap,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("environment_bind_group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(
+                    &environment.irradiance.view,
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(
+                    &environment.prefiltered.view,
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(
+                    &environment.brdf_lut.view,
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::Sampler(
+                    &environment.prefiltered.sampler,
+                ),
+            },
+        ],
+    })
+}
+
+/// Converts to a half precision float, rounding to nearest with ties to
+/// even. Values too large for half precision become infinity, NaN stays NaN.
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+
+    if value.is_nan() {
+        sign | 0x7e00
+    } else if exponent >= 0x1f {
+        sign | 0x7c00
+    } else if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        // subnormal, shift in the implicit leading bit
+        sign | shift_rounding(mantissa | 0x80_0000, (14 - exponent) as u32) as u16
+    } else {
+        // a rounding carry correctly moves into the exponent, up to infinity
+        sign | (((exponent as u32) << 10) + shift_rounding(mantissa, 13)) as u16
+    }
+}
+
+/// `value >> shift`, rounded to nearest with ties to even
+fn shift_rounding(value: u32, shift: u32) -> u32 {
+    let halfway = 1 << (shift - 1);
+    let rest = value & ((1 << shift) - 1);
+    let shifted = value >> shift;
+    if rest > halfway || (rest == halfway && shifted & 1 == 1) {
+        shifted + 1
+    } else {
+        shifted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_floats_keep_special_values() {
+        assert_eq!(f32_to_f16(0.0), 0x0000);
+        assert_eq!(f32_to_f16(-0.0), 0x8000);
+        assert_eq!(f32_to_f16(f32::INFINITY), 0x7c00);
+        assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0xfc00);
+        for nan in [f32::NAN, -f32::NAN] {
+            let half = f32_to_f16(nan);
+            assert_eq!(half & 0x7c00, 0x7c00, "{half:#06x}");
+            assert_ne!(half & 0x03ff, 0, "{half:#06x}");
+        }
+    }
+
+    #[test]
+    fn half_floats_round_to_nearest_even() {
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.0), 0xc000);
+        // halfway between 1 and the next half float rounds down to even
+        assert_eq!(f32_to_f16(1.0 + 2.0f32.powi(-11)), 0x3c00);
+        assert_eq!(
+            f32_to_f16(1.0 + 2.0f32.powi(-11) + 2.0f32.powi(-20)),
+            0x3c01
+        );
+        // halfway above an odd mantissa rounds up
+        assert_eq!(f32_to_f16(1.0 + 3.0 * 2.0f32.powi(-11)), 0x3c02);
+        // rounding up the largest mantissa carries into the exponent
+        assert_eq!(f32_to_f16(2.0 - 2.0f32.powi(-12)), 0x4000);
+    }
+
+    #[test]
+    fn half_floats_overflow_to_infinity() {
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(65519.0), 0x7bff);
+        assert_eq!(f32_to_f16(65520.0), 0x7c00);
+        assert_eq!(f32_to_f16(-1.0e6), 0xfc00);
+    }
+
+    #[test]
+    fn half_floats_have_subnormals() {
+        assert_eq!(f32_to_f16(2.0f32.powi(-14)), 0x0400);
+        assert_eq!(f32_to_f16(1023.0 * 2.0f32.powi(-24)), 0x03ff);
+        assert_eq!(f32_to_f16(2.0f32.powi(-24)), 0x0001);
+        assert_eq!(f32_to_f16(-2.0f32.powi(-24)), 0x8001);
+        // half the smallest subnormal is a tie, rounding to zero
+        assert_eq!(f32_to_f16(2.0f32.powi(-25)), 0x0000);
+        assert_eq!(f32_to_f16(1.5 * 2.0f32.powi(-25)), 0x0001);
+        assert_eq!(f32_to_f16(1.0e-10), 0x0000);
+        // the largest subnormal rounds up into the smallest normal
+        assert_eq!(f32_to_f16(2.0f32.powi(-14) - 2.0f32.powi(-26)), 0x0400);
+    }
+}
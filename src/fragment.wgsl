@@ -0,0 +1,37 @@
+// Fragment entry point of the mesh shaders, included after `shade` is
+// defined. Defines:
+// - WEIGHTED_BLENDED: write the weighted blended transparency targets
+#ifdef WEIGHTED_BLENDED
+// Weighted blended order-independent transparency (McGuire and Bavoil 2013).
+// Both targets are blended, see transparency.rs for the blend states.
+struct OitOutput {
+    // premultiplied color and alpha, scaled by the weight
+    @location(0) accum: vec4<f32>,
+    // alpha, multiplied into the destination
+    @location(1) revealage: f32,
+}
+
+fn oit_output(color: vec4<f32>, depth: f32) -> OitOutput {
+    let a = color.a;
+    // closer and more opaque fragments dominate the average
+    let weight = clamp(
+        pow(min(1.0, a * 10.0) + 0.01, 3.0) * 1e8 * pow(1.0 - depth * 0.9, 3.0),
+        1e-2,
+        3e3,
+    );
+    var out: OitOutput;
+    out.accum = vec4<f32>(color.rgb * a, a) * weight;
+    out.revealage = a;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> OitOutput {
+    return oit_output(shade(in), in.position.z);
+}
+#else
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return shade(in);
+}
+#endif
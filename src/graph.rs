@@ -0,0 +1,610 @@
+//! A per-frame graph of render passes.
+//!
+//! Passes declare the resources they read and write and a closure recording
+//! their commands. `RenderGraph::execute` orders them, skips the ones nothing
+//! depends on, allocates the transient textures they draw into and records
+//! everything into one command encoder.
+//!
+//! Ordering follows from the declarations, not from the order passes were
+//! added in:
+//!
+//! - Passes writing the same resource run in the order they were added, each
+//!   one building on what the previous ones wrote.
+//! - A pass that only reads a resource runs after every pass writing it.
+//!   Passes that need an earlier state of a resource should read a copy.
+//!
+//! Resources are either transient textures created by the graph, or imported
+//! ones like the frame or the shadow maps, which the pass closures capture
+//! themselves. Writing an imported resource is the result of a frame, so
+//! those passes are always kept, as are passes marked with
+//! `PassDesc::side_effect`. Other passes only run when a kept pass depends
+//! on them.
+//!
+//! Transient textures with the same description whose lifetimes do not
+//! overlap share one allocation. `TransientTextures` keeps them between
+//! frames and recreates the ones sized by the surface when it is resized.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ResourceId(usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PassId(usize);
+
+/// Size of a transient texture
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TextureSize {
+    /// The surface size, following resizes
+    Surface,
+    Fixed {
+        width: u32,
+        height: u32,
+    },
+}
+
+impl TextureSize {
+    fn resolve(self, surface: (u32, u32)) -> (u32, u32) {
+        match self {
+            Self::Surface => surface,
+            Self::Fixed { width, height } => (width, height),
+        }
+    }
+}
+
+/// A texture the graph allocates, only valid during the frame
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TextureDesc {
+    pub label: String,
+    pub size: TextureSize,
+    pub format: wgpu::TextureFormat,
+    pub sample_count: u32,
+    pub usage: wgpu::TextureUsages,
+}
+
+impl TextureDesc {
+    /// A single sampled render attachment the size of the surface
+    pub fn new(label: impl Into<String>, format: wgpu::TextureFormat) -> Self {
+        Self {
+            label: label.into(),
+            size: TextureSize::Surface,
+            format,
+            sample_count: 1,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        }
+    }
+
+    pub fn with_size(mut self, size: TextureSize) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn with_sample_count(mut self, sample_count: u32) -> Self {
+        self.sample_count = sample_count;
+        self
+    }
+
+    pub fn with_usage(mut self, usage: wgpu::TextureUsages) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Whether an allocation for `self` can hold `other`, labels aside
+    fn compatible(&self, other: &Self) -> bool {
+        self.size == other.size
+            && self.format == other.format
+            && self.sample_count == other.sample_count
+            && self.usage == other.usage
+    }
+}
+
+/// What a pass reads and writes
+#[derive(Clone, Debug)]
+pub struct PassDesc {
+    name: String,
+    reads: Vec<ResourceId>,
+    writes: Vec<ResourceId>,
+    side_effect: bool,
+}
+
+impl PassDesc {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            reads: Vec::new(),
+            writes: Vec::new(),
+            side_effect: false,
+        }
+    }
+
+    pub fn read(mut self, resource: ResourceId) -> Self {
+        self.reads.push(resource);
+        self
+    }
+
+    /// Declares a write. Render attachments that are loaded rather than
+    /// cleared are writes too.
+    pub fn write(mut self, resource: ResourceId) -> Self {
+        self.writes.push(resource);
+        self
+    }
+
+    /// Keeps the pass even when nothing reads what it writes, for passes
+    /// with results outside the graph like frame captures
+    pub fn side_effect(mut self) -> Self {
+        self.side_effect = true;
+        self
+    }
+
+    fn accesses(&self, resource: ResourceId) -> bool {
+        self.reads.contains(&resource) || self.writes.contains(&resource)
+    }
+}
+
+/// Handed to pass closures while recording
+pub struct PassContext<'a> {
+    pub encoder: &'a mut wgpu::CommandEncoder,
+    pub textures: &'a PassTextures<'a>,
+}
+
+/// Views of the transient textures of a frame
+pub struct PassTextures<'a> {
+    pass: &'a PassDesc,
+    slots: &'a [Option<usize>],
+    textures: &'a TransientTextures,
+}
+
+impl PassTextures<'_> {
+    /// The view of a transient texture the pass declared
+    pub fn view(&self, resource: ResourceId) -> &wgpu::TextureView {
+        assert!(
+            self.pass.accesses(resource),
+            "{} did not declare {resource:?}",
+            self.pass.name
+        );
+        let slot = self.slots[resource.0]
+            .unwrap_or_else(|| panic!("{resource:?} is not a transient texture"));
+        &self.textures.textures[slot].view
+    }
+}
+
+enum Resource {
+    Transient(TextureDesc),
+    Imported(String),
+}
+
+impl Resource {
+    fn label(&self) -> &str {
+        match self {
+            Self::Transient(desc) => &desc.label,
+            Self::Imported(label) => label,
+        }
+    }
+}
+
+type RecordFn<'g> = Box<dyn FnOnce(&mut PassContext) + 'g>;
+
+/// Passes and resources of one frame, see the module documentation
+#[derive(Default)]
+pub struct RenderGraph<'g> {
+    resources: Vec<Resource>,
+    passes: Vec<(PassDesc, RecordFn<'g>)>,
+}
+
+/// The order passes run in and where transient textures live
+#[derive(Debug)]
+pub struct Schedule {
+    order: Vec<PassId>,
+    culled: Vec<PassId>,
+    /// Allocation of every transient texture, by resource
+    slots: Vec<Option<usize>>,
+    /// Description of every allocation, from its first texture
+    allocations: Vec<TextureDesc>,
+}
+
+impl Schedule {
+    /// Passes in the order they run
+    pub fn order(&self) -> &[PassId] {
+        &self.order
+    }
+
+    /// Passes that are skipped because nothing depends on them
+    pub fn culled(&self) -> &[PassId] {
+        &self.culled
+    }
+
+    /// The allocation a transient texture is placed in, `None` for imported
+    /// resources and textures of culled passes
+    pub fn slot(&self, resource: ResourceId) -> Option<usize> {
+        self.slots[resource.0]
+    }
+
+    /// Number of textures allocated for the transient ones
+    pub fn allocation_count(&self) -> usize {
+        self.allocations.len()
+    }
+}
+
+impl<'g> RenderGraph<'g> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declares a texture the graph allocates for the frame
+    pub fn create_texture(&mut self, desc: TextureDesc) -> ResourceId {
+        self.resources.push(Resource::Transient(desc));
+        ResourceId(self.resources.len() - 1)
+    }
+
+    /// Declares a texture or buffer that lives outside the graph
+    pub fn import(&mut self, label: impl Into<String>) -> ResourceId {
+        self.resources.push(Resource::Imported(label.into()));
+        ResourceId(self.resources.len() - 1)
+    }
+
+    /// Adds a pass recording its commands with `record` when the graph runs
+    pub fn add_pass(
+        &mut self,
+        desc: PassDesc,
+        record: impl FnOnce(&mut PassContext) + 'g,
+    ) -> PassId {
+        self.passes.push((desc, Box::new(record)));
+        PassId(self.passes.len() - 1)
+    }
+
+    pub fn pass_name(&self, pass: PassId) -> &str {
+        &self.passes[pass.0].0.name
+    }
+
+    /// Orders and culls the passes and assigns the transient textures to
+    /// allocations. Fails on cycles and transient textures that are read
+    /// but never written.
+    pub fn compile(&self) -> anyhow::Result<Schedule> {
+        let passes: Vec<&PassDesc> = self.passes.iter().map(|(desc, _)| desc).collect();
+        let passes = passes.as_slice();
+        let writers = |resource: ResourceId| {
+            (0..passes.len())
+                .filter(move |&pass| passes[pass].writes.contains(&resource))
+        };
+
+        for (pass, resource) in passes
+            .iter()
+            .flat_map(|pass| pass.reads.iter().map(move |&resource| (pass, resource)))
+        {
+            let transient =
+                matches!(self.resources[resource.0], Resource::Transient(_));
+            if transient && writers(resource).next().is_none() {
+                anyhow::bail!(
+                    "{} reads {}, which no pass writes",
+                    pass.name,
+                    self.resources[resource.0].label()
+                );
+            }
+        }
+
+        // passes producing a result, then everything they build on
+        let mut kept: Vec<bool> = passes
+            .iter()
+            .map(|pass| {
+                pass.side_effect
+                    || pass.writes.iter().any(|resource| {
+                        matches!(self.resources[resource.0], Resource::Imported(_))
+                    })
+            })
+            .collect();
+        let mut stack: Vec<usize> =
+            (0..passes.len()).filter(|&pass| kept[pass]).collect();
+        while let Some(pass) = stack.pop() {
+            for &resource in passes[pass].reads.iter().chain(&passes[pass].writes) {
+                for writer in writers(resource) {
+                    if !kept[writer] {
+                        kept[writer] = true;
+                        stack.push(writer);
+                    }
+                }
+            }
+        }
+
+        // edges from every pass to the ones that must run after it
+        let mut successors = vec![Vec::new(); passes.len()];
+        let mut predecessor_count = vec![0; passes.len()];
+        let mut add_edge = |from: usize, to: usize| {
+            if from != to && !successors[from].contains(&to) {
+                successors[from].push(to);
+                predecessor_count[to] += 1;
+            }
+        };
+        for resource in (0..self.resources.len()).map(ResourceId) {
+            let resource_writers: Vec<usize> =
+                writers(resource).filter(|&pass| kept[pass]).collect();
+            for pair in resource_writers.windows(2) {
+                add_edge(pair[0], pair[1]);
+            }
+            let readers = (0..passes.len()).filter(|&pass| {
+                kept[pass]
+                    && passes[pass].reads.contains(&resource)
+                    && !passes[pass].writes.contains(&resource)
+            });
+            for reader in readers {
+                for &writer in &resource_writers {
+                    add_edge(writer, reader);
+                }
+            }
+        }
+
+        // Kahn's algorithm, preferring passes added earlier
+        let mut ready: BinaryHeap<Reverse<usize>> = (0..passes.len())
+            .filter(|&pass| kept[pass] && predecessor_count[pass] == 0)
+            .map(Reverse)
+            .collect();
+        let mut order = Vec::new();
+        while let Some(Reverse(pass)) = ready.pop() {
+            order.push(pass);
+            for &next in &successors[pass] {
+                predecessor_count[next] -= 1;
+                if predecessor_count[next] == 0 {
+                    ready.push(Reverse(next));
+                }
+            }
+        }
+        let kept_count = kept.iter().filter(|&&kept| kept).count();
+        if order.len() < kept_count {
+            let cycle: Vec<&str> = (0..passes.len())
+                .filter(|&pass| kept[pass] && !order.contains(&pass))
+                .map(|pass| passes[pass].name.as_str())
+                .collect();
+            anyhow::bail!("Render passes depend on each other: {}", cycle.join(", "));
+        }
+
+        // first and last position in `order` of every transient texture, in
+        // the order they are first used
+        let mut lifetimes: Vec<(usize, usize, ResourceId)> = Vec::new();
+        for (resource, desc) in self.resources.iter().enumerate() {
+            if !matches!(desc, Resource::Transient(_)) {
+                continue;
+            }
+            let resource = ResourceId(resource);
+            let mut uses = order
+                .iter()
+                .enumerate()
+                .filter(|(_, &pass)| passes[pass].accesses(resource))
+                .map(|(position, _)| position);
+            if let Some(first) = uses.next() {
+                let last = uses.next_back().unwrap_or(first);
+                lifetimes.push((first, last, resource));
+            }
+        }
+        lifetimes.sort();
+
+        let mut slots = vec![None; self.resources.len()];
+        let mut allocations: Vec<TextureDesc> = Vec::new();
+        // position after which every allocation is free again
+        let mut free_after: Vec<usize> = Vec::new();
+        for (first, last, resource) in lifetimes {
+            let Resource::Transient(desc) = &self.resources[resource.0] else {
+                unreachable!("only transient textures have lifetimes");
+            };
+            let slot = (0..allocations.len())
+                .find(|&slot| {
+                    free_after[slot] < first && allocations[slot].compatible(desc)
+                })
+                .unwrap_or_else(|| {
+                    allocations.push(desc.clone());
+                    free_after.push(0);
+                    allocations.len() - 1
+                });
+            free_after[slot] = last;
+            slots[resource.0] = Some(slot);
+        }
+
+        Ok(Schedule {
+            order: order.into_iter().map(PassId).collect(),
+            culled: (0..passes.len())
+                .filter(|&pass| !kept[pass])
+                .map(PassId)
+                .collect(),
+            slots,
+            allocations,
+        })
+    }
+
+    /// Compiles the graph, allocates its transient textures for a surface
+    /// of `surface_size` and records every kept pass into `encoder`
+    pub fn execute(
+        mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        textures: &mut TransientTextures,
+        surface_size: (u32, u32),
+    ) -> anyhow::Result<()> {
+        let schedule = self.compile()?;
+        textures.prepare(device, &schedule.allocations, surface_size);
+
+        let mut passes: Vec<_> = self.passes.drain(..).map(Some).collect();
+        for pass in &schedule.order {
+            let (desc, record) =
+                passes[pass.0].take().expect("every pass is scheduled once");
+            let pass_textures = PassTextures {
+                pass: &desc,
+                slots: &schedule.slots,
+                textures,
+            };
+            record(&mut PassContext {
+                encoder,
+                textures: &pass_textures,
+            });
+        }
+        Ok(())
+    }
+}
+
+struct TransientTexture {
+    desc: TextureDesc,
+    /// Resolved size the texture was created with
+    size: (u32, u32),
+    view: wgpu::TextureView,
+}
+
+/// Allocations of transient textures, reused between frames
+#[derive(Default)]
+pub struct TransientTextures {
+    textures: Vec<TransientTexture>,
+}
+
+impl TransientTextures {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `textures[i]` match `allocations[i]`, keeping textures that
+    /// still fit and recreating the others
+    fn prepare(
+        &mut self,
+        device: &wgpu::Device,
+        allocations: &[TextureDesc],
+        surface_size: (u32, u32),
+    ) {
+        let mut previous: Vec<Option<TransientTexture>> =
+            self.textures.drain(..).map(Some).collect();
+        for desc in allocations {
+            let size = desc.size.resolve(surface_size);
+            let reused = previous.iter_mut().find_map(|texture| {
+                texture.take_if(|texture| {
+                    texture.size == size && texture.desc.compatible(desc)
+                })
+            });
+            let texture = reused.unwrap_or_else(|| TransientTexture {
+                desc: desc.clone(),
+                size,
+                view: create_texture(device, desc, size),
+            });
+            self.textures.push(texture);
+        }
+    }
+}
+
+fn create_texture(
+    device: &wgpu::Device,
+    desc: &TextureDesc,
+    (width, height): (u32, u32),
+) -> wgpu::TextureView {
+    device
+        .create_texture(&wgpu::TextureDescriptor {
+            label: Some(&desc.label),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: desc.sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: desc.format,
+            usage: desc.usage,
+            view_formats: &[],
+        })
+        .create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEPTH: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+    const COLOR: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+    fn order(graph: &RenderGraph) -> Vec<String> {
+        let schedule = graph.compile().unwrap_or_else(|e| panic!("{e:#}"));
+        schedule
+            .order()
+            .iter()
+            .map(|&pass| graph.pass_name(pass).to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn readers_run_after_writers_whatever_the_add_order() {
+        let mut graph = RenderGraph::new();
+        let shadows = graph.create_texture(TextureDesc::new("shadows", DEPTH));
+        let scene = graph.create_texture(TextureDesc::new("scene", COLOR));
+        let frame = graph.import("frame");
+        graph.add_pass(PassDesc::new("post").read(scene).write(frame), |_| {});
+        graph.add_pass(PassDesc::new("main").read(shadows).write(scene), |_| {});
+        graph.add_pass(PassDesc::new("shadow").write(shadows), |_| {});
+        assert_eq!(order(&graph), ["shadow", "main", "post"]);
+    }
+
+    #[test]
+    fn writers_of_one_resource_keep_their_add_order() {
+        let mut graph = RenderGraph::new();
+        let depth = graph.create_texture(TextureDesc::new("depth", DEPTH));
+        let scene = graph.import("scene");
+        let frame = graph.import("frame");
+        graph.add_pass(PassDesc::new("post").read(scene).write(frame), |_| {});
+        graph.add_pass(PassDesc::new("main").write(scene).write(depth), |_| {});
+        graph.add_pass(PassDesc::new("composite").write(scene), |_| {});
+        graph.add_pass(PassDesc::new("debug").read(depth).write(scene), |_| {});
+        assert_eq!(order(&graph), ["main", "composite", "debug", "post"]);
+    }
+
+    #[test]
+    fn passes_without_results_are_culled() {
+        let mut graph = RenderGraph::new();
+        let unused = graph.create_texture(TextureDesc::new("unused", COLOR));
+        let frame = graph.import("frame");
+        let unused_pass = graph.add_pass(PassDesc::new("unused").write(unused), |_| {});
+        graph.add_pass(PassDesc::new("capture").read(frame).side_effect(), |_| {});
+        graph.add_pass(PassDesc::new("main").write(frame), |_| {});
+        let schedule = graph.compile().unwrap();
+        assert_eq!(schedule.culled(), [unused_pass]);
+        assert_eq!(schedule.slot(unused), None);
+        assert_eq!(order(&graph), ["main", "capture"]);
+    }
+
+    #[test]
+    fn cycles_are_errors() {
+        let mut graph = RenderGraph::new();
+        let a = graph.create_texture(TextureDesc::new("a", COLOR));
+        let b = graph.create_texture(TextureDesc::new("b", COLOR));
+        let frame = graph.import("frame");
+        graph.add_pass(PassDesc::new("first").read(a).write(b), |_| {});
+        graph.add_pass(PassDesc::new("second").read(b).write(a), |_| {});
+        graph.add_pass(PassDesc::new("output").read(a).write(frame), |_| {});
+        let error = graph.compile().unwrap_err().to_string();
+        assert!(error.contains("first, second"), "{error}");
+    }
+
+    #[test]
+    fn reading_unwritten_transients_is_an_error() {
+        let mut graph = RenderGraph::new();
+        let depth = graph.create_texture(TextureDesc::new("depth", DEPTH));
+        let frame = graph.import("frame");
+        graph.add_pass(PassDesc::new("main").read(depth).write(frame), |_| {});
+        let error = graph.compile().unwrap_err().to_string();
+        assert_eq!(error, "main reads depth, which no pass writes");
+    }
+
+    #[test]
+    fn transients_share_allocations_when_their_lifetimes_do_not_overlap() {
+        let mut graph = RenderGraph::new();
+        let a = graph.create_texture(TextureDesc::new("a", COLOR));
+        let b = graph.create_texture(TextureDesc::new("b", COLOR));
+        let c = graph.create_texture(TextureDesc::new("c", COLOR));
+        let multisampled = graph.create_texture(
+            TextureDesc::new("multisampled", COLOR).with_sample_count(4),
+        );
+        let frame = graph.import("frame");
+        graph.add_pass(PassDesc::new("1").write(a), |_| {});
+        graph.add_pass(PassDesc::new("2").read(a).write(b), |_| {});
+        graph.add_pass(PassDesc::new("3").read(b).write(c), |_| {});
+        graph.add_pass(PassDesc::new("4").read(c).write(multisampled), |_| {});
+        graph.add_pass(PassDesc::new("5").read(multisampled).write(frame), |_| {});
+        let schedule = graph.compile().unwrap();
+        // a is done once b is written, b and c overlap in pass 3
+        assert_eq!(schedule.slot(a), schedule.slot(c));
+        assert_ne!(schedule.slot(a), schedule.slot(b));
+        assert_ne!(schedule.slot(multisampled), schedule.slot(a));
+        assert_ne!(schedule.slot(multisampled), schedule.slot(b));
+        assert_eq!(schedule.allocation_count(), 3);
+    }
+}
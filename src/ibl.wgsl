@@ -0,0 +1,181 @@
+// Precomputes the image based lighting maps of an environment cube map.
+// Every entry point renders one face (or the BRDF lookup table) with a
+// fullscreen triangle.
+
+struct Params {
+    zenith: vec4<f32>,
+    horizon: vec4<f32>,
+    ground: vec4<f32>,
+    face: u32,
+    roughness: f32,
+}
+
+@group(0) @binding(0)
+var<uniform> params: Params;
+@group(0) @binding(1)
+var source: texture_cube<f32>;
+@group(0) @binding(2)
+var source_sampler: sampler;
+@group(0) @binding(3)
+var equirect: texture_2d<f32>;
+
+const PI: f32 = 3.14159265359;
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    // (0, 0) in the top left corner
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vs_fullscreen(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+// Direction through `uv` on a cube face, in the WebGPU face order
+fn face_direction(face: u32, uv: vec2<f32>) -> vec3<f32> {
+    let s = uv.x * 2.0 - 1.0;
+    let t = uv.y * 2.0 - 1.0;
+    var dir: vec3<f32>;
+    switch face {
+        case 0u: { dir = vec3<f32>(1.0, -t, -s); }
+        case 1u: { dir = vec3<f32>(-1.0, -t, s); }
+        case 2u: { dir = vec3<f32>(s, 1.0, t); }
+        case 3u: { dir = vec3<f32>(s, -1.0, -t); }
+        case 4u: { dir = vec3<f32>(s, -t, 1.0); }
+        default: { dir = vec3<f32>(-s, -t, -1.0); }
+    }
+    return normalize(dir);
+}
+
+// Orthonormal basis with `n` as its z axis
+fn tangent_frame(n: vec3<f32>) -> mat3x3<f32> {
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if abs(n.y) > 0.999 {
+        up = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    let tangent = normalize(cross(up, n));
+    let bitangent = cross(n, tangent);
+    return mat3x3<f32>(tangent, bitangent, n);
+}
+
+fn radical_inverse(bits_in: u32) -> f32 {
+    var bits = (bits_in << 16u) | (bits_in >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return f32(bits) * 2.3283064365386963e-10;
+}
+
+fn hammersley(i: u32, count: u32) -> vec2<f32> {
+    return vec2<f32>(f32(i) / f32(count), radical_inverse(i));
+}
+
+// GGX distributed half vector in tangent space
+fn importance_sample_ggx(xi: vec2<f32>, roughness: f32) -> vec3<f32> {
+    let a = roughness * roughness;
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    return vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+}
+
+@fragment
+fn fs_gradient(in: VertexOutput) -> @location(0) vec4<f32> {
+    let dir = face_direction(params.face, in.uv);
+    if dir.y >= 0.0 {
+        let t = pow(dir.y, 0.5);
+        return vec4<f32>(mix(params.horizon.rgb, params.zenith.rgb, t), 1.0);
+    }
+    let t = pow(-dir.y, 0.5);
+    return vec4<f32>(mix(params.horizon.rgb, params.ground.rgb, t), 1.0);
+}
+
+// Projects an equirectangular (latitude/longitude) image onto a face
+@fragment
+fn fs_equirect(in: VertexOutput) -> @location(0) vec4<f32> {
+    let dir = face_direction(params.face, in.uv);
+    let longitude = atan2(dir.z, dir.x);
+    let latitude = asin(clamp(dir.y, -1.0, 1.0));
+    let uv = vec2<f32>(longitude / (2.0 * PI) + 0.5, 0.5 - latitude / PI);
+    return vec4<f32>(textureSampleLevel(equirect, source_sampler, uv, 0.0).rgb, 1.0);
+}
+
+// Cosine weighted convolution of the hemisphere around each direction
+@fragment
+fn fs_irradiance(in: VertexOutput) -> @location(0) vec4<f32> {
+    let frame = tangent_frame(face_direction(params.face, in.uv));
+    let delta = 0.05;
+    var irradiance = vec3<f32>(0.0);
+    var samples = 0.0;
+    for (var phi = 0.0; phi < 2.0 * PI; phi += delta) {
+        for (var theta = 0.0; theta < 0.5 * PI; theta += delta) {
+            let local = vec3<f32>(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            let color = textureSampleLevel(source, source_sampler, frame * local, 0.0).rgb;
+            irradiance += color * cos(theta) * sin(theta);
+            samples += 1.0;
+        }
+    }
+    return vec4<f32>(PI * irradiance / samples, 1.0);
+}
+
+const PREFILTER_SAMPLES: u32 = 256u;
+
+// Specular lobe of `params.roughness` around each direction, assuming the
+// view direction equals the normal
+@fragment
+fn fs_prefilter(in: VertexOutput) -> @location(0) vec4<f32> {
+    let n = face_direction(params.face, in.uv);
+    let frame = tangent_frame(n);
+    var color = vec3<f32>(0.0);
+    var weight = 0.0;
+    for (var i = 0u; i < PREFILTER_SAMPLES; i++) {
+        let h = frame * importance_sample_ggx(hammersley(i, PREFILTER_SAMPLES), params.roughness);
+        let l = normalize(2.0 * dot(n, h) * h - n);
+        let n_dot_l = dot(n, l);
+        if n_dot_l > 0.0 {
+            color += textureSampleLevel(source, source_sampler, l, 0.0).rgb * n_dot_l;
+            weight += n_dot_l;
+        }
+    }
+    return vec4<f32>(color / max(weight, 0.0001), 1.0);
+}
+
+const BRDF_SAMPLES: u32 = 512u;
+
+fn geometry_schlick_ggx_ibl(n_dot_v: f32, roughness: f32) -> f32 {
+    let k = roughness * roughness / 2.0;
+    return n_dot_v / (n_dot_v * (1.0 - k) + k);
+}
+
+// Split sum scale (r) and bias (g) applied to F0, indexed by
+// (n dot v, roughness)
+@fragment
+fn fs_brdf_lut(in: VertexOutput) -> @location(0) vec4<f32> {
+    let n_dot_v = max(in.uv.x, 0.0001);
+    let roughness = in.uv.y;
+    let v = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+    var scale = 0.0;
+    var bias = 0.0;
+    for (var i = 0u; i < BRDF_SAMPLES; i++) {
+        let h = importance_sample_ggx(hammersley(i, BRDF_SAMPLES), roughness);
+        let l = normalize(2.0 * dot(v, h) * h - v);
+        let n_dot_l = max(l.z, 0.0);
+        let n_dot_h = max(h.z, 0.0);
+        let v_dot_h = max(dot(v, h), 0.0);
+        if n_dot_l > 0.0 {
+            let g = geometry_schlick_ggx_ibl(n_dot_v, roughness)
+                * geometry_schlick_ggx_ibl(n_dot_l, roughness);
+            let g_vis = g * v_dot_h / (n_dot_h * n_dot_v);
+            let fc = pow(1.0 - v_dot_h, 5.0);
+            scale += (1.0 - fc) * g_vis;
+            bias += fc * g_vis;
+        }
+    }
+    return vec4<f32>(scale, bias, 0.0, 1.0) / vec4<f32>(f32(BRDF_SAMPLES), f32(BRDF_SAMPLES), 1.0, 1.0);
+}
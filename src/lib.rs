@@ -2,13 +2,27 @@ use bevy_input::ButtonInput;
 use glam::Vec2;
 use winit::keyboard::KeyCode;
 
+pub mod app;
 pub mod camera;
+pub mod capture;
+pub mod debug_draw;
+pub mod environment;
+pub mod graph;
+pub mod light;
+pub mod material;
 pub mod mesh;
+pub mod post;
 pub mod render;
+pub mod shader;
+pub mod shadow;
+mod skybox;
 pub mod texture;
 pub mod time;
 pub mod transform;
+pub mod transparency;
 pub mod vertex;
+pub mod view;
+pub mod window;
 
 pub struct Input {
     pub keyboard: ButtonInput<KeyCode>,
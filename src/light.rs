@@ -0,0 +1,119 @@
+use glam::Vec3;
+
+pub const MAX_DIRECTIONAL_LIGHTS: usize = 2;
+pub const MAX_SPOT_LIGHTS: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct DirectionalLight {
+    /// Direction the light travels in
+    pub direction: Vec3,
+    pub color: Vec3,
+    pub intensity: f32,
+    pub casts_shadows: bool,
+}
+
+impl Default for DirectionalLight {
+    fn default() -> Self {
+        Self {
+            direction: Vec3::new(-0.4, -1.0, -0.3).normalize(),
+            color: Vec3::ONE,
+            intensity: 1.0,
+            casts_shadows: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SpotLight {
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub color: Vec3,
+    pub intensity: f32,
+    /// Distance at which the light has faded out completely
+    pub range: f32,
+    /// Half angle in radians of the fully lit inner cone
+    pub inner_angle: f32,
+    /// Half angle in radians at which the light has faded out
+    pub outer_angle: f32,
+    pub casts_shadows: bool,
+}
+
+impl Default for SpotLight {
+    fn default() -> Self {
+        Self {
+            position: Vec3::new(0.0, 5.0, 0.0),
+            direction: Vec3::NEG_Y,
+            color: Vec3::ONE,
+            intensity: 1.0,
+            range: 20.0,
+            inner_angle: 20.0_f32.to_radians(),
+            outer_angle: 30.0_f32.to_radians(),
+            casts_shadows: true,
+        }
+    }
+}
+
+/// The lights `Render` shades the scene with. Lights beyond
+/// `MAX_DIRECTIONAL_LIGHTS` and `MAX_SPOT_LIGHTS` are ignored.
+#[derive(Clone, Debug)]
+pub struct Lights {
+    /// Constant light added to flat shaded meshes
+    pub ambient: Vec3,
+    /// Scales the image based lighting of PBR materials
+    pub environment_intensity: f32,
+    pub directional: Vec<DirectionalLight>,
+    pub spot: Vec<SpotLight>,
+}
+
+impl Default for Lights {
+    fn default() -> Self {
+        Self {
+            ambient: Vec3::splat(0.15),
+            environment_intensity: 1.0,
+            directional: vec![DirectionalLight::default()],
+            spot: Vec::new(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct DirectionalLightUniform {
+    /// xyz: direction, w: 1.0 when casting shadows
+    pub direction: [f32; 4],
+    /// rgb: color scaled by intensity
+    pub color: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SpotLightUniform {
+    /// xyz: position, w: range
+    pub position: [f32; 4],
+    /// xyz: direction, w: 1.0 when casting shadows
+    pub direction: [f32; 4],
+    /// rgb: color scaled by intensity
+    pub color: [f32; 4],
+    /// x: cosine of the inner angle, y: cosine of the outer angle
+    pub cone: [f32; 4],
+}
+
+/// Mirrors `Lights` in lights.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LightsUniform {
+    /// rgb: ambient, w: environment intensity
+    pub ambient: [f32; 4],
+    /// x: directional lights, y: spot lights, z: cascades per directional light
+    pub counts: [u32; 4],
+    /// View space distance at which each cascade ends
+    pub cascade_splits: [f32; 4],
+    /// x: normal bias, y: shadow map texel size, z: PCF radius in texels
+    pub shadow: [f32; 4],
+    pub directional: [DirectionalLightUniform; MAX_DIRECTIONAL_LIGHTS],
+    pub spot: [SpotLightUniform; MAX_SPOT_LIGHTS],
+    pub shadow_matrices: [[[f32; 4]; 4]; crate::shadow::MAX_SHADOW_MAPS],
+}
+
+unsafe impl bytemuck::Pod for LightsUniform {}
+unsafe impl bytemuck::Zeroable for LightsUniform {}
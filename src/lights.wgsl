@@ -0,0 +1,121 @@
+#include "camera.wgsl"
+
+struct DirectionalLight {
+    // w: 1.0 when casting shadows
+    direction: vec4<f32>,
+    color: vec4<f32>,
+}
+
+struct SpotLight {
+    // w: range
+    position: vec4<f32>,
+    // w: 1.0 when casting shadows
+    direction: vec4<f32>,
+    color: vec4<f32>,
+    // x: cos(inner angle), y: cos(outer angle)
+    cone: vec4<f32>,
+}
+
+// keep in sync with light::LightsUniform
+struct Lights {
+    // w: environment (image based lighting) intensity
+    ambient: vec4<f32>,
+    // x: directional lights, y: spot lights, z: cascades
+    counts: vec4<u32>,
+    cascade_splits: vec4<f32>,
+    // x: normal bias, y: texel size, z: pcf radius
+    shadow: vec4<f32>,
+    directional: array<DirectionalLight, 2>,
+    spot: array<SpotLight, 4>,
+    shadow_matrices: array<mat4x4<f32>, 12>,
+}
+
+const MAX_CASCADES: u32 = 4u;
+const FIRST_SPOT_LAYER: u32 = 8u;
+
+@group(1) @binding(0)
+var<uniform> lights: Lights;
+@group(1) @binding(1)
+var shadow_maps: texture_depth_2d_array;
+@group(1) @binding(2)
+var shadow_sampler: sampler_comparison;
+
+// 1.0 when fully lit, 0.0 when fully in shadow
+fn shadow_factor(layer: u32, world_position: vec3<f32>, normal: vec3<f32>) -> f32 {
+    let biased = world_position + normal * lights.shadow.x;
+    let clip = lights.shadow_matrices[layer] * vec4<f32>(biased, 1.0);
+    let ndc = clip.xyz / clip.w;
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5, 0.5);
+    if ndc.z > 1.0 || any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) {
+        return 1.0;
+    }
+
+    let radius = i32(lights.shadow.z);
+    var lit = 0.0;
+    var samples = 0.0;
+    for (var x = -radius; x <= radius; x++) {
+        for (var y = -radius; y <= radius; y++) {
+            let offset = vec2<f32>(f32(x), f32(y)) * lights.shadow.y;
+            lit += textureSampleCompareLevel(shadow_maps, shadow_sampler, uv + offset, layer, ndc.z);
+            samples += 1.0;
+        }
+    }
+    return lit / samples;
+}
+
+fn directional_shadow(light: u32, world_position: vec3<f32>, normal: vec3<f32>) -> f32 {
+    let depth = dot(world_position - camera.position.xyz, camera.forward.xyz);
+    for (var cascade = 0u; cascade < lights.counts.z; cascade++) {
+        if depth < lights.cascade_splits[cascade] {
+            return shadow_factor(light * MAX_CASCADES + cascade, world_position, normal);
+        }
+    }
+    return 1.0;
+}
+
+// Light arriving at a surface point from one light, shadows included
+struct LightSample {
+    // towards the light
+    direction: vec3<f32>,
+    radiance: vec3<f32>,
+}
+
+fn directional_light(i: u32, world_position: vec3<f32>, normal: vec3<f32>) -> LightSample {
+    let directional = lights.directional[i];
+    let l = -directional.direction.xyz;
+    var shadow = 1.0;
+    if directional.direction.w > 0.5 && dot(normal, l) > 0.0 {
+        shadow = directional_shadow(i, world_position, normal);
+    }
+    return LightSample(l, directional.color.rgb * shadow);
+}
+
+fn spot_light(i: u32, world_position: vec3<f32>, normal: vec3<f32>) -> LightSample {
+    let spot = lights.spot[i];
+    let to_light = spot.position.xyz - world_position;
+    let distance = length(to_light);
+    let l = to_light / distance;
+    let falloff = pow(clamp(1.0 - distance / spot.position.w, 0.0, 1.0), 2.0);
+    let cone = smoothstep(spot.cone.y, spot.cone.x, dot(-l, spot.direction.xyz));
+    var shadow = 1.0;
+    if spot.direction.w > 0.5 && dot(normal, l) * cone > 0.0 {
+        shadow = shadow_factor(FIRST_SPOT_LAYER + i, world_position, normal);
+    }
+    return LightSample(l, spot.color.rgb * falloff * cone * shadow);
+}
+
+fn lighting(world_position: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    var light = lights.ambient.rgb;
+
+    for (var i = 0u; i < lights.counts.x; i++) {
+        let sample = directional_light(i, world_position, normal);
+        light += sample.radiance * max(dot(normal, sample.direction), 0.0);
+    }
+
+    for (var i = 0u; i < lights.counts.y; i++) {
+        let sample = spot_light(i, world_position, normal);
+        light += sample.radiance * max(dot(normal, sample.direction), 0.0);
+    }
+
+    return light;
+}
@@ -1,111 +1,135 @@
-#![allow(clippy::collapsible_match)]
-use glam::Vec3;
-use rust_graphics::camera::{Camera, Perspective, Projection};
-use rust_graphics::mesh::Mesh;
-use rust_graphics::time;
+use glam::{Quat, Vec3, Vec4};
+use rust_graphics::app::{self, App, AppConfig, Context};
+use rust_graphics::camera::{Camera, Perspective};
+use rust_graphics::capture::{self, Recorder, RecordingFormat};
+use rust_graphics::debug_draw::{self, DrawOptions};
+use rust_graphics::material::PbrMaterial;
+use rust_graphics::mesh::{LoadOptions, Mesh};
+use rust_graphics::render::{Render, RenderSettings};
 use rust_graphics::transform::Transform;
+use rust_graphics::view::{View, Viewport};
+use rust_graphics::window::WindowConfig;
 use rust_graphics::Entity;
-use rust_graphics::{render::Render, Input};
-use winit::event::DeviceEvent;
-use winit::{
-    dpi::{LogicalSize, Size},
-    event::{Event, WindowEvent},
-    event_loop::{ControlFlow, EventLoop, EventLoopWindowTarget},
-    keyboard::PhysicalKey,
-    window::Window,
-};
+use winit::keyboard::KeyCode;
+use winit::window::CursorGrabMode;
 
 use ::anyhow::Result;
 
-async fn run(event_loop: EventLoop<()>, window: Window) {
-    time::startup();
-    window
-        .set_cursor_grab(winit::window::CursorGrabMode::Locked)
-        .unwrap();
-    window.set_cursor_visible(false);
+/// Where the minimap is drawn, in the top right corner
+const MINIMAP_VIEWPORT: Viewport = Viewport::new(0.7, 0.05, 0.25, 0.25);
 
-    let mut render = Render::new(window, Perspective).await;
-    let mut input = rust_graphics::Input::default();
-    let mut camera = Camera::new(
-        90.0,
-        render.size.width as f32 / render.size.height as f32,
-        Perspective,
-        Transform::from_translation(Vec3::new(0.0, 0.0, -10.0)),
-    );
-    let mut meshes: Vec<Mesh> = vec![Mesh::from(
-        std::env::current_dir().unwrap().join("assets/teapot.obj"),
-    )];
+/// Top down picture in picture view of the scene, toggled with F10
+struct Minimap {
+    camera: Camera<Perspective>,
+    visible: bool,
+}
 
-    event_loop.set_control_flow(ControlFlow::Poll);
-    event_loop
-        .run(move |event, target| {
-            // Pre Update
-            event_handler(event, target, &mut render, &mut input, &mut camera, &meshes);
+/// The teapot scene. F12 saves a screenshot and F11 starts or stops a
+/// recording.
+struct Demo {
+    meshes: Vec<Mesh>,
+    minimap: Minimap,
+    recorder: Option<Recorder>,
+}
 
-            // Update
-            update(&mut meshes);
+impl App for Demo {
+    fn init(&mut self, ctx: &mut Context) -> Result<()> {
+        ctx.camera.transform = Transform::from_translation(Vec3::new(0.0, 0.0, -10.0));
+        let teapot_material = ctx.render.add_material(PbrMaterial::new(
+            Vec4::new(0.9, 0.6, 0.3, 1.0),
+            1.0,
+            0.35,
+        ));
+        self.meshes.push(
+            Mesh::load(
+                std::env::current_dir()?.join("assets/teapot.obj"),
+                LoadOptions::default(),
+            )?
+            .with_material(teapot_material),
+        );
+        Ok(())
+    }
 
-            // Post Update
-            time::update();
-            render.window().request_redraw();
-        })
-        .unwrap();
+    fn update(&mut self, ctx: &mut Context) {
+        let keyboard = &ctx.input.keyboard;
+        if keyboard.just_pressed(KeyCode::F12) {
+            ctx.render.capture_frame();
+        }
+        if keyboard.just_pressed(KeyCode::F11) {
+            toggle_recording(&mut self.recorder);
+        }
+        if keyboard.just_pressed(KeyCode::F10) {
+            self.minimap.visible = !self.minimap.visible;
+        }
+        update(&mut self.meshes);
+    }
+
+    fn render(&mut self, ctx: &mut Context) -> Result<(), wgpu::SurfaceError> {
+        // views fit the cameras to their viewports, the field is kept in
+        // step for anything else reading it
+        let size = ctx.render.size();
+        self.minimap.camera.aspect_ratio =
+            MINIMAP_VIEWPORT.aspect_ratio(size.width.max(1), size.height.max(1));
+        if self.recorder.is_some() {
+            ctx.render.capture_frame();
+        }
+        let mut views = vec![View::new(&*ctx.camera)];
+        if self.minimap.visible {
+            views.push(
+                View::new(&self.minimap.camera)
+                    .with_viewport(MINIMAP_VIEWPORT)
+                    .with_clear_color(wgpu::Color {
+                        r: 0.02,
+                        g: 0.02,
+                        b: 0.05,
+                        a: 1.0,
+                    }),
+            );
+        }
+        ctx.render.render_views(&views, &self.meshes)?;
+        save_capture(ctx.render, &mut self.recorder);
+        Ok(())
+    }
 }
 
-fn event_handler<P>(
-    event: Event<()>,
-    target: &EventLoopWindowTarget<()>,
-    state: &mut Render,
-    input: &mut Input,
-    camera: &mut Camera<P>,
-    meshes: &[Mesh],
-) where
-    P: Projection,
-{
-    if let Event::DeviceEvent { event, .. } = &event {
-        if let DeviceEvent::MouseMotion { delta } = event {
-            input.mouse_motion = (delta.0 as f32, delta.1 as f32).into();
+/// Writes the captured frame to the recording, or to `screenshots/` when it
+/// was captured with F12
+fn save_capture(state: &mut Render, recorder: &mut Option<Recorder>) {
+    let Some(capture) = state.take_capture() else {
+        return;
+    };
+    if let Some(active) = recorder {
+        if let Err(e) = capture.and_then(|image| active.write_frame(&image)) {
+            log::error!("Stopped recording: {e:#}");
+            *recorder = None;
         }
+        return;
+    }
+    let path = capture::screenshot_path("screenshots");
+    match capture.and_then(|image| capture::save_png(&image, &path)) {
+        Ok(()) => log::info!("Saved screenshot to {}", path.display()),
+        Err(e) => log::error!("Could not save screenshot: {e:#}"),
     }
-    if let Event::WindowEvent { event, .. } = event {
-        match event {
-            WindowEvent::Resized(new_size) => {
-                log::debug!("Resized to: {:?}", new_size);
-                state.resize(new_size, camera);
-            }
-            WindowEvent::RedrawRequested => {
-                // point camera at origin
-                // state.camera.point_at(glam::Vec3::splat(0.0));
-                match state.render(meshes) {
-                    Ok(_) => {}
-                    Err(wgpu::SurfaceError::Lost) => {
-                        log::error!("surface error: lost");
-                        state.resize(state.size, camera)
-                    }
-                    Err(wgpu::SurfaceError::OutOfMemory) => target.exit(),
-                    Err(e) => eprintln!("{e:?}"),
-                }
-            }
-            WindowEvent::KeyboardInput { event, .. } => {
-                match event.state {
-                    winit::event::ElementState::Pressed => {
-                        let PhysicalKey::Code(key) = event.physical_key else {
-                            unreachable!();
-                        };
-                        input.keyboard.press(key);
-                    }
-                    winit::event::ElementState::Released => {
-                        let PhysicalKey::Code(key) = event.physical_key else {
-                            unreachable!();
-                        };
-                        input.keyboard.release(key);
-                    }
-                }
-                state.window().request_redraw();
-            }
-            WindowEvent::CloseRequested => target.exit(),
-            _ => {}
+}
+
+/// Starts recording 30 frames per second to `recordings/<time>/`, or stops
+/// the running recording
+fn toggle_recording(recorder: &mut Option<Recorder>) {
+    if let Some(active) = recorder.take() {
+        let path = active.path().to_owned();
+        match active.finish() {
+            Ok(frames) => log::info!("Recorded {frames} frames to {}", path.display()),
+            Err(e) => log::error!("Could not finish the recording: {e:#}"),
         }
+        return;
+    }
+    let millis = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis());
+    let path = std::path::Path::new("recordings").join(millis.to_string());
+    match Recorder::new(RecordingFormat::PngSequence, &path, 30) {
+        Ok(active) => *recorder = Some(active),
+        Err(e) => log::error!("Could not start recording: {e:#}"),
     }
 }
 
@@ -113,23 +137,56 @@ fn update(entities: &mut [Mesh]) {
     for entity in entities.iter_mut() {
         entity.update();
     }
+
+    debug_draw::grid(
+        Vec3::new(0.0, -2.0, 0.0),
+        1.0,
+        20,
+        Vec4::new(0.4, 0.4, 0.4, 1.0),
+    );
+    debug_draw::axes(
+        Vec3::ZERO,
+        Quat::IDENTITY,
+        3.0,
+        DrawOptions::default().overlay(),
+    );
 }
 
 fn main() -> Result<()> {
-    let event_loop = EventLoop::new().unwrap();
-    #[allow(unused_mut)]
-    let mut builder = winit::window::WindowBuilder::new();
-    let window = builder
-        .with_resizable(false)
-        .with_inner_size(Size::Logical(LogicalSize {
-            width: 800.0,
-            height: 600.0,
-        }))
-        .build(&event_loop)
-        .unwrap();
-
     env_logger::init();
-    pollster::block_on(run(event_loop, window));
+    let mut minimap = Minimap {
+        camera: Camera::new(
+            60.0,
+            1.0,
+            Perspective,
+            Transform::from_translation(Vec3::new(0.0, 12.0, -2.0)),
+        ),
+        visible: false,
+    };
+    // straight down would be parallel to the up vector
+    minimap.camera.pitch = -1.4;
+    let demo = Demo {
+        meshes: Vec::new(),
+        minimap,
+        recorder: None,
+    };
 
-    Ok(())
+    let mut render = RenderSettings {
+        sample_count: 4,
+        ..Default::default()
+    };
+    render.present.frame_rate_cap = Some(120.0);
+    // edit the shader while the demo runs
+    if cfg!(debug_assertions) {
+        render.shader_path =
+            Some(concat!(env!("CARGO_MANIFEST_DIR"), "/src/shader.wgsl").into());
+    }
+    let config = AppConfig {
+        window: WindowConfig::default()
+            .with_cursor_grab(CursorGrabMode::Locked)
+            .with_cursor_visible(false),
+        render,
+        ..Default::default()
+    };
+    app::run(demo, config)
 }
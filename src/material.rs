@@ -0,0 +1,651 @@
+//! Materials: a shader, its factors and textures, and the render state it is
+//! drawn with.
+//!
+//! Materials are registered with `Render::add_material` and referenced from
+//! meshes through the returned `MaterialId`. The factors and textures follow
+//! the glTF 2.0 metallic/roughness conventions and are bound at group 2 for
+//! every shader. Pipelines are built on first use for every combination of
+//! shader, render state and pass, and shared by all materials using it.
+use crate::shader::{self, ShaderCache, ShaderDefs};
+use crate::texture::{self, Texture};
+use crate::transparency;
+use crate::vertex::Vertex;
+use glam::{Vec3, Vec4};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MaterialId(pub(crate) usize);
+
+/// The WGSL a material is drawn with
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MaterialShader {
+    /// Vertex color times the base color and its texture, ignoring lights
+    Unlit,
+    /// The unlit color lit by the scene lights. Meshes without a material
+    /// are drawn like this.
+    Lit,
+    /// Metallic/roughness shading with every factor and texture, lit by the
+    /// lights and the environment
+    Pbr,
+    /// A shader registered with `Render::add_shader`. It is preprocessed
+    /// like the built in ones and typically is
+    ///
+    /// ```wgsl
+    /// #include "mesh.wgsl"
+    ///
+    /// fn shade(in: VertexOutput) -> vec4<f32> {
+    ///     return surface_color(in);
+    /// }
+    ///
+    /// #include "fragment.wgsl"
+    /// ```
+    ///
+    /// but may also declare its own `vs_main` and `fs_main`.
+    Custom(String),
+}
+
+/// How a material blends with what is behind it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    /// `Alpha` when the base color alpha, or for vertex colored shaders any
+    /// vertex alpha, is below 1, otherwise `Opaque`
+    #[default]
+    Auto,
+    Opaque,
+    /// Drawn after opaque meshes, combined as `Render::transparency_mode`
+    /// says
+    Alpha,
+    /// Added onto the scene after opaque meshes, in any order
+    Additive,
+}
+
+/// Fixed function state of a material's pipelines
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RenderState {
+    /// Faces that are not drawn, `None` draws both sides
+    pub cull_mode: Option<wgpu::Face>,
+    /// Winding of front faces in screen space. OBJ files wind them
+    /// counter-clockwise, see `Mesh::fix_winding` for files that do not.
+    pub front_face: wgpu::FrontFace,
+    /// How the mesh indices are assembled. Only triangle lists cast shadows.
+    pub topology: wgpu::PrimitiveTopology,
+    /// `Line` and `Point` need `Features::POLYGON_MODE_LINE` or
+    /// `POLYGON_MODE_POINT`, see `RenderBuilder::required_features`
+    pub polygon_mode: wgpu::PolygonMode,
+    pub blend: BlendMode,
+    /// Whether opaque draws write depth. Blended draws never do.
+    pub depth_write: bool,
+    pub depth_compare: wgpu::CompareFunction,
+}
+
+impl Default for RenderState {
+    fn default() -> Self {
+        Self {
+            cull_mode: None,
+            front_face: wgpu::FrontFace::Ccw,
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            blend: BlendMode::Auto,
+            depth_write: true,
+            depth_compare: wgpu::CompareFunction::Less,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Material {
+    pub shader: MaterialShader,
+    /// Factors and textures. Unlit and lit shading only use the base color
+    /// and its texture.
+    pub params: PbrMaterial,
+    pub state: RenderState,
+}
+
+impl Material {
+    pub fn new(shader: MaterialShader, params: PbrMaterial) -> Self {
+        Self {
+            shader,
+            params,
+            state: RenderState::default(),
+        }
+    }
+
+    pub fn unlit(base_color: Vec4) -> Self {
+        Self::new(
+            MaterialShader::Unlit,
+            PbrMaterial::new(base_color, 0.0, 1.0),
+        )
+    }
+
+    pub fn lit(base_color: Vec4) -> Self {
+        Self::new(MaterialShader::Lit, PbrMaterial::new(base_color, 0.0, 1.0))
+    }
+
+    /// A material drawn with the shader `Render::add_shader` registered as
+    /// `name`
+    pub fn custom(name: impl Into<String>, params: PbrMaterial) -> Self {
+        Self::new(MaterialShader::Custom(name.into()), params)
+    }
+
+    pub fn with_state(mut self, state: RenderState) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Whether the shader reads the vertex colors
+    pub(crate) fn uses_vertex_colors(&self) -> bool {
+        matches!(self.shader, MaterialShader::Unlit | MaterialShader::Lit)
+    }
+}
+
+impl From<PbrMaterial> for Material {
+    fn from(params: PbrMaterial) -> Self {
+        Self::new(MaterialShader::Pbr, params)
+    }
+}
+
+#[derive(Clone)]
+pub struct PbrMaterial {
+    /// Linear RGBA, multiplied with the base color texture
+    pub base_color: Vec4,
+    pub metallic: f32,
+    pub roughness: f32,
+    /// Linear RGB, multiplied with the emissive texture
+    pub emissive: Vec3,
+    /// Scales the X and Y of the tangent space normals
+    pub normal_scale: f32,
+    pub occlusion_strength: f32,
+    /// sRGB color and alpha
+    pub base_color_texture: Option<Arc<Texture>>,
+    /// Linear, roughness in the green and metallic in the blue channel
+    pub metallic_roughness_texture: Option<Arc<Texture>>,
+    /// Linear tangent space normals
+    pub normal_texture: Option<Arc<Texture>>,
+    /// Linear, ambient occlusion in the red channel
+    pub occlusion_texture: Option<Arc<Texture>>,
+    /// sRGB emitted color
+    pub emissive_texture: Option<Arc<Texture>>,
+}
+
+impl Default for PbrMaterial {
+    fn default() -> Self {
+        Self {
+            base_color: Vec4::ONE,
+            metallic: 1.0,
+            roughness: 1.0,
+            emissive: Vec3::ZERO,
+            normal_scale: 1.0,
+            occlusion_strength: 1.0,
+            base_color_texture: None,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            occlusion_texture: None,
+            emissive_texture: None,
+        }
+    }
+}
+
+impl PbrMaterial {
+    /// An untextured material
+    pub fn new(base_color: Vec4, metallic: f32, roughness: f32) -> Self {
+        Self {
+            base_color,
+            metallic,
+            roughness,
+            ..Default::default()
+        }
+    }
+
+    /// Every texture, in binding order
+    pub(crate) fn textures(&self) -> [&Option<Arc<Texture>>; 5] {
+        [
+            &self.base_color_texture,
+            &self.metallic_roughness_texture,
+            &self.normal_texture,
+            &self.occlusion_texture,
+            &self.emissive_texture,
+        ]
+    }
+
+    pub(crate) fn textures_mut(&mut self) -> [&mut Option<Arc<Texture>>; 5] {
+        [
+            &mut self.base_color_texture,
+            &mut self.metallic_roughness_texture,
+            &mut self.normal_texture,
+            &mut self.occlusion_texture,
+            &mut self.emissive_texture,
+        ]
+    }
+
+    fn uniform(&self) -> MaterialUniform {
+        MaterialUniform {
+            base_color: self.base_color.into(),
+            emissive: self.emissive.extend(0.0).into(),
+            params: [
+                self.metallic,
+                self.roughness,
+                self.normal_scale,
+                self.occlusion_strength,
+            ],
+        }
+    }
+}
+
+/// Mirrors `Material` in material.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct MaterialUniform {
+    base_color: [f32; 4],
+    emissive: [f32; 4],
+    /// x: metallic, y: roughness, z: normal scale, w: occlusion strength
+    params: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for MaterialUniform {}
+unsafe impl bytemuck::Zeroable for MaterialUniform {}
+
+/// Textures bound in place of the ones a material leaves out
+pub(crate) struct DefaultTextures {
+    white: Texture,
+    flat_normal: Texture,
+    sampler: wgpu::Sampler,
+}
+
+impl DefaultTextures {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        Self {
+            white: texture::create_solid_texture(
+                device,
+                queue,
+                [255; 4],
+                false,
+                "default_white_texture",
+            ),
+            flat_normal: texture::create_solid_texture(
+                device,
+                queue,
+                [128, 128, 255, 255],
+                false,
+                "default_normal_texture",
+            ),
+            sampler: texture::create_material_sampler(device),
+        }
+    }
+}
+
+/// A material with its uniform buffer and bind group
+pub(crate) struct GpuMaterial {
+    pub material: Material,
+    uniform: wgpu::Buffer,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl GpuMaterial {
+    pub fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        defaults: &DefaultTextures,
+        material: Material,
+    ) -> Self {
+        let params = &material.params;
+        let uniform = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("material_uniform"),
+            size: std::mem::size_of::<MaterialUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: true,
+        });
+        uniform
+            .slice(..)
+            .get_mapped_range_mut()
+            .copy_from_slice(bytemuck::cast_slice(&[params.uniform()]));
+        uniform.unmap();
+
+        fn view<'t>(
+            texture: &'t Option<Arc<Texture>>,
+            default: &'t Texture,
+        ) -> wgpu::BindingResource<'t> {
+            wgpu::BindingResource::TextureView(match texture {
+                Some(texture) => &texture.view,
+                None => &default.view,
+            })
+        }
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("material_bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: view(&params.base_color_texture, &defaults.white),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: view(&params.metallic_roughness_texture, &defaults.white),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: view(&params.normal_texture, &defaults.flat_normal),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: view(&params.occlusion_texture, &defaults.white),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: view(&params.emissive_texture, &defaults.white),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::Sampler(&defaults.sampler),
+                },
+            ],
+        });
+
+        Self {
+            material,
+            uniform,
+            bind_group,
+        }
+    }
+
+    /// Updates the factors without recreating the bind group
+    pub fn write_factors(&mut self, queue: &wgpu::Queue) {
+        queue.write_buffer(
+            &self.uniform,
+            0,
+            bytemuck::cast_slice(&[self.material.params.uniform()]),
+        );
+    }
+}
+
+pub(crate) fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    let texture = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            view_dimension: wgpu::TextureViewDimension::D2,
+            multisampled: false,
+        },
+        count: None,
+    };
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("material_bind_group_layout"),
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            texture(1),
+            texture(2),
+            texture(3),
+            texture(4),
+            texture(5),
+            wgpu::BindGroupLayoutEntry {
+                binding: 6,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+        ],
+    })
+}
+
+/// Which pass a pipeline draws in, deciding its color targets
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Phase {
+    Opaque,
+    /// Alpha blended back to front
+    Alpha,
+    Additive,
+    /// Accumulated into the weighted blended transparency targets
+    WeightedBlended,
+}
+
+/// Everything a mesh pipeline is built from besides the shared layout,
+/// formats and sample count
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct PipelineKey {
+    shader: MaterialShader,
+    phase: Phase,
+    cull_mode: Option<wgpu::Face>,
+    front_face: wgpu::FrontFace,
+    topology: wgpu::PrimitiveTopology,
+    polygon_mode: wgpu::PolygonMode,
+    depth_write: bool,
+    depth_compare: wgpu::CompareFunction,
+}
+
+impl PipelineKey {
+    pub fn new(material: &Material, phase: Phase) -> Self {
+        Self {
+            shader: material.shader.clone(),
+            phase,
+            cull_mode: material.state.cull_mode,
+            front_face: material.state.front_face,
+            topology: material.state.topology,
+            polygon_mode: material.state.polygon_mode,
+            depth_write: phase == Phase::Opaque && material.state.depth_write,
+            depth_compare: material.state.depth_compare,
+        }
+    }
+}
+
+/// What mesh pipelines are compiled against
+pub(crate) struct PipelineContext<'a> {
+    pub device: &'a wgpu::Device,
+    /// Camera, lights, material and environment
+    pub layout: &'a wgpu::PipelineLayout,
+    pub shaders: &'a mut ShaderCache,
+    /// File name of the built in mesh shader in `shaders`
+    pub main_shader: &'a str,
+    pub format: wgpu::TextureFormat,
+    pub sample_count: u32,
+}
+
+/// Mesh pipelines by `PipelineKey`, built on first use
+#[derive(Default)]
+pub(crate) struct MaterialPipelines {
+    /// `None` for pipelines that failed to build, so they are not retried
+    /// every frame
+    pipelines: Vec<Option<wgpu::RenderPipeline>>,
+    indices: HashMap<PipelineKey, usize>,
+}
+
+impl MaterialPipelines {
+    /// Index of the pipeline for `key`, or `None` when it could not be built,
+    /// which is logged the first time
+    pub fn get_or_create(
+        &mut self,
+        key: &PipelineKey,
+        context: &mut PipelineContext,
+    ) -> Option<usize> {
+        if let Some(&index) = self.indices.get(key) {
+            return self.pipelines[index].is_some().then_some(index);
+        }
+        let pipeline = create_pipeline(key, context)
+            .map_err(|e| log::error!("Could not build the pipeline for {key:?}: {e:#}"))
+            .ok();
+        let index = self.pipelines.len();
+        self.indices.insert(key.clone(), index);
+        self.pipelines.push(pipeline);
+        self.pipelines[index].is_some().then_some(index)
+    }
+
+    pub fn get(&self, index: usize) -> &wgpu::RenderPipeline {
+        self.pipelines[index]
+            .as_ref()
+            .expect("indices are only handed out for built pipelines")
+    }
+
+    /// Builds every pipeline built so far again, for example from reloaded
+    /// shaders. Pipelines that built before fail the whole rebuild on their
+    /// first error, so the previous ones can be kept. Ones that never built
+    /// are retried and stay missing if they still fail.
+    pub fn rebuild(&self, context: &mut PipelineContext) -> anyhow::Result<Self> {
+        let mut rebuilt = Self::default();
+        for (key, &index) in &self.indices {
+            let pipeline = match create_pipeline(key, context) {
+                Ok(pipeline) => Some(pipeline),
+                Err(e) if self.pipelines[index].is_none() => {
+                    log::error!("Could not build the pipeline for {key:?}: {e:#}");
+                    None
+                }
+                Err(e) => return Err(e),
+            };
+            rebuilt.indices.insert(key.clone(), rebuilt.pipelines.len());
+            rebuilt.pipelines.push(pipeline);
+        }
+        Ok(rebuilt)
+    }
+
+    /// Forgets the keys and pipelines of a custom shader that was replaced
+    /// or removed
+    pub fn remove_shader(&mut self, name: &str) {
+        let shader = MaterialShader::Custom(name.to_owned());
+        let mut pipelines = std::mem::take(&mut self.pipelines);
+        for (key, index) in std::mem::take(&mut self.indices) {
+            if key.shader != shader {
+                self.indices.insert(key, self.pipelines.len());
+                self.pipelines.push(pipelines[index].take());
+            }
+        }
+    }
+
+    /// Whether `key` was asked for and built
+    #[cfg(test)]
+    pub fn is_built(&self, key: &PipelineKey) -> bool {
+        self.indices
+            .get(key)
+            .is_some_and(|&index| self.pipelines[index].is_some())
+    }
+
+    /// Number of keys asked for, built or not
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+}
+
+/// The shader and defines a pipeline key is compiled from
+pub(crate) fn shader_permutation<'a>(
+    shader: &'a MaterialShader,
+    phase: Phase,
+    main_shader: &'a str,
+) -> (&'a str, ShaderDefs) {
+    let (name, defs) = match shader {
+        MaterialShader::Unlit => (main_shader, ShaderDefs::new().with(shader::UNLIT)),
+        MaterialShader::Lit => (main_shader, ShaderDefs::new()),
+        MaterialShader::Pbr => (main_shader, ShaderDefs::new().with(shader::PBR)),
+        MaterialShader::Custom(name) => (name.as_str(), ShaderDefs::new()),
+    };
+    match phase {
+        Phase::WeightedBlended => (name, defs.with(shader::WEIGHTED_BLENDED)),
+        _ => (name, defs),
+    }
+}
+
+fn create_pipeline(
+    key: &PipelineKey,
+    context: &mut PipelineContext,
+) -> anyhow::Result<wgpu::RenderPipeline> {
+    let feature = match key.polygon_mode {
+        wgpu::PolygonMode::Fill => wgpu::Features::empty(),
+        wgpu::PolygonMode::Line => wgpu::Features::POLYGON_MODE_LINE,
+        wgpu::PolygonMode::Point => wgpu::Features::POLYGON_MODE_POINT,
+    };
+    if !context.device.features().contains(feature) {
+        anyhow::bail!(
+            "Polygon mode {:?} needs the {feature:?} feature",
+            key.polygon_mode
+        );
+    }
+    let (name, defs) = shader_permutation(&key.shader, key.phase, context.main_shader);
+    let module = context.shaders.get(context.device, name, &defs)?;
+
+    let blended = |blend| {
+        vec![Some(wgpu::ColorTargetState {
+            format: context.format,
+            blend: Some(blend),
+            write_mask: wgpu::ColorWrites::ALL,
+        })]
+    };
+    let targets = match key.phase {
+        Phase::Opaque => vec![Some(context.format.into())],
+        Phase::Alpha => blended(wgpu::BlendState::ALPHA_BLENDING),
+        Phase::Additive => blended(wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::SrcAlpha,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::Zero,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+        }),
+        Phase::WeightedBlended => transparency::oit_targets().to_vec(),
+    };
+
+    // custom shaders may not match the layout or targets, which wgpu reports
+    // as an error instead of a result
+    context
+        .device
+        .push_error_scope(wgpu::ErrorFilter::Validation);
+    let pipeline =
+        context
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(&format!("{name} {:?} pipeline", key.phase)),
+                layout: Some(context.layout),
+                vertex: wgpu::VertexState {
+                    module: &module,
+                    entry_point: "vs_main",
+                    buffers: &[Vertex::desc()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &module,
+                    entry_point: "fs_main",
+                    targets: &targets,
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: key.topology,
+                    // the shared index buffer is 16 bit
+                    strip_index_format: key
+                        .topology
+                        .is_strip()
+                        .then_some(wgpu::IndexFormat::Uint16),
+                    front_face: key.front_face,
+                    cull_mode: key.cull_mode,
+                    polygon_mode: key.polygon_mode,
+                    ..Default::default()
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: texture::DEPTH_FORMAT,
+                    depth_write_enabled: key.depth_write,
+                    depth_compare: key.depth_compare,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState {
+                    count: context.sample_count,
+                    ..Default::default()
+                },
+                multiview: None,
+            });
+    if let Some(error) = pollster::block_on(context.device.pop_error_scope()) {
+        anyhow::bail!("{error}");
+    }
+    Ok(pipeline)
+}
@@ -0,0 +1,24 @@
+// Material factors and textures, bound at group 2 for every mesh shader
+
+// keep in sync with material::MaterialUniform
+struct Material {
+    base_color: vec4<f32>,
+    emissive: vec4<f32>,
+    // x: metallic, y: roughness, z: normal scale, w: occlusion strength
+    params: vec4<f32>,
+}
+
+@group(2) @binding(0)
+var<uniform> material: Material;
+@group(2) @binding(1)
+var base_color_texture: texture_2d<f32>;
+@group(2) @binding(2)
+var metallic_roughness_texture: texture_2d<f32>;
+@group(2) @binding(3)
+var normal_texture: texture_2d<f32>;
+@group(2) @binding(4)
+var occlusion_texture: texture_2d<f32>;
+@group(2) @binding(5)
+var emissive_texture: texture_2d<f32>;
+@group(2) @binding(6)
+var material_sampler: sampler;
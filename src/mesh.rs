@@ -1,32 +1,51 @@
 use crate::time;
-use std::path::PathBuf;
+use anyhow::Context;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 
-use crate::{transform::Transform, vertex::Vertex};
+use crate::{material::MaterialId, transform::Transform, vertex::Vertex};
 
 pub struct Mesh {
     pub vertices: Vec<Vertex>,
     pub indices: Vec<u16>,
     pub transform: Transform,
+    /// Meshes without a material are drawn with lit vertex colors
+    pub material: Option<MaterialId>,
+}
+
+/// Options for `Mesh::load`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoadOptions {
+    /// Runs `Mesh::fix_winding` on the loaded mesh, for files whose
+    /// triangles do not all face the same way
+    pub fix_winding: bool,
 }
 
 impl Mesh {
     pub fn vertices_transformed(&self) -> Vec<Vertex> {
+        let transformation_matrix = glam::Mat4::from_scale_rotation_translation(
+            self.transform.scale,
+            self.transform.rotation,
+            self.transform.translation,
+        );
+        let normal_matrix = glam::Mat3::from_mat4(transformation_matrix)
+            .inverse()
+            .transpose();
+
         self.vertices
             .iter()
             .map(|v| {
                 let pos = v.position;
                 let color = v.color;
 
-                let transformation_matrix = glam::Mat4::from_scale_rotation_translation(
-                    self.transform.scale,
-                    self.transform.rotation,
-                    self.transform.translation,
-                );
-
                 let transformed = transformation_matrix.mul_vec4(pos.into());
+                let normal =
+                    (normal_matrix * glam::Vec3::from(v.normal)).normalize_or_zero();
                 Vertex {
                     position: transformed.into(),
                     color,
+                    normal: normal.into(),
+                    tex_coords: v.tex_coords,
                 }
             })
             .collect()
@@ -43,7 +62,163 @@ impl Mesh {
             vertices: vertices.to_vec(),
             indices: indices.to_vec(),
             transform,
+            material: None,
+        }
+    }
+
+    pub fn with_material(mut self, material: MaterialId) -> Self {
+        self.material = Some(material);
+        self
+    }
+
+    /// Loads an OBJ file, with texture coordinates when it has them
+    pub fn load(path: impl AsRef<Path>, options: LoadOptions) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        // files without texture coordinates only load as untextured vertices
+        let mut mesh =
+            match obj::load_obj::<obj::TexturedVertex, _, u16>(contents.as_bytes()) {
+                Ok(obj) => Mesh::from(obj),
+                Err(_) => {
+                    let obj: obj::Obj = obj::load_obj(contents.as_bytes())
+                        .with_context(|| {
+                            format!("Could not load {}", path.display())
+                        })?;
+                    Mesh::from(obj)
+                }
+            };
+        if options.fix_winding {
+            let flipped = mesh.fix_winding();
+            if flipped > 0 {
+                log::info!(
+                    "Flipped {flipped} inconsistently wound triangles in {}",
+                    path.display()
+                );
+            }
+        }
+        Ok(mesh)
+    }
+
+    /// Makes the triangles of every connected part wind the same way as
+    /// their neighbours: counter-clockwise seen from outside when the part
+    /// is closed, otherwise like the majority of its triangles. Vertices at
+    /// the same position count as shared, since OBJ files split them by
+    /// normal and texture coordinate. Returns the number of triangles
+    /// flipped.
+    pub fn fix_winding(&mut self) -> usize {
+        let triangle_count = self.indices.len() / 3;
+        let mut welded = HashMap::new();
+        let corners: Vec<[usize; 3]> = self
+            .indices
+            .chunks_exact(3)
+            .map(|triangle| {
+                [0, 1, 2].map(|corner| {
+                    let position = self.vertices[triangle[corner] as usize].position;
+                    let key = [0, 1, 2].map(|i| position[i].to_bits());
+                    let next = welded.len();
+                    *welded.entry(key).or_insert(next)
+                })
+            })
+            .collect();
+        // triangles touching each undirected edge, with the direction they
+        // walk it in
+        let mut edges: HashMap<(usize, usize), Vec<(usize, bool)>> = HashMap::new();
+        for (triangle, corners) in corners.iter().enumerate() {
+            for i in 0..3 {
+                let (a, b) = (corners[i], corners[(i + 1) % 3]);
+                edges
+                    .entry((a.min(b), a.max(b)))
+                    .or_default()
+                    .push((triangle, a < b));
+            }
+        }
+
+        let mut flip: Vec<Option<bool>> = vec![None; triangle_count];
+        for seed in 0..triangle_count {
+            if flip[seed].is_some() {
+                continue;
+            }
+            flip[seed] = Some(false);
+            let mut part = vec![seed];
+            let mut queue = VecDeque::from([seed]);
+            while let Some(triangle) = queue.pop_front() {
+                let flipped = flip[triangle] == Some(true);
+                let corners = corners[triangle];
+                for i in 0..3 {
+                    let (a, b) = (corners[i], corners[(i + 1) % 3]);
+                    let forward = (a < b) != flipped;
+                    // edges of more than two triangles have no consistent
+                    // winding to follow
+                    let shared = &edges[&(a.min(b), a.max(b))];
+                    if shared.len() != 2 {
+                        continue;
+                    }
+                    for &(neighbour, neighbour_forward) in shared {
+                        if flip[neighbour].is_none() {
+                            // consistent neighbours walk shared edges in
+                            // opposite directions
+                            flip[neighbour] = Some(neighbour_forward == forward);
+                            part.push(neighbour);
+                            queue.push_back(neighbour);
+                        }
+                    }
+                }
+            }
+
+            // closed parts face outwards when their signed volume is
+            // positive, open ones keep the winding most of their triangles
+            // already had
+            let closed = part.iter().all(|&triangle| {
+                let corners = corners[triangle];
+                (0..3).all(|i| {
+                    let (a, b) = (corners[i], corners[(i + 1) % 3]);
+                    edges[&(a.min(b), a.max(b))].len() == 2
+                })
+            });
+            let inside_out = if closed {
+                let volume: f32 = part
+                    .iter()
+                    .map(|&triangle| {
+                        let [a, b, c] = self.triangle_positions(triangle);
+                        let volume = a.dot(b.cross(c));
+                        if flip[triangle] == Some(true) {
+                            -volume
+                        } else {
+                            volume
+                        }
+                    })
+                    .sum();
+                volume < 0.0
+            } else {
+                let flipped = part
+                    .iter()
+                    .filter(|&&triangle| flip[triangle] == Some(true))
+                    .count();
+                flipped * 2 > part.len()
+            };
+            if inside_out {
+                for triangle in part {
+                    flip[triangle] = flip[triangle].map(|flipped| !flipped);
+                }
+            }
         }
+
+        let mut flipped = 0;
+        for (triangle, flip) in flip.into_iter().enumerate() {
+            if flip == Some(true) {
+                self.indices.swap(triangle * 3 + 1, triangle * 3 + 2);
+                flipped += 1;
+            }
+        }
+        flipped
+    }
+
+    fn triangle_positions(&self, triangle: usize) -> [glam::Vec3; 3] {
+        [0, 1, 2].map(|corner| {
+            let index = self.indices[triangle * 3 + corner] as usize;
+            glam::Vec3::from_slice(&self.vertices[index].position[..3])
+        })
     }
 }
 
@@ -55,24 +230,39 @@ impl From<obj::Obj> for Mesh {
             .map(|v| Vertex {
                 position: [v.position[0], v.position[1], v.position[2], 1.0],
                 color: [0.33, 0.33, 0.33, 1.0],
+                normal: v.normal,
+                tex_coords: [0.0; 2],
             })
             .collect::<Vec<Vertex>>();
 
-        let transform = Transform::from_translation(glam::Vec3::new(0.0, 0.0, 0.0));
+        Mesh::new(&vertices, &value.indices)
+    }
+}
 
-        Mesh {
-            vertices,
-            indices: value.indices,
-            transform,
-        }
+impl From<obj::Obj<obj::TexturedVertex>> for Mesh {
+    fn from(value: obj::Obj<obj::TexturedVertex>) -> Self {
+        let vertices = value
+            .vertices
+            .iter()
+            .map(|v| Vertex {
+                position: [v.position[0], v.position[1], v.position[2], 1.0],
+                color: [0.33, 0.33, 0.33, 1.0],
+                normal: v.normal,
+                // obj puts v = 0 at the bottom of the image, wgpu at the top
+                tex_coords: [v.texture[0], 1.0 - v.texture[1]],
+            })
+            .collect::<Vec<Vertex>>();
+
+        Mesh::new(&vertices, &value.indices)
     }
 }
 
-impl From<PathBuf> for Mesh {
-    fn from(value: PathBuf) -> Self {
-        let contents = std::fs::read_to_string(value).unwrap();
-        let obj = obj::load_obj(contents.as_bytes()).unwrap();
-        Mesh::from(obj)
+impl TryFrom<PathBuf> for Mesh {
+    type Error = anyhow::Error;
+
+    /// Loads an OBJ file with the default `LoadOptions`
+    fn try_from(value: PathBuf) -> anyhow::Result<Self> {
+        Mesh::load(value, LoadOptions::default())
     }
 }
 
@@ -85,3 +275,118 @@ impl crate::Entity for Mesh {
         self.transform.rotation *= quat;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec3;
+
+    /// A cube of side 2 around `center` wound counter-clockwise seen from
+    /// outside, with its own vertices per face like OBJ files have
+    fn cube(center: Vec3) -> Mesh {
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for axis in 0..3 {
+            for sign in [-1.0, 1.0] {
+                let normal = Vec3::AXES[axis] * sign;
+                let (u, v) = (Vec3::AXES[(axis + 1) % 3], Vec3::AXES[(axis + 2) % 3]);
+                let start = vertices.len() as u16;
+                for (a, b) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+                    vertices.push(vertex(center + normal + u * a + v * b));
+                }
+                for triangle in [[0, 1, 2], [0, 2, 3]] {
+                    indices.extend(triangle.map(|corner| start + corner));
+                }
+            }
+        }
+        orient(Mesh::new(&vertices, &indices), |centroid| centroid - center)
+    }
+
+    /// A flat strip of four triangles facing +z, open along its border
+    fn strip() -> Mesh {
+        let vertices: Vec<_> = (0..6)
+            .map(|i| vertex(Vec3::new((i / 2) as f32, (i % 2) as f32, 0.0)))
+            .collect();
+        let indices = [0, 2, 1, 1, 2, 3, 2, 4, 3, 3, 4, 5];
+        orient(Mesh::new(&vertices, &indices), |_| Vec3::Z)
+    }
+
+    fn vertex(position: Vec3) -> Vertex {
+        Vertex {
+            position: position.extend(1.0).into(),
+            color: [1.0; 4],
+            normal: [0.0; 3],
+            tex_coords: [0.0; 2],
+        }
+    }
+
+    /// Winds every triangle counter-clockwise around the direction `facing`
+    /// returns for its centroid
+    fn orient(mut mesh: Mesh, facing: impl Fn(Vec3) -> Vec3) -> Mesh {
+        for triangle in 0..mesh.indices.len() / 3 {
+            let [a, b, c] = mesh.triangle_positions(triangle);
+            let normal = (b - a).cross(c - a);
+            if normal.dot(facing((a + b + c) / 3.0)) < 0.0 {
+                flip(&mut mesh, &[triangle]);
+            }
+        }
+        mesh
+    }
+
+    fn flip(mesh: &mut Mesh, triangles: &[usize]) {
+        for &triangle in triangles {
+            mesh.indices.swap(triangle * 3 + 1, triangle * 3 + 2);
+        }
+    }
+
+    #[test]
+    fn flipped_triangles_of_a_closed_mesh_are_fixed() {
+        let mut mesh = cube(Vec3::ZERO);
+        let expected = mesh.indices.clone();
+        flip(&mut mesh, &[1, 4, 7]);
+        assert_eq!(mesh.fix_winding(), 3);
+        assert_eq!(mesh.indices, expected);
+    }
+
+    #[test]
+    fn inside_out_closed_meshes_are_turned_outwards() {
+        let mut mesh = cube(Vec3::ZERO);
+        let expected = mesh.indices.clone();
+        flip(&mut mesh, &(0..12).collect::<Vec<_>>());
+        assert_eq!(mesh.fix_winding(), 12);
+        assert_eq!(mesh.indices, expected);
+    }
+
+    #[test]
+    fn open_meshes_follow_the_majority() {
+        let mut mesh = strip();
+        let expected = mesh.indices.clone();
+        flip(&mut mesh, &[2]);
+        assert_eq!(mesh.fix_winding(), 1);
+        assert_eq!(mesh.indices, expected);
+
+        // consistent meshes are left alone
+        assert_eq!(mesh.fix_winding(), 0);
+        assert_eq!(mesh.indices, expected);
+    }
+
+    #[test]
+    fn separate_parts_are_fixed_on_their_own() {
+        let first = cube(Vec3::ZERO);
+        let second = cube(Vec3::new(5.0, 0.0, 0.0));
+        let offset = first.vertices.len() as u16;
+        let vertices = [first.vertices, second.vertices].concat();
+        let indices: Vec<_> = first
+            .indices
+            .iter()
+            .copied()
+            .chain(second.indices.iter().map(|index| index + offset))
+            .collect();
+        let mut mesh = Mesh::new(&vertices, &indices);
+        // a few flipped triangles in the first cube, the second inside out
+        flip(&mut mesh, &[0, 9]);
+        flip(&mut mesh, &(12..24).collect::<Vec<_>>());
+        assert_eq!(mesh.fix_winding(), 14);
+        assert_eq!(mesh.indices, indices);
+    }
+}
@@ -0,0 +1,46 @@
+// Vertex stage and inputs shared by the mesh shaders. Custom material
+// shaders include this, define `shade` and include fragment.wgsl.
+#include "camera.wgsl"
+#include "lights.wgsl"
+#include "material.wgsl"
+
+struct VertexInput {
+    @location(0) position: vec4<f32>,
+    @location(1) color: vec4<f32>,
+    @location(2) normal: vec3<f32>,
+    @location(3) tex_coords: vec2<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+    @location(1) world_position: vec3<f32>,
+    @location(2) normal: vec3<f32>,
+    @location(3) tex_coords: vec2<f32>,
+}
+
+@vertex
+fn vs_main(in: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    // these values control the coordinates of the triangle
+    out.position = camera.view_proj * in.position;
+    //out.position = in.position;
+    out.color = in.color;
+    out.world_position = in.position.xyz;
+    out.normal = in.normal;
+    out.tex_coords = in.tex_coords;
+
+    return out;
+}
+
+// Vertex color times the material base color and its texture
+fn surface_color(in: VertexOutput) -> vec4<f32> {
+    let base_sample = textureSample(base_color_texture, material_sampler, in.tex_coords);
+    return in.color * material.base_color * base_sample;
+}
+
+fn shade_lit(in: VertexOutput) -> vec4<f32> {
+    let color = surface_color(in);
+    let light = lighting(in.world_position, normalize(in.normal));
+    return vec4<f32>(color.rgb * light, color.a);
+}
@@ -0,0 +1,26 @@
+// Resolves the weighted blended transparency targets onto the scene, see
+// `oit_output` in shader.wgsl.
+
+@group(0) @binding(0)
+var accum: texture_2d<f32>;
+@group(0) @binding(1)
+var revealage: texture_2d<f32>;
+
+@vertex
+fn vs_fullscreen(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    return vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+}
+
+@fragment
+fn fs_composite(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    let pixel = vec2<i32>(position.xy);
+    let reveal = textureLoad(revealage, pixel, 0).r;
+    if reveal >= 0.9999 {
+        // no transparent fragments here
+        discard;
+    }
+    let sum = textureLoad(accum, pixel, 0);
+    let average = sum.rgb / max(sum.a, 1e-5);
+    return vec4<f32>(average, 1.0 - reveal);
+}
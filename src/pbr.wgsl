@@ -0,0 +1,160 @@
+// Metallic roughness shading with image based lighting, included by
+// shader.wgsl when PBR is defined
+#include "mesh.wgsl"
+
+@group(3) @binding(0)
+var irradiance_map: texture_cube<f32>;
+@group(3) @binding(1)
+var prefiltered_map: texture_cube<f32>;
+@group(3) @binding(2)
+var brdf_lut: texture_2d<f32>;
+@group(3) @binding(3)
+var environment_sampler: sampler;
+
+const PI: f32 = 3.14159265359;
+// Mirrors PREFILTERED_MIP_LEVELS in environment.rs, textureNumLevels is not
+// available on GL
+const PREFILTERED_MIP_LEVELS: u32 = 5u;
+
+// Applies a tangent space normal map using a cotangent frame built from
+// screen space derivatives, so meshes need no tangents
+fn perturb_normal(
+    normal: vec3<f32>,
+    world_position: vec3<f32>,
+    uv: vec2<f32>,
+    mapped: vec3<f32>,
+) -> vec3<f32> {
+    let dp1 = dpdx(world_position);
+    let dp2 = dpdy(world_position);
+    let duv1 = dpdx(uv);
+    let duv2 = dpdy(uv);
+    let dp2perp = cross(dp2, normal);
+    let dp1perp = cross(normal, dp1);
+    let tangent = dp2perp * duv1.x + dp1perp * duv2.x;
+    let bitangent = dp2perp * duv1.y + dp1perp * duv2.y;
+    let scale = max(dot(tangent, tangent), dot(bitangent, bitangent));
+    if scale <= 0.0 {
+        // no usable texture coordinates
+        return normal;
+    }
+    let inv_max = inverseSqrt(scale);
+    let tbn = mat3x3<f32>(tangent * inv_max, bitangent * inv_max, normal);
+    return normalize(tbn * mapped);
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let r = roughness + 1.0;
+    let k = r * r / 8.0;
+    let gv = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let gl = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return gv * gl;
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+fn fresnel_schlick_roughness(cos_theta: f32, f0: vec3<f32>, roughness: f32) -> vec3<f32> {
+    let f90 = max(vec3<f32>(1.0 - roughness), f0);
+    return f0 + (f90 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+struct Surface {
+    base_color: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+    normal: vec3<f32>,
+    view: vec3<f32>,
+    f0: vec3<f32>,
+}
+
+// Cook-Torrance specular plus Lambert diffuse for one light
+fn brdf(surface: Surface, light: LightSample) -> vec3<f32> {
+    let n = surface.normal;
+    let l = light.direction;
+    let h = normalize(surface.view + l);
+    let n_dot_l = max(dot(n, l), 0.0);
+    let n_dot_v = max(dot(n, surface.view), 0.0001);
+    let n_dot_h = max(dot(n, h), 0.0);
+
+    let d = distribution_ggx(n_dot_h, surface.roughness);
+    let g = geometry_smith(n_dot_v, n_dot_l, surface.roughness);
+    let f = fresnel_schlick(max(dot(h, surface.view), 0.0), surface.f0);
+    let specular = d * g * f / (4.0 * n_dot_v * n_dot_l + 0.0001);
+    let diffuse = (1.0 - f) * (1.0 - surface.metallic) * surface.base_color / PI;
+
+    return (diffuse + specular) * light.radiance * n_dot_l;
+}
+
+// Split sum approximation of the environment lighting
+fn environment_lighting(surface: Surface) -> vec3<f32> {
+    let n = surface.normal;
+    let n_dot_v = max(dot(n, surface.view), 0.0001);
+    let f = fresnel_schlick_roughness(n_dot_v, surface.f0, surface.roughness);
+    let diffuse_weight = (1.0 - f) * (1.0 - surface.metallic);
+
+    let irradiance = textureSampleLevel(irradiance_map, environment_sampler, n, 0.0).rgb;
+    let diffuse = irradiance * surface.base_color;
+
+    let r = reflect(-surface.view, n);
+    let max_lod = f32(PREFILTERED_MIP_LEVELS - 1u);
+    let prefiltered = textureSampleLevel(
+        prefiltered_map,
+        environment_sampler,
+        r,
+        surface.roughness * max_lod,
+    ).rgb;
+    let lut = textureSampleLevel(
+        brdf_lut,
+        environment_sampler,
+        vec2<f32>(n_dot_v, surface.roughness),
+        0.0,
+    ).rg;
+    let specular = prefiltered * (f * lut.x + lut.y);
+
+    return diffuse_weight * diffuse + specular;
+}
+
+fn shade_pbr(in: VertexOutput) -> vec4<f32> {
+    // sample everything up front, derivatives need uniform control flow
+    let base_sample = textureSample(base_color_texture, material_sampler, in.tex_coords);
+    let mr_sample = textureSample(metallic_roughness_texture, material_sampler, in.tex_coords);
+    let normal_sample = textureSample(normal_texture, material_sampler, in.tex_coords);
+    let occlusion_sample = textureSample(occlusion_texture, material_sampler, in.tex_coords);
+    let emissive_sample = textureSample(emissive_texture, material_sampler, in.tex_coords);
+
+    let base_color = material.base_color * base_sample;
+    var mapped = normal_sample.xyz * 2.0 - 1.0;
+    mapped = vec3<f32>(mapped.xy * material.params.z, mapped.z);
+    let geometric_normal = normalize(in.normal);
+    let normal = perturb_normal(geometric_normal, in.world_position, in.tex_coords, normalize(mapped));
+
+    var surface: Surface;
+    surface.base_color = base_color.rgb;
+    surface.metallic = clamp(material.params.x * mr_sample.b, 0.0, 1.0);
+    surface.roughness = clamp(material.params.y * mr_sample.g, 0.04, 1.0);
+    surface.normal = normal;
+    surface.view = normalize(camera.position.xyz - in.world_position);
+    surface.f0 = mix(vec3<f32>(0.04), surface.base_color, surface.metallic);
+
+    var color = vec3<f32>(0.0);
+    for (var i = 0u; i < lights.counts.x; i++) {
+        color += brdf(surface, directional_light(i, in.world_position, geometric_normal));
+    }
+    for (var i = 0u; i < lights.counts.y; i++) {
+        color += brdf(surface, spot_light(i, in.world_position, geometric_normal));
+    }
+
+    let occlusion = mix(1.0, occlusion_sample.r, material.params.w);
+    color += environment_lighting(surface) * lights.ambient.w * occlusion;
+    color += material.emissive.rgb * emissive_sample.rgb;
+
+    return vec4<f32>(color, base_color.a);
+}
@@ -0,0 +1,637 @@
+//! HDR post-processing.
+//!
+//! The scene is rendered into an `HDR_FORMAT` target, which then runs
+//! through the stages of a `PostChain` in order. Each stage is a fullscreen
+//! pass that reads the previous result. A final pass writes to the surface.
+use crate::texture::{self, Texture};
+use std::borrow::Cow;
+
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Upper bound of passes per frame, bloom alone takes up to `2 * BLOOM_LEVELS`
+const MAX_PASSES: usize = 64;
+const BLOOM_LEVELS: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tonemapper {
+    Aces,
+    Reinhard,
+    AgX,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BloomSettings {
+    /// Brightness above which pixels start to glow
+    pub threshold: f32,
+    /// Fraction of `threshold` over which the glow fades in
+    pub knee: f32,
+    pub intensity: f32,
+}
+
+impl Default for BloomSettings {
+    fn default() -> Self {
+        Self {
+            threshold: 1.0,
+            knee: 0.5,
+            intensity: 0.1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VignetteSettings {
+    /// How much the corners are darkened, 0 to 1
+    pub intensity: f32,
+    /// Distance from the center, relative to the corners, where darkening
+    /// starts
+    pub radius: f32,
+    pub smoothness: f32,
+}
+
+impl Default for VignetteSettings {
+    fn default() -> Self {
+        Self {
+            intensity: 0.4,
+            radius: 0.5,
+            smoothness: 0.5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PostEffect {
+    Bloom(BloomSettings),
+    /// Exposure adjustment in stops
+    Exposure(f32),
+    Tonemap(Tonemapper),
+    /// Encodes the colors with `1 / gamma`. The final pass accounts for
+    /// sRGB surfaces encoding them again.
+    Gamma(f32),
+    Vignette(VignetteSettings),
+    Fxaa,
+}
+
+impl PostEffect {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PostEffect::Bloom(_) => "bloom",
+            PostEffect::Exposure(_) => "exposure",
+            PostEffect::Tonemap(_) => "tonemap",
+            PostEffect::Gamma(_) => "gamma",
+            PostEffect::Vignette(_) => "vignette",
+            PostEffect::Fxaa => "fxaa",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PostStage {
+    pub effect: PostEffect,
+    pub enabled: bool,
+}
+
+/// The post-processing stages, applied in order
+#[derive(Clone, Debug, PartialEq)]
+pub struct PostChain {
+    pub stages: Vec<PostStage>,
+}
+
+impl Default for PostChain {
+    fn default() -> Self {
+        Self::new()
+            .with(PostEffect::Bloom(BloomSettings::default()))
+            .with(PostEffect::Exposure(0.0))
+            .with(PostEffect::Tonemap(Tonemapper::Aces))
+            .with(PostEffect::Gamma(2.2))
+            .with(PostEffect::Vignette(VignetteSettings::default()))
+            .with(PostEffect::Fxaa)
+    }
+}
+
+impl PostChain {
+    /// A chain without any stages, the HDR colors are written as they are
+    pub fn new() -> Self {
+        Self { stages: Vec::new() }
+    }
+
+    /// Appends an enabled stage
+    pub fn with(mut self, effect: PostEffect) -> Self {
+        self.push(effect);
+        self
+    }
+
+    pub fn push(&mut self, effect: PostEffect) {
+        self.stages.push(PostStage {
+            effect,
+            enabled: true,
+        });
+    }
+
+    /// Index of the first stage with the given `PostEffect::name`
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.stages.iter().position(|s| s.effect.name() == name)
+    }
+
+    /// Enables or disables every stage with the given `PostEffect::name`
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) {
+        for stage in self.stages.iter_mut().filter(|s| s.effect.name() == name) {
+            stage.enabled = enabled;
+        }
+    }
+
+    /// Moves the stage at `from` so it ends up at index `to`, failing when
+    /// either is not the index of a stage
+    pub fn move_stage(&mut self, from: usize, to: usize) -> anyhow::Result<()> {
+        let len = self.stages.len();
+        if from >= len || to >= len {
+            anyhow::bail!("Can not move stage {from} to {to} of a chain of {len}");
+        }
+        let stage = self.stages.remove(from);
+        self.stages.insert(to, stage);
+        Ok(())
+    }
+
+    /// Whether the enabled stages gamma encode the colors
+    fn encodes_gamma(&self) -> bool {
+        self.stages
+            .iter()
+            .any(|s| s.enabled && matches!(s.effect, PostEffect::Gamma(_)))
+    }
+}
+
+/// Mirrors `PassParams` in post.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct PassParams {
+    params: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for PassParams {}
+unsafe impl bytemuck::Zeroable for PassParams {}
+
+struct PostPipelines {
+    exposure: wgpu::RenderPipeline,
+    tonemap: wgpu::RenderPipeline,
+    gamma: wgpu::RenderPipeline,
+    vignette: wgpu::RenderPipeline,
+    fxaa: wgpu::RenderPipeline,
+    bloom_prefilter: wgpu::RenderPipeline,
+    bloom_downsample: wgpu::RenderPipeline,
+    bloom_upsample: wgpu::RenderPipeline,
+    bloom_composite: wgpu::RenderPipeline,
+    output: wgpu::RenderPipeline,
+}
+
+/// Textures that depend on the surface size, and their bind groups
+struct PostTargets {
+    /// The scene is rendered into the first one, stages alternate between
+    /// them
+    ping_pong: [Texture; 2],
+    ping_pong_bind_groups: [wgpu::BindGroup; 2],
+    /// Same as `ping_pong_bind_groups` with the bloom result bound as well
+    composite_bind_groups: [wgpu::BindGroup; 2],
+    /// Half resolution and smaller
+    bloom: Vec<Texture>,
+    bloom_bind_groups: Vec<wgpu::BindGroup>,
+}
+
+/// One fullscreen pass of a frame
+struct Pass<'p> {
+    pipeline: &'p wgpu::RenderPipeline,
+    bind_group: &'p wgpu::BindGroup,
+    target: &'p wgpu::TextureView,
+    params: [f32; 4],
+    /// Blend onto the target instead of replacing it
+    additive: bool,
+}
+
+pub(crate) struct PostProcess {
+    layout: wgpu::BindGroupLayout,
+    pipelines: PostPipelines,
+    sampler: wgpu::Sampler,
+    /// `MAX_PASSES` parameter slots, `uniform_stride` apart
+    uniform: wgpu::Buffer,
+    uniform_stride: wgpu::BufferAddress,
+    /// Bound as `bloom` where it is not read, so it never aliases a target
+    placeholder: Texture,
+    targets: PostTargets,
+}
+
+impl PostProcess {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        output_format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("post_shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("post.wgsl"))),
+        });
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("post_bind_group_layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: true,
+                            min_binding_size: wgpu::BufferSize::new(
+                                std::mem::size_of::<PassParams>() as u64,
+                            ),
+                        },
+                        count: None,
+                    },
+                    texture_entry(1),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(
+                            wgpu::SamplerBindingType::Filtering,
+                        ),
+                        count: None,
+                    },
+                    texture_entry(3),
+                ],
+            });
+        let pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("post_pipeline_layout"),
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            });
+        let pipeline = |entry_point: &str,
+                        format: wgpu::TextureFormat,
+                        blend: Option<wgpu::BlendState>| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_fullscreen",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point,
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format,
+                        blend,
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        };
+        let additive = wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent::REPLACE,
+        };
+        let pipelines = PostPipelines {
+            exposure: pipeline("fs_exposure", HDR_FORMAT, None),
+            tonemap: pipeline("fs_tonemap", HDR_FORMAT, None),
+            gamma: pipeline("fs_gamma", HDR_FORMAT, None),
+            vignette: pipeline("fs_vignette", HDR_FORMAT, None),
+            fxaa: pipeline("fs_fxaa", HDR_FORMAT, None),
+            bloom_prefilter: pipeline("fs_bloom_prefilter", HDR_FORMAT, None),
+            bloom_downsample: pipeline("fs_bloom_downsample", HDR_FORMAT, None),
+            bloom_upsample: pipeline("fs_bloom_upsample", HDR_FORMAT, Some(additive)),
+            bloom_composite: pipeline("fs_bloom_composite", HDR_FORMAT, None),
+            output: pipeline("fs_output", output_format, None),
+        };
+
+        let uniform_stride = (std::mem::size_of::<PassParams>() as wgpu::BufferAddress)
+            .next_multiple_of(
+                device.limits().min_uniform_buffer_offset_alignment
+                    as wgpu::BufferAddress,
+            );
+        let uniform = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("post_uniform"),
+            size: uniform_stride * MAX_PASSES as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("post_sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let placeholder = texture::create_solid_texture(
+            device,
+            queue,
+            [0, 0, 0, 255],
+            false,
+            "post_placeholder",
+        );
+
+        let targets = create_targets(
+            device,
+            &layout,
+            &uniform,
+            &sampler,
+            &placeholder,
+            width,
+            height,
+        );
+
+        Self {
+            layout,
+            pipelines,
+            sampler,
+            uniform,
+            uniform_stride,
+            placeholder,
+            targets,
+        }
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        self.targets = create_targets(
+            device,
+            &self.layout,
+            &self.uniform,
+            &self.sampler,
+            &self.placeholder,
+            width,
+            height,
+        );
+    }
+
+    /// The HDR target the scene is rendered into
+    pub fn scene_target(&self) -> &wgpu::TextureView {
+        &self.targets.ping_pong[0].view
+    }
+
+    /// Records the enabled stages of `chain` and the final pass into
+    /// `output`
+    pub fn run(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        chain: &PostChain,
+        output: &wgpu::TextureView,
+        output_is_srgb: bool,
+    ) {
+        let targets = &self.targets;
+        let pipelines = &self.pipelines;
+        let mut passes = Vec::new();
+        let mut current = 0;
+
+        for stage in chain.stages.iter().filter(|s| s.enabled) {
+            // leave room for a bloom stage and the final pass
+            if passes.len() + 2 * BLOOM_LEVELS + 1 >= MAX_PASSES {
+                log::warn!("Post chain is too long, skipping the remaining stages");
+                break;
+            }
+            let (pipeline, params) = match stage.effect {
+                PostEffect::Exposure(stops) => {
+                    (&pipelines.exposure, [stops, 0.0, 0.0, 0.0])
+                }
+                PostEffect::Tonemap(tonemapper) => {
+                    let index = match tonemapper {
+                        Tonemapper::Aces => 0.0,
+                        Tonemapper::Reinhard => 1.0,
+                        Tonemapper::AgX => 2.0,
+                    };
+                    (&pipelines.tonemap, [index, 0.0, 0.0, 0.0])
+                }
+                PostEffect::Gamma(gamma) => {
+                    (&pipelines.gamma, [gamma.max(0.01), 0.0, 0.0, 0.0])
+                }
+                PostEffect::Vignette(v) => (
+                    &pipelines.vignette,
+                    [v.intensity, v.radius, v.smoothness, 0.0],
+                ),
+                PostEffect::Fxaa => (&pipelines.fxaa, [0.0; 4]),
+                PostEffect::Bloom(bloom) => {
+                    self.bloom_passes(&mut passes, current, bloom);
+                    passes.push(Pass {
+                        pipeline: &pipelines.bloom_composite,
+                        bind_group: &targets.composite_bind_groups[current],
+                        target: &targets.ping_pong[1 - current].view,
+                        params: [bloom.intensity, 0.0, 0.0, 0.0],
+                        additive: false,
+                    });
+                    current = 1 - current;
+                    continue;
+                }
+            };
+            passes.push(Pass {
+                pipeline,
+                bind_group: &targets.ping_pong_bind_groups[current],
+                target: &targets.ping_pong[1 - current].view,
+                params,
+                additive: false,
+            });
+            current = 1 - current;
+        }
+
+        let decode = output_is_srgb && chain.encodes_gamma();
+        passes.push(Pass {
+            pipeline: &pipelines.output,
+            bind_group: &targets.ping_pong_bind_groups[current],
+            target: output,
+            params: [decode as u32 as f32, 0.0, 0.0, 0.0],
+            additive: false,
+        });
+
+        let mut uniform_data = vec![0u8; self.uniform_stride as usize * passes.len()];
+        for (i, pass) in passes.iter().enumerate() {
+            let start = i * self.uniform_stride as usize;
+            let params = PassParams {
+                params: pass.params,
+            };
+            uniform_data[start..start + std::mem::size_of::<PassParams>()]
+                .copy_from_slice(bytemuck::bytes_of(&params));
+        }
+        queue.write_buffer(&self.uniform, 0, &uniform_data);
+
+        for (i, pass) in passes.iter().enumerate() {
+            let mut render_pass =
+                encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Post Pass"),
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view: pass.target,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: if pass.additive {
+                                wgpu::LoadOp::Load
+                            } else {
+                                wgpu::LoadOp::Clear(wgpu::Color::BLACK)
+                            },
+                            store: wgpu::StoreOp::Store,
+                        },
+                    })],
+                    depth_stencil_attachment: None,
+                    timestamp_writes: None,
+                    occlusion_query_set: None,
+                });
+            let offset = (i as wgpu::BufferAddress * self.uniform_stride) as u32;
+            render_pass.set_pipeline(pass.pipeline);
+            render_pass.set_bind_group(0, pass.bind_group, &[offset]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+
+    /// Thresholds the current image into the bloom chain, blurs it down and
+    /// adds it back up into the first level
+    fn bloom_passes<'p>(
+        &'p self,
+        passes: &mut Vec<Pass<'p>>,
+        current: usize,
+        bloom: BloomSettings,
+    ) {
+        let targets = &self.targets;
+        let pipelines = &self.pipelines;
+        passes.push(Pass {
+            pipeline: &pipelines.bloom_prefilter,
+            bind_group: &targets.ping_pong_bind_groups[current],
+            target: &targets.bloom[0].view,
+            params: [bloom.threshold, bloom.knee, 0.0, 0.0],
+            additive: false,
+        });
+        for level in 1..targets.bloom.len() {
+            passes.push(Pass {
+                pipeline: &pipelines.bloom_downsample,
+                bind_group: &targets.bloom_bind_groups[level - 1],
+                target: &targets.bloom[level].view,
+                params: [0.0; 4],
+                additive: false,
+            });
+        }
+        for level in (1..targets.bloom.len()).rev() {
+            passes.push(Pass {
+                pipeline: &pipelines.bloom_upsample,
+                bind_group: &targets.bloom_bind_groups[level],
+                target: &targets.bloom[level - 1].view,
+                params: [0.0; 4],
+                additive: true,
+            });
+        }
+    }
+}
+
+fn create_targets(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    uniform: &wgpu::Buffer,
+    sampler: &wgpu::Sampler,
+    placeholder: &Texture,
+    width: u32,
+    height: u32,
+) -> PostTargets {
+    let (width, height) = (width.max(1), height.max(1));
+    let bind_group = |source: &Texture, bloom: &Texture| {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("post_bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: uniform,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(
+                            std::mem::size_of::<PassParams>() as u64
+                        ),
+                    }),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&source.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&bloom.view),
+                },
+            ],
+        })
+    };
+
+    let ping_pong = [0, 1].map(|i| {
+        texture::create_color_target(
+            device,
+            width,
+            height,
+            HDR_FORMAT,
+            &format!("post_target_{i}"),
+        )
+    });
+    let levels = BLOOM_LEVELS.min(width.min(height).max(2).ilog2() as usize);
+    let bloom: Vec<Texture> = (1..=levels)
+        .map(|level| {
+            texture::create_color_target(
+                device,
+                (width >> level).max(1),
+                (height >> level).max(1),
+                HDR_FORMAT,
+                &format!("bloom_{level}"),
+            )
+        })
+        .collect();
+
+    PostTargets {
+        ping_pong_bind_groups: [0, 1].map(|i| bind_group(&ping_pong[i], placeholder)),
+        composite_bind_groups: [0, 1].map(|i| bind_group(&ping_pong[i], &bloom[0])),
+        bloom_bind_groups: bloom.iter().map(|b| bind_group(b, placeholder)).collect(),
+        ping_pong,
+        bloom,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(chain: &PostChain) -> Vec<&'static str> {
+        chain.stages.iter().map(|s| s.effect.name()).collect()
+    }
+
+    #[test]
+    fn stages_move_to_the_given_index() {
+        let mut chain = PostChain::default();
+        chain.move_stage(5, 0).unwrap();
+        assert_eq!(
+            names(&chain),
+            ["fxaa", "bloom", "exposure", "tonemap", "gamma", "vignette"]
+        );
+        chain.move_stage(0, 5).unwrap();
+        assert_eq!(chain, PostChain::default());
+        chain.move_stage(2, 2).unwrap();
+        assert_eq!(chain, PostChain::default());
+    }
+
+    #[test]
+    fn moving_out_of_range_stages_fails() {
+        let mut chain = PostChain::default();
+        assert!(chain.move_stage(6, 0).is_err());
+        assert!(chain.move_stage(0, 6).is_err());
+        assert!(PostChain::new().move_stage(0, 0).is_err());
+        assert_eq!(chain, PostChain::default());
+    }
+}
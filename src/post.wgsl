@@ -0,0 +1,221 @@
+// Fullscreen post-processing passes. Every pass reads `source` and writes a
+// new target; see post.rs for the meaning of `pass_params` in each pass.
+
+struct PassParams {
+    params: vec4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> pass_params: PassParams;
+@group(0) @binding(1)
+var source: texture_2d<f32>;
+@group(0) @binding(2)
+var source_sampler: sampler;
+// only meaningful in fs_bloom_composite
+@group(0) @binding(3)
+var bloom: texture_2d<f32>;
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    // (0, 0) in the top left corner
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vs_fullscreen(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+fn sample_source(uv: vec2<f32>) -> vec4<f32> {
+    return textureSampleLevel(source, source_sampler, uv, 0.0);
+}
+
+fn luma(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// x: exposure in stops
+@fragment
+fn fs_exposure(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = sample_source(in.uv);
+    return vec4<f32>(color.rgb * exp2(pass_params.params.x), color.a);
+}
+
+fn tonemap_aces(x: vec3<f32>) -> vec3<f32> {
+    // Narkowicz's fit of the ACES filmic curve
+    let a = 2.51;
+    let b = 0.03;
+    let c = 2.43;
+    let d = 0.59;
+    let e = 0.14;
+    return clamp((x * (a * x + b)) / (x * (c * x + d) + e), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+fn tonemap_reinhard(x: vec3<f32>) -> vec3<f32> {
+    return x / (1.0 + luma(x));
+}
+
+fn agx_contrast(x: vec3<f32>) -> vec3<f32> {
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x
+        + 0.4298 * x2 + 0.1191 * x - 0.00232;
+}
+
+fn tonemap_agx(x: vec3<f32>) -> vec3<f32> {
+    let inset = mat3x3<f32>(
+        0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+        0.0784335999999992, 0.878468636469772, 0.0784336,
+        0.0792237451477643, 0.0791661274605434, 0.879142973793104,
+    );
+    let outset = mat3x3<f32>(
+        1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+        -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+        -0.0990297440797205, -0.0989611768448433, 1.15107367264116,
+    );
+    let min_ev = -12.47393;
+    let max_ev = 4.026069;
+
+    var v = inset * x;
+    v = clamp(log2(max(v, vec3<f32>(1e-10))), vec3<f32>(min_ev), vec3<f32>(max_ev));
+    v = (v - min_ev) / (max_ev - min_ev);
+    v = outset * agx_contrast(v);
+    // the curve outputs display values, go back to linear for the gamma pass
+    return pow(max(v, vec3<f32>(0.0)), vec3<f32>(2.2));
+}
+
+// x: 0 ACES, 1 Reinhard, 2 AgX
+@fragment
+fn fs_tonemap(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = sample_source(in.uv);
+    var mapped: vec3<f32>;
+    switch u32(pass_params.params.x) {
+        case 0u: { mapped = tonemap_aces(color.rgb); }
+        case 1u: { mapped = tonemap_reinhard(color.rgb); }
+        default: { mapped = tonemap_agx(color.rgb); }
+    }
+    return vec4<f32>(mapped, color.a);
+}
+
+// x: gamma
+@fragment
+fn fs_gamma(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = sample_source(in.uv);
+    return vec4<f32>(pow(max(color.rgb, vec3<f32>(0.0)), vec3<f32>(1.0 / pass_params.params.x)), color.a);
+}
+
+// x: intensity, y: radius, z: smoothness
+@fragment
+fn fs_vignette(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = sample_source(in.uv);
+    // 1.0 in the corners
+    let distance = length(in.uv - vec2<f32>(0.5)) * sqrt(2.0);
+    let p = pass_params.params;
+    let darken = p.x * smoothstep(p.y, p.y + p.z, distance);
+    return vec4<f32>(color.rgb * (1.0 - darken), color.a);
+}
+
+const FXAA_REDUCE_MIN: f32 = 1.0 / 128.0;
+const FXAA_REDUCE_MUL: f32 = 1.0 / 8.0;
+const FXAA_SPAN_MAX: f32 = 8.0;
+
+// Fast approximate anti-aliasing, best run on gamma encoded colors
+@fragment
+fn fs_fxaa(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(source));
+    let center = sample_source(in.uv);
+    let nw = luma(sample_source(in.uv + vec2<f32>(-1.0, -1.0) * texel).rgb);
+    let ne = luma(sample_source(in.uv + vec2<f32>(1.0, -1.0) * texel).rgb);
+    let sw = luma(sample_source(in.uv + vec2<f32>(-1.0, 1.0) * texel).rgb);
+    let se = luma(sample_source(in.uv + vec2<f32>(1.0, 1.0) * texel).rgb);
+    let m = luma(center.rgb);
+    let luma_min = min(m, min(min(nw, ne), min(sw, se)));
+    let luma_max = max(m, max(max(nw, ne), max(sw, se)));
+
+    var dir = vec2<f32>(-((nw + ne) - (sw + se)), (nw + sw) - (ne + se));
+    let reduce = max((nw + ne + sw + se) * 0.25 * FXAA_REDUCE_MUL, FXAA_REDUCE_MIN);
+    let scale = 1.0 / (min(abs(dir.x), abs(dir.y)) + reduce);
+    dir = clamp(dir * scale, vec2<f32>(-FXAA_SPAN_MAX), vec2<f32>(FXAA_SPAN_MAX)) * texel;
+
+    let a = 0.5 * (sample_source(in.uv + dir * (1.0 / 3.0 - 0.5)).rgb
+        + sample_source(in.uv + dir * (2.0 / 3.0 - 0.5)).rgb);
+    let b = a * 0.5 + 0.25 * (sample_source(in.uv - dir * 0.5).rgb
+        + sample_source(in.uv + dir * 0.5).rgb);
+    let luma_b = luma(b);
+    if luma_b < luma_min || luma_b > luma_max {
+        return vec4<f32>(a, center.a);
+    }
+    return vec4<f32>(b, center.a);
+}
+
+// 4x4 box filter made of four bilinear taps
+fn downsample(uv: vec2<f32>) -> vec3<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(source));
+    return 0.25 * (sample_source(uv + vec2<f32>(-1.0, -1.0) * texel).rgb
+        + sample_source(uv + vec2<f32>(1.0, -1.0) * texel).rgb
+        + sample_source(uv + vec2<f32>(-1.0, 1.0) * texel).rgb
+        + sample_source(uv + vec2<f32>(1.0, 1.0) * texel).rgb);
+}
+
+// x: threshold, y: soft knee
+@fragment
+fn fs_bloom_prefilter(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = downsample(in.uv);
+    let threshold = pass_params.params.x;
+    let knee = max(threshold * pass_params.params.y, 1e-5);
+    let brightness = max(color.r, max(color.g, color.b));
+    var soft = clamp(brightness - threshold + knee, 0.0, 2.0 * knee);
+    soft = soft * soft / (4.0 * knee);
+    let contribution = max(soft, brightness - threshold) / max(brightness, 1e-5);
+    return vec4<f32>(color * contribution, 1.0);
+}
+
+@fragment
+fn fs_bloom_downsample(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(downsample(in.uv), 1.0);
+}
+
+// 3x3 tent filter, added onto the next larger level by blending
+@fragment
+fn fs_bloom_upsample(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(source));
+    var color = sample_source(in.uv).rgb * 4.0;
+    color += (sample_source(in.uv + vec2<f32>(-1.0, 0.0) * texel).rgb
+        + sample_source(in.uv + vec2<f32>(1.0, 0.0) * texel).rgb
+        + sample_source(in.uv + vec2<f32>(0.0, -1.0) * texel).rgb
+        + sample_source(in.uv + vec2<f32>(0.0, 1.0) * texel).rgb) * 2.0;
+    color += sample_source(in.uv + vec2<f32>(-1.0, -1.0) * texel).rgb
+        + sample_source(in.uv + vec2<f32>(1.0, -1.0) * texel).rgb
+        + sample_source(in.uv + vec2<f32>(-1.0, 1.0) * texel).rgb
+        + sample_source(in.uv + vec2<f32>(1.0, 1.0) * texel).rgb;
+    return vec4<f32>(color / 16.0, 1.0);
+}
+
+// x: intensity
+@fragment
+fn fs_bloom_composite(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = sample_source(in.uv);
+    let glow = textureSampleLevel(bloom, source_sampler, in.uv, 0.0).rgb;
+    return vec4<f32>(color.rgb + glow * pass_params.params.x, color.a);
+}
+
+fn srgb_to_linear(c: vec3<f32>) -> vec3<f32> {
+    let low = c / 12.92;
+    let high = pow((c + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(high, low, c <= vec3<f32>(0.04045));
+}
+
+// x: 1.0 when the colors are already gamma encoded but the surface will
+// encode them again
+@fragment
+fn fs_output(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = sample_source(in.uv);
+    if pass_params.params.x > 0.5 {
+        return vec4<f32>(srgb_to_linear(clamp(color.rgb, vec3<f32>(0.0), vec3<f32>(1.0))), color.a);
+    }
+    return color;
+}
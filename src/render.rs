@@ -1,123 +1,222 @@
-use crate::camera::{Camera, Projection};
+use crate::camera::{Camera, CameraView, Projection};
+use crate::capture::PendingCapture;
+use crate::debug_draw;
+use crate::environment::{self, EnvironmentMap, EnvironmentSource, GradientSky};
+use crate::graph::{PassDesc, PassTextures, RenderGraph, ResourceId, TextureDesc};
+use crate::light::{Lights, LightsUniform};
+use crate::material::{
+    self, BlendMode, DefaultTextures, GpuMaterial, Material, MaterialId,
+    MaterialPipelines, PbrMaterial, Phase, PipelineContext, PipelineKey,
+};
 use crate::mesh::Mesh;
-use crate::texture;
-use crate::transform::Transform;
+use crate::post::{self, PostChain};
+use crate::shader::{self, ShaderCache, ShaderDefs, ShaderWatcher};
+use crate::shadow::{self, ShadowMaps, ShadowSettings};
+use crate::skybox;
+use crate::texture::{self, RenderTarget, RenderTargetId, ResizePolicy};
+use crate::time;
+use crate::transparency::{self, OitTargets, TransparencyMode};
 use crate::vertex::Vertex;
+use crate::view::{ClearPipelines, PixelRect, View};
+use anyhow::Context;
+use glam::{Vec3, Vec4};
 use std::borrow::Cow;
-use wgpu::util::DeviceExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use winit::window::Window;
 
+mod builder;
+mod output;
+
+pub use builder::{NoAdapter, RenderBuilder, RenderSettings, SurfaceFormatPreference};
+pub use output::OutputId;
+use output::{Output, OutputState};
+
 pub struct Buffers {
     vertex: wgpu::Buffer,
     index: wgpu::Buffer,
-    uniform: wgpu::Buffer,
+    lights: wgpu::Buffer,
+    debug_vertex: wgpu::Buffer,
 }
 
+/// Size dependent textures of an output that outlive a frame, the others
+/// are transient textures of the frame graph
 pub struct RenderTextures {
-    depth_texture: texture::Texture,
+    oit: OitTargets,
 }
 
 pub struct BindGroups {
-    camera_bind_group: wgpu::BindGroup,
+    camera_layout: wgpu::BindGroupLayout,
+    /// One per view drawn in the current frame, grown on demand
+    cameras: Vec<CameraBinding>,
+    lights_layout: wgpu::BindGroupLayout,
+    lights_bind_group: wgpu::BindGroup,
+    material_layout: wgpu::BindGroupLayout,
+    environment_layout: wgpu::BindGroupLayout,
+    environment_bind_group: wgpu::BindGroup,
+    skybox_layout: wgpu::BindGroupLayout,
+    skybox_bind_group: wgpu::BindGroup,
+    oit_layout: wgpu::BindGroupLayout,
 }
 
-pub struct Render<'a> {
-    queue: wgpu::Queue,
-    buffers: Buffers,
-    _instance: wgpu::Instance,
-    device: wgpu::Device,
-    config: wgpu::SurfaceConfiguration,
-    surface: wgpu::Surface<'a>,
-    pub size: winit::dpi::PhysicalSize<u32>,
-    window: Window,
-    render_pipeline: wgpu::RenderPipeline,
-    render_textures: RenderTextures,
-    bind_groups: BindGroups,
+pub struct Shaders {
+    /// Permutations of the mesh shaders and the skybox
+    cache: ShaderCache,
+    /// File name of the mesh shader in `cache`
+    main: String,
+    debug: wgpu::ShaderModule,
+    oit: wgpu::ShaderModule,
 }
 
-impl<'a> Render<'a> {
-    pub async fn new<P: Projection>(window: Window, projection_type: P) -> Self {
-        let size = window.inner_size();
+pub struct PipelineLayouts {
+    /// Camera and lights
+    main: wgpu::PipelineLayout,
+    /// Camera, lights, material and environment, shared by every material
+    mesh: wgpu::PipelineLayout,
+    /// Camera and sky cube map
+    skybox: wgpu::PipelineLayout,
+    /// Weighted blended transparency targets
+    oit_composite: wgpu::PipelineLayout,
+}
 
-        let instance = wgpu::Instance::default();
-        let surface = {
-            let window_ptr = &window as *const Window;
-            // SAFETY:
-            //   Self owns both window and surface
-            instance.create_surface(unsafe { &*window_ptr }).unwrap()
-        };
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
-                force_fallback_adapter: false,
-                compatible_surface: Some(&surface),
-            })
-            .await
-            .expect("Adapter should be appropriate for the given surface");
-
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    label: None,
-                    required_features: wgpu::Features::empty(),
-                    required_limits: wgpu::Limits::downlevel_webgl2_defaults()
-                        .using_resolution(adapter.limits()),
-                },
-                None,
-            )
-            .await
-            .expect("Failed to create device");
-
-        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: None,
-            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!(
-                "shader.wgsl"
-            ))),
-        });
+/// Pipelines that do not depend on a material, see `MaterialPipelines` for
+/// the mesh pipelines
+pub struct Pipelines {
+    oit_composite: wgpu::RenderPipeline,
+    skybox: wgpu::RenderPipeline,
+    debug: wgpu::RenderPipeline,
+    debug_overlay: wgpu::RenderPipeline,
+    clear: ClearPipelines,
+}
 
-        let path = std::env::current_dir().unwrap().join("assets/teapot.obj");
-        let mesh = Mesh::from(path);
+/// Swapchain presentation and frame pacing options
+#[derive(Clone, Copy, Debug)]
+pub struct PresentSettings {
+    pub present_mode: wgpu::PresentMode,
+    /// Frames the CPU may queue ahead of the GPU
+    pub max_frames_in_flight: u32,
+    /// Optional frame rate cap, see `time::next_frame_time`
+    pub frame_rate_cap: Option<f32>,
+}
 
-        let vertex_buffer =
-            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Vertex Buffer"),
-                contents: bytemuck::cast_slice(&mesh.vertices_transformed()),
-                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            });
+impl Default for PresentSettings {
+    fn default() -> Self {
+        Self {
+            present_mode: wgpu::PresentMode::AutoVsync,
+            max_frames_in_flight: 2,
+            frame_rate_cap: None,
+        }
+    }
+}
 
-        let index_buffer =
-            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Index Buffer"),
-                contents: bytemuck::cast_slice(&mesh.indices),
-                usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
-            });
+/// Mirrors `CameraUniform` in camera.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct CameraUniform {
+    view_proj: [[f32; 4]; 4],
+    position: [f32; 4],
+    forward: [f32; 4],
+    /// Inverse of the view projection without translation, maps clip space
+    /// to view directions
+    inv_rotation_view_proj: [[f32; 4]; 4],
+}
 
-        let config = surface
-            .get_default_config(&adapter, size.width, size.height)
-            .unwrap();
+unsafe impl bytemuck::Pod for CameraUniform {}
+unsafe impl bytemuck::Zeroable for CameraUniform {}
 
-        let camera = Camera::new(
-            90.0,
-            config.width as f32 / config.height as f32,
-            projection_type,
-            Transform::from_translation(glam::Vec3::new(0.0, 0.0, -10.0)),
+impl CameraUniform {
+    fn new(camera: &dyn CameraView, aspect_ratio: f32) -> Self {
+        Self {
+            view_proj: camera.projection_matrix(aspect_ratio).to_cols_array_2d(),
+            position: camera.position().extend(1.0).into(),
+            forward: camera.forward().extend(0.0).into(),
+            inv_rotation_view_proj: camera
+                .rotation_projection_matrix(aspect_ratio)
+                .inverse()
+                .to_cols_array_2d(),
+        }
+    }
+}
+
+/// The camera uniform of one view
+struct CameraBinding {
+    buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+impl CameraBinding {
+    fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout) -> Self {
+        let buffer = create_buffer(
+            device,
+            "Camera Buffer",
+            std::mem::size_of::<CameraUniform>() as wgpu::BufferAddress,
+            wgpu::BufferUsages::UNIFORM,
         );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("camera_bind_group"),
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+        });
+        Self { buffer, bind_group }
+    }
+}
 
-        let mx_total = camera.projection_matrix();
-        let mx_ref: &[f32; 16] = mx_total.as_ref();
+/// Everything created from the device and shared by the outputs. It is
+/// rebuilt as a whole when the device is lost.
+pub struct GpuResources {
+    adapter: wgpu::Adapter,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    shaders: Shaders,
+    pipeline_layouts: PipelineLayouts,
+    pipelines: Pipelines,
+    material_pipelines: MaterialPipelines,
+    buffers: Buffers,
+    shadow_maps: ShadowMaps,
+    bind_groups: BindGroups,
+    default_textures: DefaultTextures,
+    materials: Vec<GpuMaterial>,
+    /// Drawn for meshes without a material
+    default_material: GpuMaterial,
+    render_targets: Vec<GpuRenderTarget>,
+    environment: EnvironmentMap,
+}
 
-        let uniform_buffer =
-            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Uniform Buffer"),
-                contents: bytemuck::cast_slice(mx_ref),
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            });
+impl GpuResources {
+    fn new(
+        adapter: wgpu::Adapter,
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        settings: &RenderSettings,
+        custom_shaders: &[(String, Cow<'static, str>)],
+    ) -> anyhow::Result<Self> {
+        let vertex_buffer = create_buffer(
+            &device,
+            "Vertex Buffer",
+            INITIAL_BUFFER_SIZE,
+            wgpu::BufferUsages::VERTEX,
+        );
+        let index_buffer = create_buffer(
+            &device,
+            "Index Buffer",
+            INITIAL_BUFFER_SIZE,
+            wgpu::BufferUsages::INDEX,
+        );
+        let lights_buffer = create_buffer(
+            &device,
+            "Lights Buffer",
+            std::mem::size_of::<LightsUniform>() as wgpu::BufferAddress,
+            wgpu::BufferUsages::UNIFORM,
+        );
 
         let camera_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
                         has_dynamic_offset: false,
@@ -128,163 +227,2341 @@ impl<'a> Render<'a> {
                 label: Some("camera_bind_group_layout"),
             });
 
-        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("camera_bind_group"),
-            layout: &camera_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: uniform_buffer.as_entire_binding(),
-            }],
-        });
+        let camera_binding = CameraBinding::new(&device, &camera_bind_group_layout);
+
+        let lights_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Depth,
+                            view_dimension: wgpu::TextureViewDimension::D2Array,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(
+                            wgpu::SamplerBindingType::Comparison,
+                        ),
+                        count: None,
+                    },
+                ],
+                label: Some("lights_bind_group_layout"),
+            });
+        let shadow_maps = ShadowMaps::new(&device, &settings.shadows);
+        let lights_bind_group = create_lights_bind_group(
+            &device,
+            &lights_layout,
+            &lights_buffer,
+            &shadow_maps,
+        );
 
         let pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: None,
-                bind_group_layouts: &[&camera_bind_group_layout],
+                bind_group_layouts: &[&camera_bind_group_layout, &lights_layout],
                 push_constant_ranges: &[],
             });
 
-        let swapchain_capabilities = surface.get_capabilities(&adapter);
-        let swapchain_format = swapchain_capabilities.formats[0];
-
-        let depth_texture =
-            texture::create_depth_texture(&device, &config, "depth_texture");
-        let render_pipeline =
-            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                label: None,
-                layout: Some(&pipeline_layout),
-                vertex: wgpu::VertexState {
-                    module: &shader,
-                    entry_point: "vs_main",
-                    buffers: &[Vertex::desc()],
-                },
-                fragment: Some(wgpu::FragmentState {
-                    module: &shader,
-                    entry_point: "fs_main",
-                    targets: &[Some(swapchain_format.into())],
-                }),
-                primitive: wgpu::PrimitiveState::default(),
-                depth_stencil: Some(wgpu::DepthStencilState {
-                    format: texture::DEPTH_FORMAT,
-                    depth_write_enabled: true,
-                    depth_compare: wgpu::CompareFunction::Less,
-                    stencil: wgpu::StencilState::default(),
-                    bias: wgpu::DepthBiasState::default(),
-                }),
-                multisample: wgpu::MultisampleState::default(),
-                multiview: None,
+        let material_layout = material::create_bind_group_layout(&device);
+        let environment_layout = environment::create_bind_group_layout(&device);
+        let mesh_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("mesh_pipeline_layout"),
+                bind_group_layouts: &[
+                    &camera_bind_group_layout,
+                    &lights_layout,
+                    &material_layout,
+                    &environment_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+        let environment =
+            EnvironmentMap::from_gradient(&device, &queue, &GradientSky::default());
+        let environment_bind_group =
+            environment::create_bind_group(&device, &environment_layout, &environment);
+        let skybox_layout = skybox::create_bind_group_layout(&device);
+        let skybox_bind_group =
+            skybox::create_bind_group(&device, &skybox_layout, &environment.cube);
+        let skybox_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("skybox_pipeline_layout"),
+                bind_group_layouts: &[&camera_bind_group_layout, &skybox_layout],
+                push_constant_ranges: &[],
+            });
+        let oit_layout = transparency::create_bind_group_layout(&device);
+        let oit_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("oit_composite_pipeline_layout"),
+                bind_group_layouts: &[&oit_layout],
+                push_constant_ranges: &[],
             });
+        let pipeline_layouts = PipelineLayouts {
+            main: pipeline_layout,
+            mesh: mesh_pipeline_layout,
+            skybox: skybox_pipeline_layout,
+            oit_composite: oit_pipeline_layout,
+        };
+        let default_textures = DefaultTextures::new(&device, &queue);
+        let default_material = GpuMaterial::new(
+            &device,
+            &material_layout,
+            &default_textures,
+            Material::lit(Vec4::ONE),
+        );
 
-        Self {
-            _instance: instance,
+        let debug_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("debug_shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("debug.wgsl"))),
+        });
+        let oit_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("oit_shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("oit.wgsl"))),
+        });
+        let (cache, main) = main_shader_cache(settings, custom_shaders)?;
+        let mut shaders = Shaders {
+            cache,
+            main,
+            debug: debug_shader,
+            oit: oit_shader,
+        };
+        let pipelines = create_pipelines(
+            &device,
+            &pipeline_layouts,
+            &mut shaders,
+            post::HDR_FORMAT,
+            settings.sample_count,
+        )?;
+        let debug_vertex_buffer = create_buffer(
+            &device,
+            "Debug Vertex Buffer",
+            INITIAL_BUFFER_SIZE,
+            wgpu::BufferUsages::VERTEX,
+        );
+
+        Ok(Self {
+            adapter,
             device,
             queue,
-            config,
-            surface,
-            size,
-            window,
-            render_pipeline,
-            bind_groups: BindGroups { camera_bind_group },
+            shaders,
+            pipeline_layouts,
+            pipelines,
+            material_pipelines: MaterialPipelines::default(),
+            bind_groups: BindGroups {
+                camera_layout: camera_bind_group_layout,
+                cameras: vec![camera_binding],
+                lights_layout,
+                lights_bind_group,
+                material_layout,
+                environment_layout,
+                environment_bind_group,
+                skybox_layout,
+                skybox_bind_group,
+                oit_layout,
+            },
             buffers: Buffers {
                 vertex: vertex_buffer,
                 index: index_buffer,
-                uniform: uniform_buffer,
+                lights: lights_buffer,
+                debug_vertex: debug_vertex_buffer,
             },
-            render_textures: RenderTextures { depth_texture },
+            shadow_maps,
+            default_textures,
+            materials: Vec::new(),
+            default_material,
+            render_targets: Vec::new(),
+            environment,
+        })
+    }
+}
+
+/// A render target with the weighted blended transparency targets of its
+/// views
+struct GpuRenderTarget {
+    target: RenderTarget,
+    /// Allocated by the first frame drawing into it with
+    /// `TransparencyMode::WeightedBlended`
+    oit: Option<OitTargets>,
+}
+
+pub struct Render {
+    instance: wgpu::Instance,
+    /// Indexed by `OutputId`, the primary output is never removed
+    outputs: Vec<Option<OutputState>>,
+    settings: RenderSettings,
+    lights: Lights,
+    gpu: GpuResources,
+    /// Set from the device lost callback, checked at the start of a frame
+    device_lost: Arc<AtomicBool>,
+    shader_watcher: Option<ShaderWatcher>,
+    /// Sources registered with `add_shader`, kept to rebuild the shader
+    /// cache after reloads and device loss
+    custom_shaders: Vec<(String, Cow<'static, str>)>,
+    /// Recreates the environment after a device loss
+    environment_source: EnvironmentSource,
+    /// Indexed by `RenderTargetId`, recreates the targets after a device loss
+    render_target_policies: Vec<ResizePolicy>,
+    /// Indexed by `MaterialId`, recreates the materials after a device loss
+    materials: Vec<Material>,
+    debug_frame: DebugFrame,
+}
+
+impl Render {
+    /// Creates a renderer with the default `RenderSettings`. The renderer
+    /// keeps the window alive until it is dropped.
+    pub async fn new(window: Arc<Window>) -> anyhow::Result<Self> {
+        RenderBuilder::new(window).build().await
+    }
+
+    pub fn builder(window: Arc<Window>) -> RenderBuilder {
+        RenderBuilder::new(window)
+    }
+
+    /// Creates a renderer that draws into an offscreen texture instead of a
+    /// window. Frames are read back with `capture_frame`.
+    pub fn headless(width: u32, height: u32) -> RenderBuilder {
+        RenderBuilder::headless(width, height)
+    }
+
+    fn from_parts(
+        instance: wgpu::Instance,
+        output: Output,
+        config: wgpu::SurfaceConfiguration,
+        settings: RenderSettings,
+        (adapter, device, queue): (wgpu::Adapter, wgpu::Device, wgpu::Queue),
+    ) -> anyhow::Result<Self> {
+        let device_lost = Arc::new(AtomicBool::new(false));
+        watch_device_loss(&device, &device_lost);
+        let gpu = GpuResources::new(adapter, device, queue, &settings, &[])?;
+        let primary = OutputState::new(&gpu, output, config, settings.sample_count);
+
+        Ok(Self {
+            instance,
+            outputs: vec![Some(primary)],
+            settings,
+            lights: Lights::default(),
+            gpu,
+            device_lost,
+            shader_watcher: None,
+            custom_shaders: Vec::new(),
+            environment_source: EnvironmentSource::default(),
+            render_target_policies: Vec::new(),
+            materials: Vec::new(),
+            debug_frame: DebugFrame::default(),
+        })
+    }
+
+    fn primary(&self) -> &OutputState {
+        self.outputs[OutputId::PRIMARY.0]
+            .as_ref()
+            .expect("the primary output is never removed")
+    }
+
+    fn primary_mut(&mut self) -> &mut OutputState {
+        self.outputs[OutputId::PRIMARY.0]
+            .as_mut()
+            .expect("the primary output is never removed")
+    }
+
+    /// The window frames are presented to, `None` for headless renderers
+    pub fn window(&self) -> Option<&Arc<Window>> {
+        self.primary().window()
+    }
+
+    /// The size of the primary window or offscreen texture
+    pub fn size(&self) -> winit::dpi::PhysicalSize<u32> {
+        self.primary().size
+    }
+
+    /// Adds another window to present frames to, with its own swapchain and
+    /// post-processing targets. It shares the device, materials, render
+    /// targets and lights with every other window, and is drawn with
+    /// `render_output`.
+    pub fn add_window(&mut self, window: Arc<Window>) -> anyhow::Result<OutputId> {
+        let surface = self
+            .instance
+            .create_surface(window.clone())
+            .context("Could not create a surface for the window")?;
+        if !self.gpu.adapter.is_surface_supported(&surface) {
+            anyhow::bail!(
+                "Adapter {:?} can not present to the window",
+                self.gpu.adapter.get_info().name
+            );
+        }
+        let mut config = builder::surface_config(
+            &surface,
+            &self.gpu.adapter,
+            window.inner_size(),
+            &self.settings,
+        )?;
+        config.present_mode = self.primary().config.present_mode;
+        config.desired_maximum_frame_latency =
+            self.primary().config.desired_maximum_frame_latency;
+        let mut output = OutputState::new(
+            &self.gpu,
+            Output::Window { surface, window },
+            config,
+            self.settings.sample_count,
+        );
+        output.configure(&self.gpu.device);
+        self.outputs.push(Some(output));
+        Ok(OutputId(self.outputs.len() - 1))
+    }
+
+    /// Stops presenting to a window added with `add_window`. Its id is not
+    /// reused.
+    pub fn remove_window(&mut self, id: OutputId) -> anyhow::Result<()> {
+        if id == OutputId::PRIMARY {
+            anyhow::bail!("The primary output can not be removed");
+        }
+        match self.outputs.get_mut(id.0) {
+            Some(output @ Some(_)) => {
+                *output = None;
+                Ok(())
+            }
+            _ => anyhow::bail!("Unknown output {id:?}"),
+        }
+    }
+
+    /// The output presenting to the winit window `window`, to route window
+    /// events like `Resized` and `RedrawRequested`
+    pub fn output_of(&self, window: winit::window::WindowId) -> Option<OutputId> {
+        self.outputs
+            .iter()
+            .position(|output| {
+                output
+                    .as_ref()
+                    .and_then(OutputState::window)
+                    .is_some_and(|w| w.id() == window)
+            })
+            .map(OutputId)
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.settings.sample_count
+    }
+
+    /// Sample counts usable for both the HDR target and the depth format on
+    /// this adapter
+    pub fn supported_sample_counts(&self) -> Vec<u32> {
+        let color = self
+            .gpu
+            .adapter
+            .get_texture_format_features(post::HDR_FORMAT);
+        let depth = self
+            .gpu
+            .adapter
+            .get_texture_format_features(texture::DEPTH_FORMAT);
+        [1, 2, 4, 8]
+            .into_iter()
+            .filter(|&count| {
+                color.flags.sample_count_supported(count)
+                    && depth.flags.sample_count_supported(count)
+            })
+            .collect()
+    }
+
+    /// Sets the MSAA sample count, rebuilding the pipelines and attachments
+    pub fn set_sample_count(&mut self, sample_count: u32) -> anyhow::Result<()> {
+        if sample_count == self.settings.sample_count {
+            return Ok(());
+        }
+        let supported = self.supported_sample_counts();
+        if !supported.contains(&sample_count) {
+            anyhow::bail!(
+                "MSAA sample count {sample_count} is not supported by this adapter \
+                 (supported: {supported:?})"
+            );
+        }
+
+        self.settings.sample_count = sample_count;
+        self.gpu.pipelines = create_pipelines(
+            &self.gpu.device,
+            &self.gpu.pipeline_layouts,
+            &mut self.gpu.shaders,
+            post::HDR_FORMAT,
+            sample_count,
+        )?;
+        // rebuilt on first use with the new sample count
+        self.gpu.material_pipelines = MaterialPipelines::default();
+        for output in self.outputs.iter_mut().flatten() {
+            output.recreate_textures(&self.gpu, sample_count);
         }
+        self.update_render_targets();
+
+        Ok(())
     }
 
-    pub fn window(&self) -> &Window {
-        &self.window
+    pub fn present_settings(&self) -> PresentSettings {
+        PresentSettings {
+            present_mode: self.primary().config.present_mode,
+            max_frames_in_flight: self.primary().config.desired_maximum_frame_latency,
+            frame_rate_cap: time::frame_rate_cap(),
+        }
     }
 
-    pub fn resize<P: Projection>(
+    /// Changes the present mode, frame latency and frame rate cap of every
+    /// window. The surfaces are reconfigured immediately.
+    pub fn set_present_settings(
         &mut self,
-        new_size: winit::dpi::PhysicalSize<u32>,
-        camera: &mut Camera<P>,
-    ) {
-        self.size = new_size;
-        self.config.width = new_size.width;
-        self.config.height = new_size.height;
-        self.render_textures.depth_texture =
-            texture::create_depth_texture(&self.device, &self.config, "depth_texture");
+        settings: PresentSettings,
+    ) -> anyhow::Result<()> {
+        let surfaces = self
+            .outputs
+            .iter()
+            .flatten()
+            .filter_map(OutputState::surface);
+        for surface in surfaces {
+            let capabilities = surface.get_capabilities(&self.gpu.adapter);
+            let automatic = matches!(
+                settings.present_mode,
+                wgpu::PresentMode::AutoVsync | wgpu::PresentMode::AutoNoVsync
+            );
+            if !automatic
+                && !capabilities.present_modes.contains(&settings.present_mode)
+            {
+                anyhow::bail!(
+                    "present mode {:?} is not supported by this surface \
+                     (supported: {:?})",
+                    settings.present_mode,
+                    capabilities.present_modes
+                );
+            }
+        }
+        if settings.max_frames_in_flight == 0 {
+            anyhow::bail!("max_frames_in_flight must be at least 1");
+        }
+
+        self.settings.present = settings;
+        time::set_frame_rate_cap(settings.frame_rate_cap);
+        for output in self.outputs.iter_mut().flatten() {
+            output.config.present_mode = settings.present_mode;
+            output.config.desired_maximum_frame_latency = settings.max_frames_in_flight;
+            output.configure(&self.gpu.device);
+        }
 
-        camera.aspect_ratio = self.size.width as f32 / self.size.height as f32;
+        Ok(())
+    }
 
-        let mx_total = camera.projection_matrix();
-        let mx_ref: &[f32; 16] = mx_total.as_ref();
+    pub fn lights(&self) -> &Lights {
+        &self.lights
+    }
 
-        self.queue
-            .write_buffer(&self.buffers.uniform, 0, bytemuck::cast_slice(mx_ref));
+    pub fn lights_mut(&mut self) -> &mut Lights {
+        &mut self.lights
+    }
 
-        self.surface.configure(&self.device, &self.config);
-        self.window.request_redraw();
+    pub fn shadow_settings(&self) -> ShadowSettings {
+        self.settings.shadows
     }
 
-    pub fn render(&mut self, meshes: &[Mesh]) -> Result<(), wgpu::SurfaceError> {
-        let frame = self
-            .surface
-            .get_current_texture()
-            .expect("failed to acquire next swap chain texture");
-        let view = frame
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    /// Changes the shadow map resolution, biases and cascades, recreating
+    /// the shadow maps
+    pub fn set_shadow_settings(
+        &mut self,
+        settings: ShadowSettings,
+    ) -> anyhow::Result<()> {
+        settings.validate(&self.gpu.device.limits())?;
+        self.settings.shadows = settings;
 
-        {
-            let mut render_pass =
-                encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                    label: Some("Render Pass"),
-                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                        view: &view,
-                        resolve_target: None,
-                        ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
-                            store: wgpu::StoreOp::Store,
-                        },
-                    })],
-                    depth_stencil_attachment: Some(
-                        wgpu::RenderPassDepthStencilAttachment {
-                            view: &self.render_textures.depth_texture.view,
-                            depth_ops: Some(wgpu::Operations {
-                                load: wgpu::LoadOp::Clear(1.0),
-                                store: wgpu::StoreOp::Store,
-                            }),
-                            stencil_ops: None,
-                        },
-                    ),
-                    timestamp_writes: None,
-                    occlusion_query_set: None,
-                });
+        let gpu = &mut self.gpu;
+        gpu.shadow_maps = ShadowMaps::new(&gpu.device, &settings);
+        gpu.bind_groups.lights_bind_group = create_lights_bind_group(
+            &gpu.device,
+            &gpu.bind_groups.lights_layout,
+            &gpu.buffers.lights,
+            &gpu.shadow_maps,
+        );
+
+        Ok(())
+    }
+
+    /// Registers a material for meshes to reference
+    pub fn add_material(&mut self, material: impl Into<Material>) -> MaterialId {
+        let material = material.into();
+        let gpu = &mut self.gpu;
+        gpu.materials.push(GpuMaterial::new(
+            &gpu.device,
+            &gpu.bind_groups.material_layout,
+            &gpu.default_textures,
+            material.clone(),
+        ));
+        self.materials.push(material);
+        MaterialId(self.materials.len() - 1)
+    }
+
+    pub fn material(&self, id: MaterialId) -> Option<&Material> {
+        self.materials.get(id.0)
+    }
+
+    /// Replaces a material. Only the uniform buffer is rewritten when the
+    /// textures did not change.
+    pub fn set_material(
+        &mut self,
+        id: MaterialId,
+        material: impl Into<Material>,
+    ) -> anyhow::Result<()> {
+        let material = material.into();
+        let gpu = &mut self.gpu;
+        let Some(current) = gpu.materials.get_mut(id.0) else {
+            anyhow::bail!("Unknown material {id:?}");
+        };
+        let same_textures = |a: &PbrMaterial, b: &PbrMaterial| {
+            let same =
+                |a: &Option<Arc<texture::Texture>>,
+                 b: &Option<Arc<texture::Texture>>| match (a, b) {
+                    (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                    (None, None) => true,
+                    _ => false,
+                };
+            same(&a.base_color_texture, &b.base_color_texture)
+                && same(&a.metallic_roughness_texture, &b.metallic_roughness_texture)
+                && same(&a.normal_texture, &b.normal_texture)
+                && same(&a.occlusion_texture, &b.occlusion_texture)
+                && same(&a.emissive_texture, &b.emissive_texture)
+        };
 
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.set_bind_group(0, &self.bind_groups.camera_bind_group, &[]);
-            render_pass.set_vertex_buffer(0, self.buffers.vertex.slice(..));
-            render_pass.set_index_buffer(
-                self.buffers.index.slice(..),
-                wgpu::IndexFormat::Uint16,
+        if same_textures(&current.material.params, &material.params) {
+            current.material = material.clone();
+            current.write_factors(&gpu.queue);
+        } else {
+            *current = GpuMaterial::new(
+                &gpu.device,
+                &gpu.bind_groups.material_layout,
+                &gpu.default_textures,
+                material.clone(),
             );
-            for mesh in meshes {
-                self.queue.write_buffer(
-                    &self.buffers.vertex,
-                    0,
-                    bytemuck::cast_slice(&mesh.vertices_transformed()),
-                );
+        }
+        self.materials[id.0] = material;
 
-                self.queue.write_buffer(
-                    &self.buffers.index,
-                    0,
-                    bytemuck::cast_slice(&mesh.indices),
-                );
-                render_pass.draw_indexed(0..mesh.indices.len() as u32, 0, 0..1);
+        Ok(())
+    }
+
+    /// Creates an offscreen target for `View::with_target`, whose color
+    /// materials can sample. `ResizePolicy::Surface` follows the primary
+    /// output.
+    pub fn add_render_target(&mut self, policy: ResizePolicy) -> RenderTargetId {
+        self.render_target_policies.push(policy);
+        self.gpu
+            .render_targets
+            .push(self.create_render_target(policy));
+        RenderTargetId(self.render_target_policies.len() - 1)
+    }
+
+    fn create_render_target(&self, policy: ResizePolicy) -> GpuRenderTarget {
+        GpuRenderTarget {
+            target: RenderTarget::new(
+                &self.gpu.device,
+                policy,
+                self.surface_size(),
+                self.settings.sample_count,
+            ),
+            oit: None,
+        }
+    }
+
+    pub fn render_target(&self, id: RenderTargetId) -> Option<&RenderTarget> {
+        self.gpu
+            .render_targets
+            .get(id.0)
+            .map(|target| &target.target)
+    }
+
+    /// Changes how a render target is sized, resizing it right away
+    pub fn set_render_target_policy(
+        &mut self,
+        id: RenderTargetId,
+        policy: ResizePolicy,
+    ) -> anyhow::Result<()> {
+        let Some(target) = self.gpu.render_targets.get_mut(id.0) else {
+            anyhow::bail!("Unknown render target {id:?}");
+        };
+        target.target.set_policy(policy);
+        self.render_target_policies[id.0] = policy;
+        self.update_render_targets();
+        Ok(())
+    }
+
+    /// The size render targets with `ResizePolicy::Surface` scale
+    fn surface_size(&self) -> (u32, u32) {
+        let config = &self.primary().config;
+        (config.width, config.height)
+    }
+
+    /// Reallocates the render targets that no longer match the surface size
+    /// or sample count, and points the materials sampling a replaced color
+    /// texture at the new one
+    fn update_render_targets(&mut self) {
+        let surface = self.surface_size();
+        let gpu = &mut self.gpu;
+        for target in &mut gpu.render_targets {
+            let before = (target.target.size(), target.target.sample_count());
+            let previous =
+                target
+                    .target
+                    .update(&gpu.device, surface, self.settings.sample_count);
+            if (target.target.size(), target.target.sample_count()) != before {
+                target.oit = None;
+            }
+            let Some(previous) = previous else {
+                continue;
+            };
+            let current = target.target.color().clone();
+            for (material, gpu_material) in
+                self.materials.iter_mut().zip(&mut gpu.materials)
+            {
+                let mut replaced = false;
+                for texture in material.params.textures_mut() {
+                    if texture
+                        .as_ref()
+                        .is_some_and(|texture| Arc::ptr_eq(texture, &previous))
+                    {
+                        *texture = Some(current.clone());
+                        replaced = true;
+                    }
+                }
+                if replaced {
+                    *gpu_material = GpuMaterial::new(
+                        &gpu.device,
+                        &gpu.bind_groups.material_layout,
+                        &gpu.default_textures,
+                        material.clone(),
+                    );
+                }
             }
         }
+    }
 
-        self.queue.submit(Some(encoder.finish()));
-        frame.present();
+    /// Registers WGSL for `MaterialShader::Custom(name)`, or replaces it.
+    /// The source may include the built in files like mesh.wgsl, and must
+    /// validate both as is and with `WEIGHTED_BLENDED` defined.
+    pub fn add_shader(
+        &mut self,
+        name: impl Into<String>,
+        source: impl Into<Cow<'static, str>>,
+    ) -> anyhow::Result<()> {
+        let (name, source) = (name.into(), source.into());
+        let preprocessor = self
+            .gpu
+            .shaders
+            .cache
+            .preprocessor()
+            .clone()
+            .with_source(name.clone(), source.clone());
+        for defs in [
+            ShaderDefs::new(),
+            ShaderDefs::new().with(shader::WEIGHTED_BLENDED),
+        ] {
+            let processed = preprocessor.process(&name, &defs)?;
+            shader::validate(&processed, &name)?;
+        }
 
+        self.gpu
+            .shaders
+            .cache
+            .add_source(name.clone(), source.clone());
+        self.gpu.material_pipelines.remove_shader(&name);
+        match self.custom_shaders.iter_mut().find(|(n, _)| *n == name) {
+            Some(custom) => custom.1 = source,
+            None => self.custom_shaders.push((name, source)),
+        }
         Ok(())
     }
+
+    /// Unregisters a shader added with `add_shader` along with its pipelines,
+    /// returning whether it was registered. Materials still using it are not
+    /// drawn.
+    pub fn remove_shader(&mut self, name: &str) -> bool {
+        let Some(position) = self.custom_shaders.iter().position(|(n, _)| n == name)
+        else {
+            return false;
+        };
+        self.custom_shaders.remove(position);
+        self.gpu.shaders.cache.remove_source(name);
+        self.gpu.material_pipelines.remove_shader(name);
+        true
+    }
+
+    pub fn environment(&self) -> &EnvironmentMap {
+        &self.gpu.environment
+    }
+
+    /// Replaces the environment PBR materials are lit by and the skybox
+    /// shows. Defaults to a `GradientSky`.
+    pub fn set_environment(&mut self, environment: EnvironmentMap) {
+        let gpu = &mut self.gpu;
+        gpu.bind_groups.environment_bind_group = environment::create_bind_group(
+            &gpu.device,
+            &gpu.bind_groups.environment_layout,
+            &environment,
+        );
+        gpu.bind_groups.skybox_bind_group = skybox::create_bind_group(
+            &gpu.device,
+            &gpu.bind_groups.skybox_layout,
+            &environment.cube,
+        );
+        self.environment_source = environment.source().clone();
+        gpu.environment = environment;
+    }
+
+    pub fn skybox_enabled(&self) -> bool {
+        self.settings.skybox
+    }
+
+    /// When disabled the clear color shows behind the scene instead
+    pub fn set_skybox_enabled(&mut self, enabled: bool) {
+        self.settings.skybox = enabled;
+    }
+
+    /// The device textures and environment maps are created with
+    pub fn transparency_mode(&self) -> TransparencyMode {
+        self.settings.transparency
+    }
+
+    pub fn set_transparency_mode(&mut self, mode: TransparencyMode) {
+        self.settings.transparency = mode;
+    }
+
+    pub fn device(&self) -> &wgpu::Device {
+        &self.gpu.device
+    }
+
+    pub fn queue(&self) -> &wgpu::Queue {
+        &self.gpu.queue
+    }
+
+    pub fn post_chain(&self) -> &PostChain {
+        &self.settings.post
+    }
+
+    /// The post-processing stages, which can be toggled and reordered at
+    /// any time
+    pub fn post_chain_mut(&mut self) -> &mut PostChain {
+        &mut self.settings.post
+    }
+
+    pub fn clear_color(&self) -> wgpu::Color {
+        self.settings.clear_color
+    }
+
+    pub fn set_clear_color(&mut self, color: wgpu::Color) {
+        self.settings.clear_color = color;
+    }
+
+    /// Copies the next frame of the primary output back to the CPU, to be
+    /// picked up with `take_capture` after the next `render`
+    pub fn capture_frame(&mut self) {
+        self.primary_mut().capture_requested = true;
+    }
+
+    /// The frame captured after `capture_frame`, as RGBA with the surface's
+    /// encoding. `None` until a frame was rendered.
+    pub fn take_capture(&mut self) -> Option<anyhow::Result<image::RgbaImage>> {
+        self.primary_mut().capture.take()
+    }
+
+    /// Whether the primary surface currently has an area to render into.
+    /// Minimized windows report a zero size.
+    pub fn is_drawable(&self) -> bool {
+        self.primary().is_drawable()
+    }
+
+    /// The scale factor of the primary window, 1 for headless renderers
+    pub fn scale_factor(&self) -> f64 {
+        self.primary().scale_factor
+    }
+
+    /// Follows `Resized` and `ScaleFactorChanged` events of any window the
+    /// renderer presents to, returning whether the size of its output
+    /// changed. Other events and windows are ignored. Cameras need no
+    /// update, views fit them to their viewport, see
+    /// `View::fit_aspect_ratio`.
+    pub fn handle_window_event(
+        &mut self,
+        window: winit::window::WindowId,
+        event: &winit::event::WindowEvent,
+    ) -> bool {
+        let Some(id) = self.output_of(window) else {
+            return false;
+        };
+        let new_size = match event {
+            winit::event::WindowEvent::Resized(new_size) => *new_size,
+            winit::event::WindowEvent::ScaleFactorChanged { scale_factor, .. } => {
+                let output = self.outputs[id.0].as_mut().expect("found above");
+                output.scale_factor = *scale_factor;
+                // the window picks its new physical size, which arrives in a
+                // `Resized` event on most platforms but not all
+                match output.window() {
+                    Some(window) => window.inner_size(),
+                    None => return false,
+                }
+            }
+            _ => return false,
+        };
+        if self.outputs[id.0]
+            .as_ref()
+            .is_some_and(|output| output.size == new_size)
+        {
+            return false;
+        }
+        self.resize_output(id, new_size);
+        true
+    }
+
+    /// Resizes the swapchain and every size dependent texture of one
+    /// output, after its window was resized. Render targets following the
+    /// primary output are resized with it. Zero sizes, like minimized
+    /// windows, only skip frames until the next resize. Unknown outputs are
+    /// ignored.
+    pub fn resize_output(
+        &mut self,
+        id: OutputId,
+        new_size: winit::dpi::PhysicalSize<u32>,
+    ) {
+        let Some(Some(output)) = self.outputs.get_mut(id.0) else {
+            return;
+        };
+        output.resize(&self.gpu, new_size, self.settings.sample_count);
+        if !output.is_drawable() {
+            return;
+        }
+        if let Some(window) = output.window() {
+            window.request_redraw();
+        }
+        if id == OutputId::PRIMARY {
+            self.update_render_targets();
+        }
+    }
+
+    /// Rebuilds the pipelines if a watched shader file changed. Shaders that
+    /// do not compile are logged and the previous pipelines are kept.
+    fn reload_changed_shaders(&mut self) {
+        let Some(watcher) = &mut self.shader_watcher else {
+            return;
+        };
+        if !watcher.poll() {
+            return;
+        }
+        let path = watcher.path().to_owned();
+        match self.rebuild_shaders() {
+            Ok(()) => log::info!("Reloaded {}", path.display()),
+            Err(e) => log::error!("Keeping the previous shaders: {e:#}"),
+        }
+    }
+
+    fn rebuild_shaders(&mut self) -> anyhow::Result<()> {
+        let (cache, _) = main_shader_cache(&self.settings, &self.custom_shaders)?;
+        let gpu = &mut self.gpu;
+        let previous = std::mem::replace(&mut gpu.shaders.cache, cache);
+
+        // naga accepts shaders the pipelines can not use, like ones missing
+        // an entry point, so wgpu errors are caught instead of panicking
+        gpu.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let pipelines = create_pipelines(
+            &gpu.device,
+            &gpu.pipeline_layouts,
+            &mut gpu.shaders,
+            post::HDR_FORMAT,
+            self.settings.sample_count,
+        );
+        let error = pollster::block_on(gpu.device.pop_error_scope());
+        let material_pipelines = gpu.material_pipelines.rebuild(&mut PipelineContext {
+            device: &gpu.device,
+            layout: &gpu.pipeline_layouts.mesh,
+            shaders: &mut gpu.shaders.cache,
+            main_shader: &gpu.shaders.main,
+            format: post::HDR_FORMAT,
+            sample_count: self.settings.sample_count,
+        });
+        let (pipelines, material_pipelines) =
+            match (pipelines, error, material_pipelines) {
+                (Ok(pipelines), None, Ok(material_pipelines)) => {
+                    (pipelines, material_pipelines)
+                }
+                (Err(e), _, _) | (_, _, Err(e)) => {
+                    gpu.shaders.cache = previous;
+                    return Err(e);
+                }
+                (Ok(_), Some(error), _) => {
+                    gpu.shaders.cache = previous;
+                    anyhow::bail!("{error}");
+                }
+            };
+
+        gpu.pipelines = pipelines;
+        gpu.material_pipelines = material_pipelines;
+        Ok(())
+    }
+
+    /// Requests a new device and rebuilds every GPU resource from it,
+    /// including the materials, render targets and the environment. Called
+    /// automatically by `render` after the device was lost.
+    ///
+    /// Material textures other than render targets were created on the lost
+    /// device, they are replaced by the defaults until `set_material` gives
+    /// the material new ones.
+    pub fn recover_device(&mut self) -> anyhow::Result<()> {
+        log::warn!("Recreating the GPU device and resources");
+        let parts = pollster::block_on(builder::request_device(
+            &self.instance,
+            self.primary().surface(),
+            &self.settings,
+        ))?;
+        let (adapter, device, queue) = parts;
+        self.device_lost.store(false, Ordering::SeqCst);
+        watch_device_loss(&device, &self.device_lost);
+        let lost = std::mem::replace(
+            &mut self.gpu,
+            GpuResources::new(
+                adapter,
+                device,
+                queue,
+                &self.settings,
+                &self.custom_shaders,
+            )?,
+        );
+        let sample_count = self.settings.sample_count;
+        for output in self.outputs.iter_mut().flatten() {
+            output.recreate(&self.gpu, sample_count);
+        }
+        self.gpu.render_targets = self
+            .render_target_policies
+            .iter()
+            .map(|&policy| self.create_render_target(policy))
+            .collect();
+
+        // materials sampling a render target follow it to the new device
+        let mut dropped = 0;
+        for material in &mut self.materials {
+            for texture in material.params.textures_mut() {
+                let Some(current) = texture.as_ref() else {
+                    continue;
+                };
+                *texture = lost
+                    .render_targets
+                    .iter()
+                    .position(|lost| Arc::ptr_eq(lost.target.color(), current))
+                    .map(|index| self.gpu.render_targets[index].target.color().clone());
+                dropped += usize::from(texture.is_none());
+            }
+        }
+        if dropped > 0 {
+            log::warn!("{dropped} material textures were lost with the device");
+        }
+        let gpu = &mut self.gpu;
+        gpu.materials = self
+            .materials
+            .iter()
+            .map(|material| {
+                GpuMaterial::new(
+                    &gpu.device,
+                    &gpu.bind_groups.material_layout,
+                    &gpu.default_textures,
+                    material.clone(),
+                )
+            })
+            .collect();
+        // the uploaded shapes went with the old vertex buffer
+        self.debug_frame = DebugFrame::default();
+
+        let gpu = &self.gpu;
+        match EnvironmentMap::from_source(
+            &gpu.device,
+            &gpu.queue,
+            &self.environment_source,
+        ) {
+            Ok(environment) => self.set_environment(environment),
+            Err(e) => {
+                log::warn!(
+                    "Could not recreate the environment, using the default sky: {e:#}"
+                );
+                self.environment_source = EnvironmentSource::default();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Renders one frame from `camera`, after reloading the shaders if they
+    /// are watched and changed.
+    ///
+    /// Frames are skipped while the surface is zero-sized. `Outdated` and
+    /// `Lost` surfaces are reconfigured before the error is returned, so the
+    /// next frame can be rendered normally. Headless renderers only fail when
+    /// the device was lost and could not be recreated.
+    pub fn render<P: Projection>(
+        &mut self,
+        camera: &Camera<P>,
+        meshes: &[Mesh],
+    ) -> Result<(), wgpu::SurfaceError> {
+        self.render_views(&[View::new(camera)], meshes)
+    }
+
+    /// Renders one frame with every view drawn to its viewport, in order.
+    /// Errors are the same as for `render`.
+    pub fn render_views(
+        &mut self,
+        views: &[View],
+        meshes: &[Mesh],
+    ) -> Result<(), wgpu::SurfaceError> {
+        self.render_output(OutputId::PRIMARY, views, meshes)
+    }
+
+    /// Renders one frame of the window `id`, like `render_views`. Removed
+    /// outputs report `SurfaceError::Lost`.
+    pub fn render_output(
+        &mut self,
+        id: OutputId,
+        views: &[View],
+        meshes: &[Mesh],
+    ) -> Result<(), wgpu::SurfaceError> {
+        if self.device_lost.load(Ordering::SeqCst) {
+            if let Err(e) = self.recover_device() {
+                log::error!("Could not recover from device loss: {e:#}");
+                return Err(wgpu::SurfaceError::Lost);
+            }
+        }
+        self.reload_changed_shaders();
+        let Some(Some(output)) = self.outputs.get_mut(id.0) else {
+            log::error!("Rendering to unknown output {id:?}");
+            return Err(wgpu::SurfaceError::Lost);
+        };
+        if !output.is_drawable() {
+            return Ok(());
+        }
+
+        let frame = output.surface().map(wgpu::Surface::get_current_texture);
+        let frame = match frame.transpose() {
+            Ok(frame) => frame,
+            Err(e @ (wgpu::SurfaceError::Outdated | wgpu::SurfaceError::Lost)) => {
+                output.configure(&self.gpu.device);
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        let size = (output.config.width, output.config.height);
+
+        let frame_views = self.prepare_views(views, size);
+        let shadow_layers = match frame_views.first() {
+            Some(first) => {
+                let shadow_frame = shadow::prepare(
+                    &self.lights,
+                    views[first.view].camera,
+                    first.aspect_ratio,
+                    &self.settings.shadows,
+                );
+                self.gpu.queue.write_buffer(
+                    &self.gpu.buffers.lights,
+                    0,
+                    bytemuck::cast_slice(&[shadow_frame.uniform]),
+                );
+                self.gpu
+                    .shadow_maps
+                    .write_layers(&self.gpu.queue, &shadow_frame.layers);
+                shadow_frame.layers
+            }
+            None => Vec::new(),
+        };
+        let mut draws = self.upload_meshes(meshes);
+        self.prepare_pipelines(&mut draws);
+        let debug_lines = self.debug_lines(id);
+
+        let mut encoder = self
+            .gpu
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let output = self.outputs[id.0]
+            .as_mut()
+            .expect("the output was checked above");
+        let mut transients = std::mem::take(&mut output.transients);
+        let output = self.outputs[id.0]
+            .as_ref()
+            .expect("the output was checked above");
+        let mut pending = None;
+        let frame_texture = output.frame_texture(&frame);
+        let graph = self.frame_graph(
+            output,
+            &frame_views,
+            &draws,
+            &shadow_layers,
+            debug_lines,
+            frame_texture,
+            &mut pending,
+        );
+        if let Err(e) =
+            graph.execute(&self.gpu.device, &mut encoder, &mut transients, size)
+        {
+            // only render targets sampling each other in a cycle get here
+            log::error!("Could not render the frame: {e:#}");
+        }
+
+        self.gpu.queue.submit(Some(encoder.finish()));
+        let output = self.outputs[id.0]
+            .as_mut()
+            .expect("the output was checked above");
+        output.transients = transients;
+        if let Some(pending) = pending {
+            output.capture_requested = false;
+            output.capture =
+                Some(pending.and_then(|pending| pending.read(&self.gpu.device)));
+        }
+        if let Some(frame) = frame {
+            frame.present();
+        }
+
+        Ok(())
+    }
+
+    /// Writes the camera uniform of every view that covers any pixels,
+    /// adding camera bindings when there are more views than before. Views
+    /// into unknown render targets are skipped. `frame` is the size of the
+    /// output drawn to. Cameras get the aspect ratio of their viewport, so
+    /// they follow resizes of the window or render target.
+    fn prepare_views(&mut self, views: &[View], frame: (u32, u32)) -> Vec<FrameView> {
+        let gpu = &mut self.gpu;
+        let weighted_blended =
+            self.settings.transparency == TransparencyMode::WeightedBlended;
+        let mut prepared: Vec<FrameView> = Vec::with_capacity(views.len());
+        for (index, view) in views.iter().enumerate() {
+            let target = view.target.map(|id| id.0);
+            let (width, height) = match target {
+                Some(index) => match gpu.render_targets.get_mut(index) {
+                    Some(target) => {
+                        let (width, height) = target.target.size();
+                        if weighted_blended && target.oit.is_none() {
+                            target.oit = Some(OitTargets::new(
+                                &gpu.device,
+                                &gpu.bind_groups.oit_layout,
+                                width,
+                                height,
+                                target.target.sample_count(),
+                            ));
+                        }
+                        (width, height)
+                    }
+                    None => continue,
+                },
+                None => frame,
+            };
+            let Some(rect) = view.viewport.pixels(width, height) else {
+                continue;
+            };
+            let aspect_ratio = if view.fit_aspect_ratio {
+                rect.width as f32 / rect.height as f32
+            } else {
+                view.camera.aspect_ratio()
+            };
+            let camera = prepared.len();
+            if camera == gpu.bind_groups.cameras.len() {
+                let binding =
+                    CameraBinding::new(&gpu.device, &gpu.bind_groups.camera_layout);
+                gpu.bind_groups.cameras.push(binding);
+            }
+            gpu.queue.write_buffer(
+                &gpu.bind_groups.cameras[camera].buffer,
+                0,
+                bytemuck::cast_slice(&[CameraUniform::new(view.camera, aspect_ratio)]),
+            );
+            prepared.push(FrameView {
+                view: index,
+                camera,
+                aspect_ratio,
+                target,
+                first: prepared.iter().all(|other| other.target != target),
+                rect,
+                eye: view.camera.position(),
+                clear_color: view.clear_color,
+                clear_depth: view.clear_depth,
+            });
+        }
+        prepared
+    }
+
+    /// Declares the passes of one frame of `output`. `capture` receives the
+    /// copy of the frame when one was requested.
+    #[allow(clippy::too_many_arguments)]
+    fn frame_graph<'g>(
+        &'g self,
+        output: &'g OutputState,
+        views: &'g [FrameView],
+        draws: &'g [MeshDraw],
+        shadow_layers: &[(usize, glam::Mat4)],
+        debug_lines: Option<DebugLines>,
+        frame_texture: &'g wgpu::Texture,
+        capture: &'g mut Option<anyhow::Result<PendingCapture>>,
+    ) -> RenderGraph<'g> {
+        let sample_count = self.settings.sample_count;
+        let mut graph = RenderGraph::new();
+        let depth = graph.create_texture(
+            TextureDesc::new("depth_texture", texture::DEPTH_FORMAT)
+                .with_sample_count(sample_count),
+        );
+        // resolved into the scene target
+        let msaa = (sample_count > 1).then(|| {
+            graph.create_texture(
+                TextureDesc::new("msaa_color", post::HDR_FORMAT)
+                    .with_sample_count(sample_count),
+            )
+        });
+        let shadow_maps = graph.import("shadow_maps");
+        let scene = graph.import("scene_target");
+        let oit = graph.import("oit_targets");
+        let frame = graph.import("frame");
+        // a render target's color, depth and transparency targets
+        let targets: Vec<_> = self
+            .gpu
+            .render_targets
+            .iter()
+            .map(|_| graph.import("render_target"))
+            .collect();
+        let frame_resources = FrameResources { depth, msaa };
+
+        for &(layer, _) in shadow_layers {
+            graph.add_pass(
+                PassDesc::new("Shadow Pass").write(shadow_maps),
+                move |ctx| self.draw_shadow_layer(ctx.encoder, layer, draws),
+            );
+        }
+
+        // every view loads the depth and color of the views before it, so
+        // the passes write them instead of only reading. Sampled render
+        // targets are read, so the views drawing into them run first.
+        let pass = |name, view: &FrameView| {
+            let mut pass = PassDesc::new(name);
+            for index in view.sampled_targets(draws) {
+                pass = pass.read(targets[index]);
+            }
+            match (view.target, msaa) {
+                (Some(index), _) => pass.write(targets[index]),
+                (None, Some(msaa)) => pass.write(scene).write(depth).write(msaa),
+                (None, None) => pass.write(scene).write(depth),
+            }
+        };
+        let weighted_blended = draws
+            .iter()
+            .any(|draw| draw.phase == Phase::WeightedBlended);
+        for view in views {
+            graph.add_pass(pass("Render Pass", view).read(shadow_maps), move |ctx| {
+                let attachments =
+                    self.attachments(output, view, frame_resources, ctx.textures);
+                self.draw_scene(ctx.encoder, &attachments, view, draws);
+            });
+
+            if weighted_blended {
+                let transparency = match view.target {
+                    Some(_) => pass("Transparency Pass", view),
+                    None => pass("Transparency Pass", view).write(oit),
+                };
+                graph.add_pass(transparency, move |ctx| {
+                    let attachments =
+                        self.attachments(output, view, frame_resources, ctx.textures);
+                    self.accumulate_weighted_blended(
+                        ctx.encoder,
+                        &attachments,
+                        view,
+                        draws,
+                    );
+                    self.composite_weighted_blended(ctx.encoder, &attachments, view);
+                });
+            }
+
+            if let (Some(lines), None) = (debug_lines, view.target) {
+                graph.add_pass(pass("Debug Pass", view), move |ctx| {
+                    let attachments =
+                        self.attachments(output, view, frame_resources, ctx.textures);
+                    self.draw_debug(ctx.encoder, &attachments, view, lines);
+                });
+            }
+        }
+        if views.iter().all(|view| view.target.is_some()) {
+            let clear = PassDesc::new("Clear Pass").write(scene).write(depth);
+            let clear = match msaa {
+                Some(msaa) => clear.write(msaa),
+                None => clear,
+            };
+            graph.add_pass(clear, move |ctx| {
+                let attachments =
+                    self.frame_attachments(output, frame_resources, ctx.textures);
+                self.scene_pass(ctx.encoder, "Clear Pass", &attachments, true);
+            });
+        }
+
+        graph.add_pass(
+            PassDesc::new("Post Process").read(scene).write(frame),
+            move |ctx| {
+                let view =
+                    frame_texture.create_view(&wgpu::TextureViewDescriptor::default());
+                output.post.run(
+                    &self.gpu.queue,
+                    ctx.encoder,
+                    &self.settings.post,
+                    &view,
+                    output.config.format.is_srgb(),
+                );
+            },
+        );
+
+        if output.capture_requested {
+            graph.add_pass(
+                PassDesc::new("Capture").read(frame).side_effect(),
+                move |ctx| {
+                    *capture = Some(PendingCapture::copy(
+                        &self.gpu.device,
+                        ctx.encoder,
+                        frame_texture,
+                    ));
+                },
+            );
+        }
+
+        graph
+    }
+
+    /// What a view draws into: the scene target and the frame graph's depth
+    /// and multisampled color, or the textures of its render target
+    fn attachments<'t>(
+        &'t self,
+        output: &'t OutputState,
+        view: &FrameView,
+        frame: FrameResources,
+        textures: &'t PassTextures,
+    ) -> Attachments<'t> {
+        let Some(index) = view.target else {
+            return self.frame_attachments(output, frame, textures);
+        };
+        let target = &self.gpu.render_targets[index];
+        let (color, resolve_target) = target.target.color_views();
+        Attachments {
+            color,
+            resolve_target,
+            depth: &target.target.depth().view,
+            oit: target.oit.as_ref(),
+        }
+    }
+
+    fn frame_attachments<'t>(
+        &'t self,
+        output: &'t OutputState,
+        frame: FrameResources,
+        textures: &'t PassTextures,
+    ) -> Attachments<'t> {
+        let scene = output.post.scene_target();
+        let (color, resolve_target) = match frame.msaa {
+            Some(msaa) => (textures.view(msaa), Some(scene)),
+            None => (scene, None),
+        };
+        Attachments {
+            color,
+            resolve_target,
+            depth: textures.view(frame.depth),
+            oit: Some(&output.textures.oit),
+        }
+    }
+
+    /// Starts a pass drawing into the color and depth of `attachments`,
+    /// which are cleared when `clear` is set and kept otherwise
+    fn scene_pass<'p>(
+        &'p self,
+        encoder: &'p mut wgpu::CommandEncoder,
+        label: &str,
+        attachments: &Attachments<'p>,
+        clear: bool,
+    ) -> wgpu::RenderPass<'p> {
+        let (color_load, depth_load) = if clear {
+            (
+                wgpu::LoadOp::Clear(self.settings.clear_color),
+                wgpu::LoadOp::Clear(1.0),
+            )
+        } else {
+            (wgpu::LoadOp::Load, wgpu::LoadOp::Load)
+        };
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(label),
+            color_attachments: &[Some(attachments.color_attachment(color_load))],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: attachments.depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: depth_load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        })
+    }
+
+    /// Draws the opaque meshes, the sky, and the sorted and additive
+    /// blended meshes of a view. The first view into the frame or a render
+    /// target clears all of it.
+    fn draw_scene(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        attachments: &Attachments,
+        view: &FrameView,
+        draws: &[MeshDraw],
+    ) {
+        let mut render_pass =
+            self.scene_pass(encoder, "Render Pass", attachments, view.first);
+        view.rect.apply(&mut render_pass);
+        self.gpu.pipelines.clear.draw(
+            &mut render_pass,
+            view.clear_color,
+            view.clear_depth && !view.first,
+        );
+
+        let camera = &self.gpu.bind_groups.cameras[view.camera];
+        render_pass.set_bind_group(0, &camera.bind_group, &[]);
+        render_pass.set_bind_group(1, &self.gpu.bind_groups.lights_bind_group, &[]);
+        render_pass.set_bind_group(
+            3,
+            &self.gpu.bind_groups.environment_bind_group,
+            &[],
+        );
+        render_pass.set_vertex_buffer(0, self.gpu.buffers.vertex.slice(..));
+        render_pass.set_index_buffer(
+            self.gpu.buffers.index.slice(..),
+            wgpu::IndexFormat::Uint16,
+        );
+        let phase = |phase| {
+            draws
+                .iter()
+                .filter(move |draw| draw.phase == phase && view.draws(draw))
+        };
+        let mut opaque: Vec<_> = phase(Phase::Opaque).collect();
+        opaque.sort_by_key(|draw| (draw.pipeline, draw.material));
+        self.draw_batched(&mut render_pass, &opaque);
+
+        if self.settings.skybox {
+            render_pass.set_pipeline(&self.gpu.pipelines.skybox);
+            render_pass.set_bind_group(1, &self.gpu.bind_groups.skybox_bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+            render_pass.set_bind_group(1, &self.gpu.bind_groups.lights_bind_group, &[]);
+        }
+
+        // only present in `TransparencyMode::Sorted`
+        let mut blended: Vec<_> = phase(Phase::Alpha).collect();
+        let eye = view.eye;
+        blended.sort_by(|a, b| {
+            b.center
+                .distance_squared(eye)
+                .total_cmp(&a.center.distance_squared(eye))
+        });
+        self.draw_batched(&mut render_pass, &blended);
+
+        let mut additive: Vec<_> = phase(Phase::Additive).collect();
+        additive.sort_by_key(|draw| (draw.pipeline, draw.material));
+        self.draw_batched(&mut render_pass, &additive);
+    }
+
+    /// Draws meshes in the given order, switching pipelines and materials
+    /// only when they change. Expects the camera, lights and environment bind
+    /// groups and the mesh buffers to be set.
+    fn draw_batched<'p>(
+        &'p self,
+        render_pass: &mut wgpu::RenderPass<'p>,
+        draws: &[&'p MeshDraw],
+    ) {
+        let (mut pipeline, mut material) = (None, None);
+        for draw in draws {
+            let Some(index) = draw.pipeline else {
+                continue;
+            };
+            if pipeline != Some(index) {
+                render_pass.set_pipeline(self.gpu.material_pipelines.get(index));
+                pipeline = Some(index);
+            }
+            if material != Some(draw.material) {
+                render_pass.set_bind_group(2, &self.gpu_material(draw).bind_group, &[]);
+                material = Some(draw.material);
+            }
+            render_pass.draw_indexed(draw.indices.clone(), draw.base_vertex, 0..1);
+        }
+    }
+
+    fn gpu_material(&self, draw: &MeshDraw) -> &GpuMaterial {
+        draw.material
+            .and_then(|index| self.gpu.materials.get(index))
+            .unwrap_or(&self.gpu.default_material)
+    }
+
+    /// Accumulates the weighted blended meshes of a view in any order
+    fn accumulate_weighted_blended(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        attachments: &Attachments,
+        view: &FrameView,
+        draws: &[MeshDraw],
+    ) {
+        let transparent: Vec<_> = draws
+            .iter()
+            .filter(|draw| draw.phase == Phase::WeightedBlended && view.draws(draw))
+            .collect();
+        let oit = attachments.oit();
+        oit.clear_revealage(encoder);
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Transparency Pass"),
+            color_attachments: &oit.color_attachments(),
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: attachments.depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        view.rect.apply(&mut render_pass);
+        let camera = &self.gpu.bind_groups.cameras[view.camera];
+        render_pass.set_bind_group(0, &camera.bind_group, &[]);
+        render_pass.set_bind_group(1, &self.gpu.bind_groups.lights_bind_group, &[]);
+        render_pass.set_bind_group(
+            3,
+            &self.gpu.bind_groups.environment_bind_group,
+            &[],
+        );
+        render_pass.set_vertex_buffer(0, self.gpu.buffers.vertex.slice(..));
+        render_pass.set_index_buffer(
+            self.gpu.buffers.index.slice(..),
+            wgpu::IndexFormat::Uint16,
+        );
+        self.draw_batched(&mut render_pass, &transparent);
+    }
+
+    /// Blends the weighted average of the transparent meshes over the
+    /// viewport of a view
+    fn composite_weighted_blended(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        attachments: &Attachments,
+        view: &FrameView,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Transparency Composite Pass"),
+            color_attachments: &[Some(
+                attachments.color_attachment(wgpu::LoadOp::Load),
+            )],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        view.rect.apply(&mut render_pass);
+        render_pass.set_pipeline(&self.gpu.pipelines.oit_composite);
+        render_pass.set_bind_group(0, &attachments.oit().bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+
+    /// Renders every mesh into one layer of the shadow map array
+    fn draw_shadow_layer(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        layer: usize,
+        draws: &[MeshDraw],
+    ) {
+        let shadow_maps = &self.gpu.shadow_maps;
+        let mut shadow_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Shadow Pass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &shadow_maps.layer_views[layer],
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        let offset = layer as wgpu::BufferAddress * shadow_maps.uniform_stride;
+        shadow_pass.set_pipeline(&shadow_maps.pipeline);
+        shadow_pass.set_bind_group(0, &shadow_maps.pass_bind_group, &[offset as u32]);
+        shadow_pass.set_vertex_buffer(0, self.gpu.buffers.vertex.slice(..));
+        shadow_pass.set_index_buffer(
+            self.gpu.buffers.index.slice(..),
+            wgpu::IndexFormat::Uint16,
+        );
+        for draw in draws.iter().filter(|draw| draw.casts_shadow) {
+            shadow_pass.draw_indexed(draw.indices.clone(), draw.base_vertex, 0..1);
+        }
+    }
+
+    /// Packs every mesh into the shared vertex and index buffers, growing
+    /// them when needed
+    fn upload_meshes(&mut self, meshes: &[Mesh]) -> Vec<MeshDraw> {
+        let mut vertices = Vec::new();
+        let mut indices: Vec<u16> = Vec::new();
+        let mut draws = Vec::with_capacity(meshes.len());
+        for mesh in meshes {
+            let start = indices.len() as u32;
+            let base_vertex = vertices.len();
+            vertices.extend(mesh.vertices_transformed());
+            let transformed = &vertices[base_vertex..];
+            let (min, max) = transformed.iter().fold(
+                (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+                |(min, max), vertex| {
+                    let position = Vec3::from_slice(&vertex.position);
+                    (min.min(position), max.max(position))
+                },
+            );
+            let material = mesh
+                .material
+                .map(|id| id.0)
+                .filter(|&index| index < self.gpu.materials.len());
+            let gpu_material = match material {
+                Some(index) => &self.gpu.materials[index],
+                None => &self.gpu.default_material,
+            };
+            let material_state = &gpu_material.material;
+            let blend = match material_state.state.blend {
+                BlendMode::Auto => {
+                    let translucent = material_state.params.base_color.w < 1.0
+                        || material_state.uses_vertex_colors()
+                            && transformed.iter().any(|vertex| vertex.color[3] < 1.0);
+                    if translucent {
+                        BlendMode::Alpha
+                    } else {
+                        BlendMode::Opaque
+                    }
+                }
+                blend => blend,
+            };
+            let phase = match blend {
+                BlendMode::Auto | BlendMode::Opaque => Phase::Opaque,
+                BlendMode::Alpha => match self.settings.transparency {
+                    TransparencyMode::Sorted => Phase::Alpha,
+                    TransparencyMode::WeightedBlended => Phase::WeightedBlended,
+                },
+                BlendMode::Additive => Phase::Additive,
+            };
+            draws.push(MeshDraw {
+                indices: start..start + mesh.indices.len() as u32,
+                base_vertex: base_vertex as i32,
+                material,
+                phase,
+                pipeline: None,
+                casts_shadow: material_state.state.topology
+                    == wgpu::PrimitiveTopology::TriangleList,
+                sampled_targets: self.sampled_targets(&material_state.params),
+                center: (min + max) * 0.5,
+            });
+            indices.extend_from_slice(&mesh.indices);
+        }
+        // buffer writes must be a multiple of four bytes
+        if indices.len() % 2 == 1 {
+            indices.push(0);
+        }
+
+        let gpu = &mut self.gpu;
+        write_buffer_growing(
+            &gpu.device,
+            &gpu.queue,
+            &mut gpu.buffers.vertex,
+            "Vertex Buffer",
+            wgpu::BufferUsages::VERTEX,
+            bytemuck::cast_slice(&vertices),
+        );
+        write_buffer_growing(
+            &gpu.device,
+            &gpu.queue,
+            &mut gpu.buffers.index,
+            "Index Buffer",
+            wgpu::BufferUsages::INDEX,
+            bytemuck::cast_slice(&indices),
+        );
+
+        draws
+    }
+
+    /// Indices of the render targets whose color `params` samples
+    fn sampled_targets(&self, params: &PbrMaterial) -> Vec<usize> {
+        let textures = params.textures();
+        let samples = |target: &GpuRenderTarget| {
+            textures.iter().any(|texture| {
+                texture
+                    .as_ref()
+                    .is_some_and(|texture| Arc::ptr_eq(texture, target.target.color()))
+            })
+        };
+        self.gpu
+            .render_targets
+            .iter()
+            .enumerate()
+            .filter(|(_, target)| samples(target))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Looks up the pipeline of every draw, building the ones not used
+    /// before. Draws whose pipeline fails to build are skipped.
+    fn prepare_pipelines(&mut self, draws: &mut [MeshDraw]) {
+        let gpu = &mut self.gpu;
+        let mut context = PipelineContext {
+            device: &gpu.device,
+            layout: &gpu.pipeline_layouts.mesh,
+            shaders: &mut gpu.shaders.cache,
+            main_shader: &gpu.shaders.main,
+            format: post::HDR_FORMAT,
+            sample_count: self.settings.sample_count,
+        };
+        for draw in draws {
+            let material = match draw.material {
+                Some(index) => &gpu.materials[index].material,
+                None => &gpu.default_material.material,
+            };
+            let key = PipelineKey::new(material, draw.phase);
+            draw.pipeline = gpu.material_pipelines.get_or_create(&key, &mut context);
+        }
+    }
+
+    /// The debug shapes output `id` draws. The `debug_draw` queue is drained
+    /// once per frame, which ends when an output renders again, so every
+    /// output of a frame draws the same shapes.
+    fn debug_lines(&mut self, id: OutputId) -> Option<DebugLines> {
+        let outputs = &self.debug_frame.outputs;
+        if outputs.is_empty() || outputs.contains(&id) {
+            self.debug_frame = DebugFrame {
+                lines: self.upload_debug_lines(),
+                outputs: Vec::new(),
+            };
+        }
+        self.debug_frame.outputs.push(id);
+        self.debug_frame.lines
+    }
+
+    /// Uploads the shapes queued through `debug_draw`, `None` when there
+    /// are none
+    fn upload_debug_lines(&mut self) -> Option<DebugLines> {
+        let debug_draw::DebugVertices {
+            depth_tested,
+            overlay,
+        } = debug_draw::take_vertices(time::delta_time());
+        if depth_tested.is_empty() && overlay.is_empty() {
+            return None;
+        }
+
+        let gpu = &mut self.gpu;
+        write_buffer_growing(
+            &gpu.device,
+            &gpu.queue,
+            &mut gpu.buffers.debug_vertex,
+            "Debug Vertex Buffer",
+            wgpu::BufferUsages::VERTEX,
+            bytemuck::cast_slice(
+                &[depth_tested.as_slice(), overlay.as_slice()].concat(),
+            ),
+        );
+        Some(DebugLines {
+            depth_tested: depth_tested.len() as u32,
+            vertex_count: (depth_tested.len() + overlay.len()) as u32,
+        })
+    }
+
+    /// Draws the uploaded debug shapes on top of a view
+    fn draw_debug(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        attachments: &Attachments,
+        view: &FrameView,
+        lines: DebugLines,
+    ) {
+        let mut render_pass =
+            self.scene_pass(encoder, "Debug Pass", attachments, false);
+        view.rect.apply(&mut render_pass);
+
+        let split = lines.depth_tested;
+        let camera = &self.gpu.bind_groups.cameras[view.camera];
+        render_pass.set_bind_group(0, &camera.bind_group, &[]);
+        // unused by the shader, but part of the pipeline layout
+        render_pass.set_bind_group(1, &self.gpu.bind_groups.lights_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.gpu.buffers.debug_vertex.slice(..));
+        if split > 0 {
+            render_pass.set_pipeline(&self.gpu.pipelines.debug);
+            render_pass.draw(0..split, 0..1);
+        }
+        if split < lines.vertex_count {
+            render_pass.set_pipeline(&self.gpu.pipelines.debug_overlay);
+            render_pass.draw(split..lines.vertex_count, 0..1);
+        }
+    }
+}
+
+impl Drop for Render {
+    fn drop(&mut self) {
+        // some backends report dropping the device as an unknown loss
+        self.gpu.device.set_device_lost_callback(|_, _| {});
+    }
+}
+
+fn watch_device_loss(device: &wgpu::Device, device_lost: &Arc<AtomicBool>) {
+    let device_lost = device_lost.clone();
+    device.set_device_lost_callback(move |reason, message| {
+        // wgpu also calls this when the device is dropped or the callback is
+        // replaced, neither of which needs a rebuild
+        if matches!(
+            reason,
+            wgpu::DeviceLostReason::Dropped | wgpu::DeviceLostReason::ReplacedCallback
+        ) {
+            return;
+        }
+        log::error!("GPU device lost ({reason:?}): {message}");
+        device_lost.store(true, Ordering::SeqCst);
+    });
+}
+
+fn create_lights_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    lights: &wgpu::Buffer,
+    shadow_maps: &ShadowMaps,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("lights_bind_group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: lights.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&shadow_maps.texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Sampler(&shadow_maps.texture.sampler),
+            },
+        ],
+    })
+}
+
+fn create_size_dependent_textures(
+    device: &wgpu::Device,
+    config: &wgpu::SurfaceConfiguration,
+    oit_layout: &wgpu::BindGroupLayout,
+    sample_count: u32,
+) -> RenderTextures {
+    RenderTextures {
+        oit: OitTargets::new(
+            device,
+            oit_layout,
+            config.width,
+            config.height,
+            sample_count,
+        ),
+    }
+}
+
+const INITIAL_BUFFER_SIZE: wgpu::BufferAddress = 1 << 16;
+
+/// Vertex counts of the debug shapes in the debug vertex buffer
+#[derive(Clone, Copy)]
+struct DebugLines {
+    /// Depth tested lines come first, overlay lines after them
+    depth_tested: u32,
+    vertex_count: u32,
+}
+
+/// The debug shapes uploaded for the current frame
+#[derive(Default)]
+struct DebugFrame {
+    lines: Option<DebugLines>,
+    /// Outputs that rendered this frame
+    outputs: Vec<OutputId>,
+}
+
+/// A view resolved for the current frame
+#[derive(Clone, Copy)]
+struct FrameView {
+    /// Index into the views passed to `render_views`
+    view: usize,
+    /// Index into `BindGroups::cameras`
+    camera: usize,
+    /// The camera is drawn with
+    aspect_ratio: f32,
+    /// Index into `GpuResources::render_targets`, `None` for the frame
+    target: Option<usize>,
+    /// Whether this is the first view into its frame or render target,
+    /// which clears it
+    first: bool,
+    rect: PixelRect,
+    /// Camera position, for sorting transparent meshes
+    eye: Vec3,
+    clear_color: Option<wgpu::Color>,
+    clear_depth: bool,
+}
+
+impl FrameView {
+    /// Whether the view draws a mesh, which it does not when the mesh
+    /// samples the render target the view draws into
+    fn draws(&self, draw: &MeshDraw) -> bool {
+        self.target
+            .is_none_or(|target| !draw.sampled_targets.contains(&target))
+    }
+
+    /// Render targets sampled by the meshes the view draws
+    fn sampled_targets(&self, draws: &[MeshDraw]) -> Vec<usize> {
+        let mut targets: Vec<usize> = draws
+            .iter()
+            .filter(|draw| self.draws(draw))
+            .flat_map(|draw| draw.sampled_targets.iter().copied())
+            .collect();
+        targets.sort_unstable();
+        targets.dedup();
+        targets
+    }
+}
+
+/// Frame graph textures of the views drawing into the frame
+#[derive(Clone, Copy)]
+struct FrameResources {
+    depth: ResourceId,
+    /// Resolved into the scene target
+    msaa: Option<ResourceId>,
+}
+
+/// The textures a view draws into
+struct Attachments<'t> {
+    color: &'t wgpu::TextureView,
+    resolve_target: Option<&'t wgpu::TextureView>,
+    depth: &'t wgpu::TextureView,
+    /// `None` for render targets while weighted blended transparency is off
+    oit: Option<&'t OitTargets>,
+}
+
+impl<'t> Attachments<'t> {
+    fn color_attachment(
+        &self,
+        load: wgpu::LoadOp<wgpu::Color>,
+    ) -> wgpu::RenderPassColorAttachment<'t> {
+        wgpu::RenderPassColorAttachment {
+            view: self.color,
+            resolve_target: self.resolve_target,
+            ops: wgpu::Operations {
+                load,
+                store: wgpu::StoreOp::Store,
+            },
+        }
+    }
+
+    fn oit(&self) -> &'t OitTargets {
+        self.oit
+            .expect("weighted blended transparency targets are allocated")
+    }
+}
+
+/// Index range and base vertex of one mesh inside the shared buffers
+struct MeshDraw {
+    indices: std::ops::Range<u32>,
+    base_vertex: i32,
+    /// Index into `GpuResources::materials`, `None` for the default material
+    material: Option<usize>,
+    phase: Phase,
+    /// Index into `GpuResources::material_pipelines`, `None` when it could
+    /// not be built
+    pipeline: Option<usize>,
+    /// Whether the shadow pipeline, which draws triangle lists, can draw it
+    casts_shadow: bool,
+    /// Indices into `GpuResources::render_targets` of the targets its
+    /// material samples
+    sampled_targets: Vec<usize>,
+    /// World space center of the bounds, for sorting transparent meshes
+    center: Vec3,
+}
+
+fn create_buffer(
+    device: &wgpu::Device,
+    label: &str,
+    size: wgpu::BufferAddress,
+    usage: wgpu::BufferUsages,
+) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        size,
+        usage: usage | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// Writes `contents` to the start of `buffer`, replacing it with a larger one
+/// first if it does not fit
+fn write_buffer_growing(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    buffer: &mut wgpu::Buffer,
+    label: &str,
+    usage: wgpu::BufferUsages,
+    contents: &[u8],
+) {
+    let needed = contents.len() as wgpu::BufferAddress;
+    if needed > buffer.size() {
+        *buffer = create_buffer(device, label, needed.next_power_of_two(), usage);
+    }
+    if !contents.is_empty() {
+        queue.write_buffer(buffer, 0, contents);
+    }
+}
+
+/// The preprocessor for the mesh shaders and the skybox, with the files from
+/// `shader_source` or the watched directory in place of the built in ones.
+/// Fails when the watched shader no longer exists.
+fn main_shader_cache(
+    settings: &RenderSettings,
+    custom_shaders: &[(String, Cow<'static, str>)],
+) -> anyhow::Result<(ShaderCache, String)> {
+    let mut preprocessor = shader::builtin_preprocessor()
+        .with_source(shader::MAIN_SHADER, settings.shader_source.clone());
+    for (name, source) in custom_shaders {
+        preprocessor.add_source(name.clone(), source.clone());
+    }
+    match &settings.shader_path {
+        Some(path) => {
+            let watcher = ShaderWatcher::new(path)?;
+            let main = path.file_name().map_or_else(
+                || shader::MAIN_SHADER.to_owned(),
+                |name| name.to_string_lossy().into_owned(),
+            );
+            Ok((ShaderCache::new(preprocessor.with_dir(watcher.dir())), main))
+        }
+        None => Ok((
+            ShaderCache::new(preprocessor),
+            shader::MAIN_SHADER.to_owned(),
+        )),
+    }
+}
+
+/// Compiles the pipelines that do not depend on a material
+fn create_pipelines(
+    device: &wgpu::Device,
+    layouts: &PipelineLayouts,
+    shaders: &mut Shaders,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+) -> anyhow::Result<Pipelines> {
+    let multisample = wgpu::MultisampleState {
+        count: sample_count,
+        ..Default::default()
+    };
+    let skybox_shader = shaders
+        .cache
+        .get(device, "skybox.wgsl", &ShaderDefs::new())?;
+    let oit_composite = transparency::create_composite_pipeline(
+        device,
+        &layouts.oit_composite,
+        &shaders.oit,
+        format,
+        multisample,
+    );
+    let skybox = skybox::create_pipeline(
+        device,
+        &layouts.skybox,
+        &skybox_shader,
+        format,
+        multisample,
+    );
+    let debug = create_debug_pipeline(
+        device,
+        &layouts.main,
+        &shaders.debug,
+        format,
+        multisample,
+        true,
+    );
+    let debug_overlay = create_debug_pipeline(
+        device,
+        &layouts.main,
+        &shaders.debug,
+        format,
+        multisample,
+        false,
+    );
+
+    Ok(Pipelines {
+        oit_composite,
+        skybox,
+        debug,
+        debug_overlay,
+        clear: ClearPipelines::new(device, format, multisample),
+    })
+}
+
+fn create_debug_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+    multisample: wgpu::MultisampleState,
+    depth_test: bool,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(if depth_test {
+            "debug_pipeline"
+        } else {
+            "debug_overlay_pipeline"
+        }),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_line",
+            buffers: &[Vertex::desc()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_line",
+            targets: &[Some(format.into())],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::LineList,
+            ..Default::default()
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: texture::DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare: if depth_test {
+                wgpu::CompareFunction::LessEqual
+            } else {
+                wgpu::CompareFunction::Always
+            },
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample,
+        multiview: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A headless renderer on the software adapter, `None` without one
+    fn headless() -> Option<Render> {
+        let builder = Render::headless(16, 16).force_fallback_adapter(true);
+        match pollster::block_on(builder.build()) {
+            Ok(render) => Some(render),
+            Err(e) if e.is::<NoAdapter>() => {
+                eprintln!("{e:#}, skipping");
+                None
+            }
+            Err(e) => panic!("Could not build the renderer: {e:#}"),
+        }
+    }
+
+    /// Asks for the pipeline of `material`, returning its key and whether it
+    /// was built
+    fn pipeline(render: &mut Render, material: &Material) -> (PipelineKey, bool) {
+        let key = PipelineKey::new(material, Phase::Opaque);
+        let gpu = &mut render.gpu;
+        let index = gpu.material_pipelines.get_or_create(
+            &key,
+            &mut PipelineContext {
+                device: &gpu.device,
+                layout: &gpu.pipeline_layouts.mesh,
+                shaders: &mut gpu.shaders.cache,
+                main_shader: &gpu.shaders.main,
+                format: post::HDR_FORMAT,
+                sample_count: render.settings.sample_count,
+            },
+        );
+        (key, index.is_some())
+    }
+
+    #[test]
+    fn pipelines_that_never_built_do_not_block_reloads() {
+        let Some(mut render) = headless() else {
+            return;
+        };
+        let (lit, built) = pipeline(&mut render, &Material::lit(Vec4::ONE));
+        assert!(built);
+        // the device was created without `POLYGON_MODE_LINE`
+        let mut wireframe = Material::lit(Vec4::ONE);
+        wireframe.state.polygon_mode = wgpu::PolygonMode::Line;
+        let (wireframe, built) = pipeline(&mut render, &wireframe);
+        assert!(!built);
+
+        render.rebuild_shaders().unwrap();
+        let pipelines = &render.gpu.material_pipelines;
+        assert!(pipelines.is_built(&lit));
+        assert!(!pipelines.is_built(&wireframe));
+        assert_eq!(pipelines.len(), 2);
+    }
+
+    #[test]
+    fn removed_shaders_drop_their_pipelines() {
+        let Some(mut render) = headless() else {
+            return;
+        };
+        render
+            .add_shader(
+                "custom.wgsl",
+                "#include \"mesh.wgsl\"\n\
+                 fn shade(in: VertexOutput) -> vec4<f32> {\n\
+                 \x20   return vec4<f32>(in.normal * 0.5 + 0.5, 1.0);\n\
+                 }\n\
+                 #include \"fragment.wgsl\"\n",
+            )
+            .unwrap();
+        let custom = Material::custom("custom.wgsl", PbrMaterial::default());
+        let (custom, built) = pipeline(&mut render, &custom);
+        assert!(built);
+        let (lit, built) = pipeline(&mut render, &Material::lit(Vec4::ONE));
+        assert!(built);
+
+        assert!(render.remove_shader("custom.wgsl"));
+        let pipelines = &render.gpu.material_pipelines;
+        assert!(!pipelines.is_built(&custom));
+        assert!(pipelines.is_built(&lit));
+        assert_eq!(pipelines.len(), 1);
+        assert!(!render.remove_shader("custom.wgsl"));
+        // a removed shader can not be compiled again
+        let (_, built) = pipeline(
+            &mut render,
+            &Material::custom("custom.wgsl", PbrMaterial::default()),
+        );
+        assert!(!built);
+    }
+
+    #[test]
+    fn every_output_draws_the_debug_lines() {
+        let Some(mut render) = headless() else {
+            return;
+        };
+        render.set_clear_color(wgpu::Color::BLACK);
+        render.set_skybox_enabled(false);
+        let config = render.primary().config.clone();
+        let texture = output::create_output_texture(&render.gpu.device, &config);
+        let second = OutputState::new(&render.gpu, Output::Texture(texture), config, 1);
+        render.outputs.push(Some(second));
+        let second = OutputId(1);
+        let camera = Camera::new(
+            60.0,
+            1.0,
+            crate::camera::Perspective,
+            crate::transform::Transform::from_translation(Vec3::new(0.0, 0.0, -3.0)),
+        );
+        let views = [View::new(&camera)];
+
+        let lit_pixels = |render: &mut Render, id: OutputId| {
+            render.outputs[id.0].as_mut().unwrap().capture_requested = true;
+            render.render_output(id, &views, &[]).unwrap();
+            let output = render.outputs[id.0].as_mut().unwrap();
+            let frame = output.capture.take().unwrap().unwrap();
+            frame
+                .pixels()
+                .filter(|pixel| pixel.0[..3] != [0; 3])
+                .count()
+        };
+
+        debug_draw::line(
+            Vec3::new(-2.0, 0.0, 0.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            debug_draw::DrawOptions::new(Vec4::ONE).overlay(),
+        );
+        assert!(lit_pixels(&mut render, OutputId::PRIMARY) > 0);
+        assert!(lit_pixels(&mut render, second) > 0);
+        // the next frame starts with the single frame line drained
+        assert_eq!(lit_pixels(&mut render, OutputId::PRIMARY), 0);
+        assert_eq!(lit_pixels(&mut render, second), 0);
+    }
+
+    #[test]
+    fn recovered_devices_keep_the_render_targets() {
+        let Some(mut render) = headless() else {
+            return;
+        };
+        let fixed = render.add_render_target(ResizePolicy::Fixed {
+            width: 8,
+            height: 4,
+        });
+        let scaled = render.add_render_target(ResizePolicy::Surface { scale: 0.5 });
+        render
+            .set_render_target_policy(
+                fixed,
+                ResizePolicy::Fixed {
+                    width: 4,
+                    height: 2,
+                },
+            )
+            .unwrap();
+
+        render.recover_device().unwrap();
+        assert_eq!(render.render_target(fixed).unwrap().size(), (4, 2));
+        assert_eq!(render.render_target(scaled).unwrap().size(), (8, 8));
+    }
+
+    #[test]
+    fn recovered_devices_keep_the_materials() {
+        let Some(mut render) = headless() else {
+            return;
+        };
+        let mirror = render.add_render_target(ResizePolicy::Surface { scale: 1.0 });
+        let mut params = PbrMaterial::new(Vec4::new(0.5, 0.25, 1.0, 1.0), 0.0, 0.5);
+        params.emissive_texture =
+            Some(render.render_target(mirror).unwrap().color().clone());
+        let gpu = &render.gpu;
+        params.normal_texture = Some(Arc::new(texture::create_solid_texture(
+            &gpu.device,
+            &gpu.queue,
+            [128, 128, 255, 255],
+            false,
+            "normal",
+        )));
+        let material = render.add_material(params);
+
+        render.recover_device().unwrap();
+        let params = &render.material(material).unwrap().params;
+        assert_eq!(params.base_color, Vec4::new(0.5, 0.25, 1.0, 1.0));
+        let color = render.render_target(mirror).unwrap().color();
+        assert!(Arc::ptr_eq(
+            params.emissive_texture.as_ref().unwrap(),
+            color
+        ));
+        // created by the application on the lost device
+        assert!(params.normal_texture.is_none());
+        let gpu_params = &render.gpu.materials[material.0].material.params;
+        assert!(Arc::ptr_eq(
+            gpu_params.emissive_texture.as_ref().unwrap(),
+            color
+        ));
+    }
+
+    #[test]
+    fn recovered_devices_keep_the_environment() {
+        let Some(mut render) = headless() else {
+            return;
+        };
+        let sky = GradientSky {
+            zenith: Vec3::Z,
+            ..Default::default()
+        };
+        let gpu = &render.gpu;
+        let environment = EnvironmentMap::from_gradient(&gpu.device, &gpu.queue, &sky);
+        render.set_environment(environment);
+
+        render.recover_device().unwrap();
+        match render.environment().source() {
+            EnvironmentSource::Gradient(recovered) => {
+                assert_eq!(recovered.zenith, Vec3::Z)
+            }
+            source => panic!("Unexpected environment {source:?}"),
+        }
+    }
 }
@@ -0,0 +1,415 @@
+use super::output::{self, Output};
+use super::{PresentSettings, Render};
+use crate::post::{self, PostChain};
+use crate::shader::ShaderWatcher;
+use crate::shadow::ShadowSettings;
+use crate::texture;
+use crate::transparency::TransparencyMode;
+use anyhow::Context;
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::sync::Arc;
+use winit::window::Window;
+
+/// Which kind of surface format `RenderBuilder` picks from the formats the
+/// surface supports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SurfaceFormatPreference {
+    Srgb,
+    Linear,
+    Exact(wgpu::TextureFormat),
+}
+
+/// The error `RenderBuilder::build` fails with when no adapter matches the
+/// settings, to tell a machine without a usable GPU apart from other errors
+/// with `anyhow::Error::is`
+#[derive(Debug)]
+pub struct NoAdapter {
+    message: String,
+}
+
+impl std::fmt::Display for NoAdapter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for NoAdapter {}
+
+/// Everything `Render` is created from, apart from the window or the size of
+/// a headless renderer
+#[derive(Clone, Debug)]
+pub struct RenderSettings {
+    pub backends: wgpu::Backends,
+    pub power_preference: wgpu::PowerPreference,
+    pub force_fallback_adapter: bool,
+    pub required_features: wgpu::Features,
+    /// Defaults to the WebGL2 downlevel limits at the adapter's resolution
+    pub required_limits: Option<wgpu::Limits>,
+    pub surface_format: SurfaceFormatPreference,
+    pub sample_count: u32,
+    pub present: PresentSettings,
+    /// Shown behind the scene when the skybox is disabled
+    pub clear_color: wgpu::Color,
+    /// Draw the environment cube map behind the scene
+    pub skybox: bool,
+    pub transparency: TransparencyMode,
+    pub shadows: ShadowSettings,
+    pub post: PostChain,
+    /// The mesh shader, preprocessed with `shader::Preprocessor`. It can
+    /// `#include` the built in `camera.wgsl`, `lights.wgsl` and `pbr.wgsl`.
+    pub shader_source: Cow<'static, str>,
+    /// Loads the main shader from this file instead of `shader_source`, with
+    /// includes from its directory, and rebuilds the pipelines whenever one
+    /// of them changes, for development
+    pub shader_path: Option<PathBuf>,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            backends: wgpu::Backends::all(),
+            power_preference: wgpu::PowerPreference::default(),
+            force_fallback_adapter: false,
+            required_features: wgpu::Features::empty(),
+            required_limits: None,
+            surface_format: SurfaceFormatPreference::Srgb,
+            sample_count: 1,
+            present: PresentSettings::default(),
+            clear_color: wgpu::Color::BLACK,
+            skybox: true,
+            transparency: TransparencyMode::default(),
+            shadows: ShadowSettings::default(),
+            post: PostChain::default(),
+            shader_source: Cow::Borrowed(include_str!("../shader.wgsl")),
+            shader_path: None,
+        }
+    }
+}
+
+pub struct RenderBuilder {
+    /// `None` renders into an offscreen texture of `size`
+    window: Option<Arc<Window>>,
+    size: winit::dpi::PhysicalSize<u32>,
+    settings: RenderSettings,
+}
+
+impl RenderBuilder {
+    pub fn new(window: Arc<Window>) -> Self {
+        Self::with_settings(window, RenderSettings::default())
+    }
+
+    pub fn with_settings(window: Arc<Window>, settings: RenderSettings) -> Self {
+        Self {
+            size: window.inner_size(),
+            window: Some(window),
+            settings,
+        }
+    }
+
+    /// Renders into an offscreen texture instead of a window. The surface
+    /// format preference picks between `Rgba8UnormSrgb` and `Rgba8Unorm`.
+    pub fn headless(width: u32, height: u32) -> Self {
+        Self {
+            window: None,
+            size: winit::dpi::PhysicalSize::new(width, height),
+            settings: RenderSettings::default(),
+        }
+    }
+
+    pub fn settings(mut self, settings: RenderSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    pub fn backends(mut self, backends: wgpu::Backends) -> Self {
+        self.settings.backends = backends;
+        self
+    }
+
+    pub fn power_preference(mut self, power_preference: wgpu::PowerPreference) -> Self {
+        self.settings.power_preference = power_preference;
+        self
+    }
+
+    pub fn force_fallback_adapter(mut self, force: bool) -> Self {
+        self.settings.force_fallback_adapter = force;
+        self
+    }
+
+    pub fn required_features(mut self, features: wgpu::Features) -> Self {
+        self.settings.required_features = features;
+        self
+    }
+
+    pub fn required_limits(mut self, limits: wgpu::Limits) -> Self {
+        self.settings.required_limits = Some(limits);
+        self
+    }
+
+    pub fn surface_format(mut self, preference: SurfaceFormatPreference) -> Self {
+        self.settings.surface_format = preference;
+        self
+    }
+
+    pub fn sample_count(mut self, sample_count: u32) -> Self {
+        self.settings.sample_count = sample_count;
+        self
+    }
+
+    pub fn present_mode(mut self, present_mode: wgpu::PresentMode) -> Self {
+        self.settings.present.present_mode = present_mode;
+        self
+    }
+
+    pub fn max_frames_in_flight(mut self, frames: u32) -> Self {
+        self.settings.present.max_frames_in_flight = frames;
+        self
+    }
+
+    pub fn frame_rate_cap(mut self, frames_per_second: Option<f32>) -> Self {
+        self.settings.present.frame_rate_cap = frames_per_second;
+        self
+    }
+
+    pub fn clear_color(mut self, color: wgpu::Color) -> Self {
+        self.settings.clear_color = color;
+        self
+    }
+
+    pub fn skybox(mut self, enabled: bool) -> Self {
+        self.settings.skybox = enabled;
+        self
+    }
+
+    pub fn transparency(mut self, mode: TransparencyMode) -> Self {
+        self.settings.transparency = mode;
+        self
+    }
+
+    pub fn shadows(mut self, shadows: ShadowSettings) -> Self {
+        self.settings.shadows = shadows;
+        self
+    }
+
+    pub fn post_chain(mut self, chain: PostChain) -> Self {
+        self.settings.post = chain;
+        self
+    }
+
+    pub fn shader_source(mut self, source: impl Into<Cow<'static, str>>) -> Self {
+        self.settings.shader_source = source.into();
+        self
+    }
+
+    /// Loads the main shader from `path`, and the files it includes from its
+    /// directory, and reloads them when a file changes. Shaders that fail to
+    /// compile are logged and the previous pipelines are kept. `build` fails
+    /// when `path` does not exist.
+    pub fn watch_shader(mut self, path: impl Into<PathBuf>) -> Self {
+        self.settings.shader_path = Some(path.into());
+        self
+    }
+
+    pub async fn build(self) -> anyhow::Result<Render> {
+        let Self {
+            window,
+            size,
+            settings,
+        } = self;
+
+        let shader_watcher = settings
+            .shader_path
+            .as_ref()
+            .map(ShaderWatcher::new)
+            .transpose()?;
+
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: settings.backends,
+            ..Default::default()
+        });
+        let (output, config, (adapter, device, queue)) = match window {
+            Some(window) => {
+                let surface = instance
+                    .create_surface(window.clone())
+                    .context("Could not create a surface for the window")?;
+                let parts =
+                    request_device(&instance, Some(&surface), &settings).await?;
+                let config = surface_config(&surface, &parts.0, size, &settings)?;
+                (Output::Window { surface, window }, config, parts)
+            }
+            None => {
+                if size.width == 0 || size.height == 0 {
+                    anyhow::bail!(
+                        "Headless renderers need a non-zero size, got {size:?}"
+                    );
+                }
+                let parts = request_device(&instance, None, &settings).await?;
+                let format = match settings.surface_format {
+                    SurfaceFormatPreference::Srgb => {
+                        wgpu::TextureFormat::Rgba8UnormSrgb
+                    }
+                    SurfaceFormatPreference::Linear => wgpu::TextureFormat::Rgba8Unorm,
+                    SurfaceFormatPreference::Exact(format) => format,
+                };
+                let config = wgpu::SurfaceConfiguration {
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                        | wgpu::TextureUsages::COPY_SRC,
+                    format,
+                    width: size.width,
+                    height: size.height,
+                    present_mode: settings.present.present_mode,
+                    desired_maximum_frame_latency: settings
+                        .present
+                        .max_frames_in_flight,
+                    alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+                    view_formats: Vec::new(),
+                };
+                let texture = output::create_output_texture(&parts.1, &config);
+                (Output::Texture(texture), config, parts)
+            }
+        };
+
+        settings.shadows.validate(&device.limits())?;
+
+        let format_features = adapter.get_texture_format_features(post::HDR_FORMAT);
+        let depth_features = adapter.get_texture_format_features(texture::DEPTH_FORMAT);
+        if !format_features
+            .flags
+            .sample_count_supported(settings.sample_count)
+            || !depth_features
+                .flags
+                .sample_count_supported(settings.sample_count)
+        {
+            anyhow::bail!(
+                "MSAA sample count {} is not supported for the HDR format {:?}",
+                settings.sample_count,
+                post::HDR_FORMAT
+            );
+        }
+
+        let mut render = Render::from_parts(
+            instance,
+            output,
+            config,
+            settings,
+            (adapter, device, queue),
+        )?;
+        let present = render.settings.present;
+        render.set_present_settings(present)?;
+        render.shader_watcher = shader_watcher;
+
+        Ok(render)
+    }
+}
+
+/// Picks an adapter for `surface`, or any adapter for headless renderers, and
+/// creates a device on it, validating the requested features and limits
+pub(super) async fn request_device(
+    instance: &wgpu::Instance,
+    surface: Option<&wgpu::Surface<'_>>,
+    settings: &RenderSettings,
+) -> anyhow::Result<(wgpu::Adapter, wgpu::Device, wgpu::Queue)> {
+    let adapter = instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: settings.power_preference,
+            force_fallback_adapter: settings.force_fallback_adapter,
+            compatible_surface: surface,
+        })
+        .await
+        .ok_or_else(|| NoAdapter {
+            message: format!(
+                "No adapter for backends {:?} (power preference {:?}, fallback {}) \
+                     is compatible with {}",
+                settings.backends,
+                settings.power_preference,
+                settings.force_fallback_adapter,
+                if surface.is_some() {
+                    "the window surface"
+                } else {
+                    "headless rendering"
+                }
+            ),
+        })?;
+
+    let missing = settings.required_features - adapter.features();
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "Adapter {:?} does not support the required features {missing:?}",
+            adapter.get_info().name
+        );
+    }
+
+    let required_limits = settings.required_limits.clone().unwrap_or_else(|| {
+        wgpu::Limits::downlevel_webgl2_defaults().using_resolution(adapter.limits())
+    });
+    let mut failed_limits = Vec::new();
+    required_limits.check_limits_with_fail_fn(
+        &adapter.limits(),
+        false,
+        |name, required, allowed| {
+            failed_limits
+                .push(format!("{name} (required {required}, allowed {allowed})"))
+        },
+    );
+    if !failed_limits.is_empty() {
+        anyhow::bail!(
+            "Adapter {:?} does not meet the required limits: {}",
+            adapter.get_info().name,
+            failed_limits.join(", ")
+        );
+    }
+
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                required_features: settings.required_features,
+                required_limits,
+            },
+            None,
+        )
+        .await
+        .context("Failed to create device")?;
+
+    Ok((adapter, device, queue))
+}
+
+/// The configuration of a window surface of `size`, with the preferred
+/// format. Present settings are left at their defaults.
+pub(super) fn surface_config(
+    surface: &wgpu::Surface<'_>,
+    adapter: &wgpu::Adapter,
+    size: winit::dpi::PhysicalSize<u32>,
+    settings: &RenderSettings,
+) -> anyhow::Result<wgpu::SurfaceConfiguration> {
+    let capabilities = surface.get_capabilities(adapter);
+    let format = choose_surface_format(&capabilities.formats, settings.surface_format)?;
+    let mut config = surface
+        .get_default_config(adapter, size.width, size.height)
+        .context("Surface is not supported by the adapter")?;
+    config.format = format;
+    // lets `Render::capture_frame` copy frames back
+    if capabilities.usages.contains(wgpu::TextureUsages::COPY_SRC) {
+        config.usage |= wgpu::TextureUsages::COPY_SRC;
+    }
+    Ok(config)
+}
+
+fn choose_surface_format(
+    formats: &[wgpu::TextureFormat],
+    preference: SurfaceFormatPreference,
+) -> anyhow::Result<wgpu::TextureFormat> {
+    let chosen = match preference {
+        SurfaceFormatPreference::Srgb => formats.iter().find(|f| f.is_srgb()),
+        SurfaceFormatPreference::Linear => formats.iter().find(|f| !f.is_srgb()),
+        SurfaceFormatPreference::Exact(format) => {
+            formats.iter().find(|f| **f == format)
+        }
+    };
+    chosen.copied().with_context(|| {
+        format!(
+            "Surface does not support a {preference:?} format (supported: {formats:?})"
+        )
+    })
+}
@@ -0,0 +1,206 @@
+//! The windows, or the offscreen texture, a renderer presents frames to.
+//!
+//! Every output has its own swapchain and the textures sized to it, while
+//! the device, pipelines, materials and render targets are shared by all of
+//! them.
+use super::{create_size_dependent_textures, GpuResources, RenderTextures};
+use crate::graph::TransientTextures;
+use crate::post::PostProcess;
+use std::sync::Arc;
+use winit::window::Window;
+
+/// Identifies a window a renderer draws to, see `Render::add_window`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OutputId(pub(crate) usize);
+
+impl OutputId {
+    /// The window, or offscreen texture, the renderer was built with
+    pub const PRIMARY: Self = Self(0);
+}
+
+/// Where finished frames go
+pub(super) enum Output {
+    Window {
+        /// Holds its own reference to the window, so the two can be dropped
+        /// in any order
+        surface: wgpu::Surface<'static>,
+        window: Arc<Window>,
+    },
+    /// Offscreen texture with the size and format of the config, for
+    /// rendering without a window
+    Texture(wgpu::Texture),
+}
+
+/// The swapchain of one output and the textures that follow its size
+pub(super) struct OutputState {
+    pub output: Output,
+    /// Also describes the offscreen texture of headless renderers
+    pub config: wgpu::SurfaceConfiguration,
+    pub size: winit::dpi::PhysicalSize<u32>,
+    pub scale_factor: f64,
+    pub textures: RenderTextures,
+    /// Depth and multisampled color, allocated by the frame graph
+    pub transients: TransientTextures,
+    pub post: PostProcess,
+    pub capture_requested: bool,
+    pub capture: Option<anyhow::Result<image::RgbaImage>>,
+}
+
+impl OutputState {
+    /// The surface is configured separately, with `configure`. Windows
+    /// that start minimized get textures of one pixel until they are resized.
+    pub fn new(
+        gpu: &GpuResources,
+        output: Output,
+        mut config: wgpu::SurfaceConfiguration,
+        sample_count: u32,
+    ) -> Self {
+        let size = winit::dpi::PhysicalSize::new(config.width, config.height);
+        config.width = config.width.max(1);
+        config.height = config.height.max(1);
+        let scale_factor = match &output {
+            Output::Window { window, .. } => window.scale_factor(),
+            Output::Texture(_) => 1.0,
+        };
+        let textures = create_size_dependent_textures(
+            &gpu.device,
+            &config,
+            &gpu.bind_groups.oit_layout,
+            sample_count,
+        );
+        let post = PostProcess::new(
+            &gpu.device,
+            &gpu.queue,
+            config.format,
+            config.width,
+            config.height,
+        );
+        Self {
+            output,
+            config,
+            size,
+            scale_factor,
+            textures,
+            transients: TransientTextures::new(),
+            post,
+            capture_requested: false,
+            capture: None,
+        }
+    }
+
+    pub fn window(&self) -> Option<&Arc<Window>> {
+        match &self.output {
+            Output::Window { window, .. } => Some(window),
+            Output::Texture(_) => None,
+        }
+    }
+
+    pub fn surface(&self) -> Option<&wgpu::Surface<'static>> {
+        match &self.output {
+            Output::Window { surface, .. } => Some(surface),
+            Output::Texture(_) => None,
+        }
+    }
+
+    /// Whether there is an area to render into. Minimized windows report a
+    /// zero size.
+    pub fn is_drawable(&self) -> bool {
+        self.size.width > 0 && self.size.height > 0
+    }
+
+    /// Configures the surface with the current config, or recreates the
+    /// offscreen texture, unless it is zero-sized which wgpu rejects
+    pub fn configure(&mut self, device: &wgpu::Device) {
+        if !self.is_drawable() {
+            return;
+        }
+        match &mut self.output {
+            Output::Window { surface, .. } => surface.configure(device, &self.config),
+            Output::Texture(texture) => {
+                *texture = create_output_texture(device, &self.config)
+            }
+        }
+    }
+
+    /// Reconfigures the surface and recreates the textures for `new_size`.
+    /// Zero sizes are only recorded.
+    pub fn resize(
+        &mut self,
+        gpu: &GpuResources,
+        new_size: winit::dpi::PhysicalSize<u32>,
+        sample_count: u32,
+    ) {
+        self.size = new_size;
+        if !self.is_drawable() {
+            return;
+        }
+        self.config.width = new_size.width;
+        self.config.height = new_size.height;
+        self.recreate_textures(gpu, sample_count);
+        self.configure(&gpu.device);
+    }
+
+    /// Recreates every texture that follows the size or sample count of the
+    /// output: the transparency targets and the HDR scene and
+    /// post-processing targets. The frame graph's depth and multisampled
+    /// color are released too and allocated again by the next frame.
+    pub fn recreate_textures(&mut self, gpu: &GpuResources, sample_count: u32) {
+        self.textures = create_size_dependent_textures(
+            &gpu.device,
+            &self.config,
+            &gpu.bind_groups.oit_layout,
+            sample_count,
+        );
+        self.post
+            .resize(&gpu.device, self.config.width, self.config.height);
+        self.transients = TransientTextures::new();
+    }
+
+    /// Rebuilds everything created from the device, after it was replaced
+    pub fn recreate(&mut self, gpu: &GpuResources, sample_count: u32) {
+        self.post = PostProcess::new(
+            &gpu.device,
+            &gpu.queue,
+            self.config.format,
+            self.config.width,
+            self.config.height,
+        );
+        self.recreate_textures(gpu, sample_count);
+        self.configure(&gpu.device);
+    }
+
+    /// The texture the current frame is rendered to
+    pub fn frame_texture<'f>(
+        &'f self,
+        frame: &'f Option<wgpu::SurfaceTexture>,
+    ) -> &'f wgpu::Texture {
+        match (frame, &self.output) {
+            (Some(frame), _) => &frame.texture,
+            (None, Output::Texture(texture)) => texture,
+            (None, Output::Window { .. }) => {
+                unreachable!("window frames come from the surface")
+            }
+        }
+    }
+}
+
+/// Target of headless renderers, copyable for `capture_frame`
+pub(super) fn create_output_texture(
+    device: &wgpu::Device,
+    config: &wgpu::SurfaceConfiguration,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("output_texture"),
+        size: wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: config.format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    })
+}
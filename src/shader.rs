@@ -0,0 +1,342 @@
+//! Loading, preprocessing and validating WGSL at runtime.
+//!
+//! The mesh shader is split into files joined with `#include` and compiled
+//! once per feature permutation, see `Preprocessor`. While developing,
+//! `RenderBuilder::watch_shader` loads the files from disk instead and
+//! `Render` rebuilds its pipelines whenever one of them changes.
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+mod preprocessor;
+
+pub use preprocessor::{Preprocessor, ProcessedShader, ShaderDefs};
+
+/// The built in mesh shader, drawn with every material but custom ones
+pub const MAIN_SHADER: &str = "shader.wgsl";
+/// Shades with the vertex and material color alone, ignoring lights
+pub const UNLIT: &str = "UNLIT";
+/// Shades with every material factor and the environment instead of the
+/// vertex colors
+pub const PBR: &str = "PBR";
+/// Writes the weighted blended transparency targets instead of a color
+pub const WEIGHTED_BLENDED: &str = "WEIGHTED_BLENDED";
+
+/// A preprocessor with the crate's shader files registered
+pub fn builtin_preprocessor() -> Preprocessor {
+    Preprocessor::new()
+        .with_source(MAIN_SHADER, include_str!("shader.wgsl"))
+        .with_source("camera.wgsl", include_str!("camera.wgsl"))
+        .with_source("lights.wgsl", include_str!("lights.wgsl"))
+        .with_source("material.wgsl", include_str!("material.wgsl"))
+        .with_source("mesh.wgsl", include_str!("mesh.wgsl"))
+        .with_source("fragment.wgsl", include_str!("fragment.wgsl"))
+        .with_source("pbr.wgsl", include_str!("pbr.wgsl"))
+        .with_source("skybox.wgsl", include_str!("skybox.wgsl"))
+}
+
+/// Parses and validates preprocessed WGSL with naga. Errors start with the
+/// file, line and column the offending line came from and quote it.
+pub fn validate(shader: &ProcessedShader, name: &str) -> anyhow::Result<naga::Module> {
+    let source = &shader.source;
+    let origin = |location: Option<naga::SourceLocation>| {
+        location
+            .and_then(|location| {
+                let (file, line) = shader.origin(location.line_number)?;
+                Some(format!("{file}:{line}:{}", location.line_position))
+            })
+            .unwrap_or_else(|| name.to_owned())
+    };
+    let module = naga::front::wgsl::parse_str(source).map_err(|e| {
+        anyhow::anyhow!(
+            "Could not parse {}\n{}",
+            origin(e.location(source)),
+            e.emit_to_string_with_path(source, name)
+        )
+    })?;
+    naga::valid::Validator::new(
+        naga::valid::ValidationFlags::all(),
+        naga::valid::Capabilities::all(),
+    )
+    .validate(&module)
+    .map_err(|e| {
+        anyhow::anyhow!(
+            "Invalid shader {}\n{}",
+            origin(e.location(source)),
+            e.emit_to_string_with_path(source, name)
+        )
+    })?;
+    Ok(module)
+}
+
+/// Shader modules compiled on first use, one per file and defines
+pub struct ShaderCache {
+    preprocessor: Preprocessor,
+    modules: HashMap<(String, ShaderDefs), Arc<wgpu::ShaderModule>>,
+}
+
+impl ShaderCache {
+    pub fn new(preprocessor: Preprocessor) -> Self {
+        Self {
+            preprocessor,
+            modules: HashMap::new(),
+        }
+    }
+
+    /// Registers or replaces the file `name`. Compiled modules are dropped,
+    /// since any of them may include it.
+    pub fn add_source(
+        &mut self,
+        name: impl Into<String>,
+        source: impl Into<Cow<'static, str>>,
+    ) {
+        self.preprocessor.add_source(name, source);
+        self.modules.clear();
+    }
+
+    /// Unregisters the file `name` and drops the compiled modules, returning
+    /// whether it was registered
+    pub fn remove_source(&mut self, name: &str) -> bool {
+        self.modules.clear();
+        self.preprocessor.remove_source(name)
+    }
+
+    pub fn preprocessor(&self) -> &Preprocessor {
+        &self.preprocessor
+    }
+
+    /// The module for `name` preprocessed with `defs`, compiled and
+    /// validated the first time it is asked for
+    pub fn get(
+        &mut self,
+        device: &wgpu::Device,
+        name: &str,
+        defs: &ShaderDefs,
+    ) -> anyhow::Result<Arc<wgpu::ShaderModule>> {
+        let key = (name.to_owned(), defs.clone());
+        if let Some(module) = self.modules.get(&key) {
+            return Ok(module.clone());
+        }
+
+        let label = permutation_label(name, defs);
+        let processed = self.preprocessor.process(name, defs)?;
+        validate(&processed, &label)?;
+        let module =
+            Arc::new(device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(&label),
+                source: wgpu::ShaderSource::Wgsl(Cow::Owned(processed.source)),
+            }));
+        self.modules.insert(key, module.clone());
+        Ok(module)
+    }
+
+    /// Number of compiled permutations
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+}
+
+/// `name` followed by its defines, like `shader.wgsl [PBR]`
+fn permutation_label(name: &str, defs: &ShaderDefs) -> String {
+    let defs: Vec<_> = defs
+        .iter()
+        .map(|(name, value)| match value {
+            "" => name.to_owned(),
+            value => format!("{name}={value}"),
+        })
+        .collect();
+    if defs.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{name} [{}]", defs.join(", "))
+    }
+}
+
+/// How often `ShaderWatcher::poll` reads the modification times at most
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Notices changes to a shader file, or any WGSL file next to it that it may
+/// include, by polling their modification times
+pub struct ShaderWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_poll: Instant,
+}
+
+impl ShaderWatcher {
+    /// Fails when `path` is not a file, instead of silently falling back to
+    /// the built in shaders
+    pub fn new(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        if !path.is_file() {
+            anyhow::bail!("Could not find the shader {}", path.display());
+        }
+        let mut watcher = Self {
+            path,
+            modified: None,
+            last_poll: Instant::now(),
+        };
+        watcher.modified = watcher.latest_modification();
+        Ok(watcher)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The directory includes are read from
+    pub fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or(Path::new("."))
+    }
+
+    /// Whether any of the files changed since the last call. The files are
+    /// only looked at every `POLL_INTERVAL`, so this can be called every
+    /// frame.
+    pub fn poll(&mut self) -> bool {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.last_poll = Instant::now();
+        let modified = self.latest_modification();
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        true
+    }
+
+    fn latest_modification(&self) -> Option<SystemTime> {
+        let entries = std::fs::read_dir(self.dir()).ok()?;
+        entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "wgsl"))
+            .filter_map(|entry| entry.metadata().and_then(|m| m.modified()).ok())
+            .max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_main_shader_permutation_validates() {
+        let preprocessor = builtin_preprocessor();
+        for shading in [None, Some(UNLIT), Some(PBR)] {
+            for weighted_blended in [false, true] {
+                let mut defs = ShaderDefs::new();
+                if let Some(shading) = shading {
+                    defs = defs.with(shading);
+                }
+                if weighted_blended {
+                    defs = defs.with(WEIGHTED_BLENDED);
+                }
+                let label = permutation_label(MAIN_SHADER, &defs);
+                let processed = preprocessor.process(MAIN_SHADER, &defs).unwrap();
+                let module =
+                    validate(&processed, &label).unwrap_or_else(|e| panic!("{e:#}"));
+                let entry_points: Vec<_> = module
+                    .entry_points
+                    .iter()
+                    .map(|e| e.name.as_str())
+                    .collect();
+                assert_eq!(entry_points, ["vs_main", "fs_main"], "{label}");
+            }
+        }
+    }
+
+    #[test]
+    fn skybox_validates() {
+        let processed = builtin_preprocessor()
+            .process("skybox.wgsl", &ShaderDefs::new())
+            .unwrap();
+        validate(&processed, "skybox.wgsl").unwrap_or_else(|e| panic!("{e:#}"));
+    }
+
+    #[test]
+    fn custom_shaders_can_reuse_the_mesh_stages() {
+        let preprocessor = builtin_preprocessor().with_source(
+            "custom.wgsl",
+            "#include \"mesh.wgsl\"\n\
+             fn shade(in: VertexOutput) -> vec4<f32> {\n\
+             \x20   return vec4<f32>(in.normal * 0.5 + 0.5, 1.0);\n\
+             }\n\
+             #include \"fragment.wgsl\"\n",
+        );
+        for defs in [ShaderDefs::new(), ShaderDefs::new().with(WEIGHTED_BLENDED)] {
+            let processed = preprocessor.process("custom.wgsl", &defs).unwrap();
+            validate(&processed, "custom.wgsl").unwrap_or_else(|e| panic!("{e:#}"));
+        }
+    }
+
+    #[test]
+    fn errors_point_into_the_included_file() {
+        let processed = Preprocessor::new()
+            .with_source(
+                "broken.wgsl",
+                "fn broken() -> f32 {\n    return 1.0 +;\n}\n",
+            )
+            .with_source("main.wgsl", "// main\n#include \"broken.wgsl\"\n")
+            .process("main.wgsl", &ShaderDefs::new())
+            .unwrap();
+        let error = validate(&processed, "main.wgsl").unwrap_err().to_string();
+        assert!(
+            error.starts_with("Could not parse broken.wgsl:2:"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn missing_shader_files_are_errors() {
+        let path = std::env::temp_dir().join("rust_graphics-missing/shader.wgsl");
+        let error = ShaderWatcher::new(&path).err().unwrap().to_string();
+        assert!(error.contains("Could not find the shader"), "{error}");
+    }
+
+    #[test]
+    fn watchers_poll_at_most_every_interval() {
+        let dir = std::env::temp_dir()
+            .join(format!("rust_graphics-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(MAIN_SHADER);
+        std::fs::write(&path, include_str!("shader.wgsl")).unwrap();
+        let mut watcher = ShaderWatcher::new(&path).unwrap();
+
+        let touch = |path: &Path, seconds| {
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+            let file = std::fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(modified).unwrap();
+        };
+        touch(&path, 1_000_000_000);
+        assert!(!watcher.poll());
+        std::thread::sleep(POLL_INTERVAL);
+        assert!(watcher.poll());
+        assert!(!watcher.poll());
+
+        // included files next to the shader are watched too
+        let include = dir.join("include.wgsl");
+        std::fs::write(&include, "").unwrap();
+        touch(&include, 2_000_000_000);
+        std::thread::sleep(POLL_INTERVAL);
+        assert!(watcher.poll());
+    }
+
+    #[test]
+    fn labels_list_the_defines() {
+        let defs = ShaderDefs::new().with(PBR).with_value("COUNT", "4u");
+        assert_eq!(
+            permutation_label(MAIN_SHADER, &defs),
+            "shader.wgsl [COUNT=4u, PBR]"
+        );
+        assert_eq!(
+            permutation_label(MAIN_SHADER, &ShaderDefs::new()),
+            MAIN_SHADER
+        );
+    }
+}
@@ -1,32 +1,24 @@
-struct CameraUniform {
-    view_proj: mat4x4<f32>,
-}
-
-@group(0) @binding(0)
-var<uniform> camera: CameraUniform;
+// Built in mesh shader. Defines:
+// - UNLIT: the vertex and material color without lighting
+// - PBR: shade with every material factor and the environment
+// - neither: the vertex and material color, lit by the scene lights
+// - WEIGHTED_BLENDED: write the weighted blended transparency targets
+#include "mesh.wgsl"
 
-struct VertexInput {
-    @location(0) position: vec4<f32>,
-    @location(1) color: vec4<f32>,
-}
+#ifdef PBR
+#include "pbr.wgsl"
+#endif
 
-struct VertexOutput {
-    @builtin(position) position: vec4<f32>,
-    @location(0) color: vec4<f32>,
+fn shade(in: VertexOutput) -> vec4<f32> {
+#ifdef PBR
+    return shade_pbr(in);
+#else
+#ifdef UNLIT
+    return surface_color(in);
+#else
+    return shade_lit(in);
+#endif
+#endif
 }
 
-@vertex
-fn vs_main(in: VertexInput) -> VertexOutput {
-    var out: VertexOutput;
-    // these values control the coordinates of the triangle
-    out.position = camera.view_proj * in.position;
-    //out.position = in.position;
-    out.color = in.color;
-
-    return out;
-}
-
-@fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return in.color;
-}
+#include "fragment.wgsl"
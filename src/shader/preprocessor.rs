@@ -0,0 +1,350 @@
+//! A small line based preprocessor for WGSL.
+//!
+//! Directives start a line with `#`:
+//!
+//! - `#include "name.wgsl"` pastes a file in. Every file is included at most
+//!   once, so shared declarations can be included from anywhere.
+//! - `#define NAME` sets a flag, `#define NAME value` also replaces the
+//!   identifier `NAME` with `value` in the lines after it. `#undef NAME`
+//!   removes either.
+//! - `#ifdef NAME`, `#ifndef NAME`, `#else` and `#endif` keep or drop lines.
+//!   They nest, but must be closed in the file they were opened in.
+use anyhow::Context;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+/// Defines a shader is preprocessed with, also the key of its permutation
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ShaderDefs(BTreeMap<String, String>);
+
+impl ShaderDefs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a flag for `#ifdef`
+    pub fn with(self, name: impl Into<String>) -> Self {
+        self.with_value(name, "")
+    }
+
+    /// Adds a define that is also substituted into the source
+    pub fn with_value(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.0.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+/// Preprocessor output, with the origin of every line for error messages
+#[derive(Clone, Debug)]
+pub struct ProcessedShader {
+    pub source: String,
+    files: Vec<String>,
+    /// Index into `files` and 1-based line number of every output line
+    origins: Vec<(usize, u32)>,
+}
+
+impl ProcessedShader {
+    /// The file and line output line `line` (1-based) came from
+    pub fn origin(&self, line: u32) -> Option<(&str, u32)> {
+        let &(file, line) = self.origins.get(line.checked_sub(1)? as usize)?;
+        Some((&self.files[file], line))
+    }
+}
+
+/// Resolves includes from registered sources and optionally a directory
+#[derive(Clone, Debug, Default)]
+pub struct Preprocessor {
+    sources: HashMap<String, Cow<'static, str>>,
+    dir: Option<PathBuf>,
+}
+
+impl Preprocessor {
+    /// A preprocessor without any sources
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `source` under `name` for `#include` and `process`
+    pub fn add_source(
+        &mut self,
+        name: impl Into<String>,
+        source: impl Into<Cow<'static, str>>,
+    ) {
+        self.sources.insert(name.into(), source.into());
+    }
+
+    /// Unregisters `name`, returning whether it was registered
+    pub fn remove_source(&mut self, name: &str) -> bool {
+        self.sources.remove(name).is_some()
+    }
+
+    pub fn with_source(
+        mut self,
+        name: impl Into<String>,
+        source: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        self.add_source(name, source);
+        self
+    }
+
+    /// Reads files from `dir` in preference to the registered sources, so
+    /// edits on disk are picked up without recompiling
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// Preprocesses the file `name` with `defs`
+    pub fn process(
+        &self,
+        name: &str,
+        defs: &ShaderDefs,
+    ) -> anyhow::Result<ProcessedShader> {
+        let mut state = State {
+            defs: defs.0.clone(),
+            output: ProcessedShader {
+                source: String::new(),
+                files: Vec::new(),
+                origins: Vec::new(),
+            },
+        };
+        self.include(name, &mut state)?;
+        Ok(state.output)
+    }
+
+    fn load(&self, name: &str) -> anyhow::Result<Cow<'static, str>> {
+        if let Some(dir) = &self.dir {
+            let path = dir.join(name);
+            if path.is_file() {
+                return std::fs::read_to_string(&path)
+                    .map(Cow::Owned)
+                    .with_context(|| format!("Could not read {}", path.display()));
+            }
+        }
+        self.sources
+            .get(name)
+            .cloned()
+            .with_context(|| format!("Unknown shader file {name:?}"))
+    }
+
+    fn include(&self, name: &str, state: &mut State) -> anyhow::Result<()> {
+        if state.output.files.iter().any(|file| file == name) {
+            return Ok(());
+        }
+        let source = self.load(name)?;
+        let file = state.output.files.len();
+        state.output.files.push(name.to_owned());
+
+        // whether lines are kept, one entry per open #ifdef
+        let mut branches: Vec<Branch> = Vec::new();
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index as u32 + 1;
+            let error =
+                |message: String| anyhow::anyhow!("{name}:{line_number}: {message}");
+            let active = branches.iter().all(|branch| branch.active);
+
+            let Some(directive) = line.trim_start().strip_prefix('#') else {
+                if active {
+                    state.output.source.push_str(&substitute(line, &state.defs));
+                    state.output.source.push('\n');
+                    state.output.origins.push((file, line_number));
+                }
+                continue;
+            };
+            let mut words = directive.split_whitespace();
+            let keyword = words.next().unwrap_or_default();
+            let argument = words.next();
+            let rest: Vec<_> = words.collect();
+            let required =
+                || argument.ok_or_else(|| error(format!("#{keyword} needs a name")));
+            match keyword {
+                "ifdef" | "ifndef" => {
+                    let defined = state.defs.contains_key(required()?);
+                    branches.push(Branch {
+                        active: defined == (keyword == "ifdef"),
+                        seen_else: false,
+                    });
+                }
+                "else" => {
+                    let branch = branches
+                        .last_mut()
+                        .filter(|branch| !branch.seen_else)
+                        .ok_or_else(|| error("#else without #ifdef".into()))?;
+                    branch.active = !branch.active;
+                    branch.seen_else = true;
+                }
+                "endif" => {
+                    branches
+                        .pop()
+                        .ok_or_else(|| error("#endif without #ifdef".into()))?;
+                }
+                _ if !active => {}
+                "include" => {
+                    let included = directive.trim_start()["include".len()..].trim();
+                    let included = included
+                        .strip_prefix('"')
+                        .and_then(|included| included.strip_suffix('"'))
+                        .ok_or_else(|| {
+                            error(format!("Expected a quoted name, got {included}"))
+                        })?;
+                    self.include(included, state).with_context(|| {
+                        format!("Included from {name}:{line_number}")
+                    })?;
+                }
+                "define" => {
+                    let define = required()?;
+                    state.defs.insert(define.to_owned(), rest.join(" "));
+                }
+                "undef" => {
+                    state.defs.remove(required()?);
+                }
+                _ => return Err(error(format!("Unknown directive #{keyword}"))),
+            }
+        }
+        if !branches.is_empty() {
+            anyhow::bail!("{name}: {} #ifdef not closed with #endif", branches.len());
+        }
+        Ok(())
+    }
+}
+
+struct State {
+    defs: BTreeMap<String, String>,
+    output: ProcessedShader,
+}
+
+struct Branch {
+    active: bool,
+    seen_else: bool,
+}
+
+/// Replaces identifiers that have a define with a value
+fn substitute<'a>(line: &'a str, defs: &BTreeMap<String, String>) -> Cow<'a, str> {
+    if defs.values().all(String::is_empty) {
+        return Cow::Borrowed(line);
+    }
+    let mut output = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let identifier = &rest[..end];
+        match defs.get(identifier) {
+            Some(value) if !value.is_empty() => output.push_str(value),
+            _ => output.push_str(identifier),
+        }
+        rest = &rest[end..];
+    }
+    output.push_str(rest);
+    Cow::Owned(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(source: &'static str, defs: &ShaderDefs) -> anyhow::Result<String> {
+        Preprocessor::new()
+            .with_source("test.wgsl", source)
+            .process("test.wgsl", defs)
+            .map(|processed| processed.source)
+    }
+
+    #[test]
+    fn ifdef_keeps_the_active_branch() {
+        let source = "a\n#ifdef FOO\nfoo\n#else\nnot foo\n#endif\nb\n";
+        assert_eq!(
+            process(source, &ShaderDefs::new()).unwrap(),
+            "a\nnot foo\nb\n"
+        );
+        assert_eq!(
+            process(source, &ShaderDefs::new().with("FOO")).unwrap(),
+            "a\nfoo\nb\n"
+        );
+    }
+
+    #[test]
+    fn nested_branches() {
+        let source =
+            "#ifndef A\n#ifdef B\nb\n#endif\n#define C\n#endif\n#ifdef C\nc\n#endif\n";
+        assert_eq!(process(source, &ShaderDefs::new()).unwrap(), "c\n");
+        assert_eq!(
+            process(source, &ShaderDefs::new().with("B")).unwrap(),
+            "b\nc\n"
+        );
+        assert_eq!(
+            process(source, &ShaderDefs::new().with("A").with("B")).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn defines_with_values_replace_whole_identifiers() {
+        let source = "#define COUNT 4u\nlet a = COUNT + COUNTS + MY_COUNT;\n#undef COUNT\nCOUNT\n";
+        assert_eq!(
+            process(source, &ShaderDefs::new()).unwrap(),
+            "let a = 4u + COUNTS + MY_COUNT;\nCOUNT\n"
+        );
+        assert_eq!(
+            process("x * SCALE\n", &ShaderDefs::new().with_value("SCALE", "2.0"))
+                .unwrap(),
+            "x * 2.0\n"
+        );
+    }
+
+    #[test]
+    fn files_are_included_once() {
+        let processed = Preprocessor::new()
+            .with_source("common.wgsl", "common\n")
+            .with_source("a.wgsl", "#include \"common.wgsl\"\na\n")
+            .with_source(
+                "main.wgsl",
+                "#include \"common.wgsl\"\n#include \"a.wgsl\"\nmain\n",
+            )
+            .process("main.wgsl", &ShaderDefs::new())
+            .unwrap();
+        assert_eq!(processed.source, "common\na\nmain\n");
+        assert_eq!(processed.origin(1), Some(("common.wgsl", 1)));
+        assert_eq!(processed.origin(2), Some(("a.wgsl", 2)));
+        assert_eq!(processed.origin(3), Some(("main.wgsl", 3)));
+        assert_eq!(processed.origin(4), None);
+    }
+
+    #[test]
+    fn includes_in_inactive_branches_are_skipped() {
+        let source = "#ifdef MISSING\n#include \"missing.wgsl\"\n#endif\nok\n";
+        assert_eq!(process(source, &ShaderDefs::new()).unwrap(), "ok\n");
+    }
+
+    #[test]
+    fn errors_name_the_file_and_line() {
+        let error =
+            |source| format!("{:#}", process(source, &ShaderDefs::new()).unwrap_err());
+        assert!(error("a\n#endif\n").starts_with("test.wgsl:2: #endif without #ifdef"));
+        assert!(error("#ifdef A\n#else\n#else\n#endif\n").starts_with("test.wgsl:3:"));
+        assert!(error("#ifdef A\n").contains("not closed"));
+        assert!(error("#pragma once\n").contains("Unknown directive #pragma"));
+        assert!(error("#include missing.wgsl\n").contains("quoted name"));
+        let missing = error("\n#include \"missing.wgsl\"\n");
+        assert!(missing.contains("Included from test.wgsl:2"));
+        assert!(missing.contains("Unknown shader file \"missing.wgsl\""));
+    }
+}
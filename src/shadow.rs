@@ -0,0 +1,469 @@
+//! Shadow maps for directional and spot lights.
+//!
+//! Every shadow casting light renders depth into one layer of a shared
+//! texture array. Directional lights use cascades fitted to slices of the
+//! camera frustum, spot lights a single perspective layer.
+use crate::camera::CameraView;
+use crate::light::{
+    DirectionalLightUniform, Lights, LightsUniform, SpotLightUniform,
+    MAX_DIRECTIONAL_LIGHTS, MAX_SPOT_LIGHTS,
+};
+use crate::texture;
+use crate::vertex::Vertex;
+use glam::{Mat4, Vec3, Vec4};
+use std::borrow::Cow;
+
+pub const MAX_CASCADES: usize = 4;
+pub const MAX_SHADOW_MAPS: usize =
+    MAX_DIRECTIONAL_LIGHTS * MAX_CASCADES + MAX_SPOT_LIGHTS;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowSettings {
+    /// Width and height of every shadow map in texels
+    pub resolution: u32,
+    /// Constant depth bias added while rendering the shadow maps
+    pub depth_bias: i32,
+    /// Depth bias scaled by the slope of the rendered triangle
+    pub slope_bias: f32,
+    /// World space offset along the surface normal applied when sampling
+    pub normal_bias: f32,
+    /// PCF kernel radius in texels, 0 takes a single sample
+    pub pcf_radius: u32,
+    /// Cascades per directional light, between 1 and `MAX_CASCADES`
+    pub cascades: u32,
+    /// Blends cascade splits between uniform (0.0) and logarithmic (1.0)
+    pub cascade_split_lambda: f32,
+    /// Directional light shadows end at this distance from the camera
+    pub max_distance: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 2048,
+            depth_bias: 2,
+            slope_bias: 2.0,
+            normal_bias: 0.02,
+            pcf_radius: 1,
+            cascades: 4,
+            cascade_split_lambda: 0.75,
+            max_distance: 100.0,
+        }
+    }
+}
+
+impl ShadowSettings {
+    pub(crate) fn validate(&self, limits: &wgpu::Limits) -> anyhow::Result<()> {
+        if self.resolution == 0 || self.resolution > limits.max_texture_dimension_2d {
+            anyhow::bail!(
+                "shadow map resolution {} must be between 1 and {}",
+                self.resolution,
+                limits.max_texture_dimension_2d
+            );
+        }
+        if self.cascades == 0 || self.cascades as usize > MAX_CASCADES {
+            anyhow::bail!(
+                "shadow cascade count {} must be between 1 and {MAX_CASCADES}",
+                self.cascades
+            );
+        }
+        if !(0.0..=1.0).contains(&self.cascade_split_lambda) {
+            anyhow::bail!(
+                "cascade_split_lambda {} must be between 0 and 1",
+                self.cascade_split_lambda
+            );
+        }
+        if self.max_distance <= 0.0 {
+            anyhow::bail!("shadow max_distance must be positive");
+        }
+        Ok(())
+    }
+}
+
+/// Shadow map layer used by `cascade` of the directional light at `light`
+pub(crate) fn directional_layer(light: usize, cascade: usize) -> usize {
+    light * MAX_CASCADES + cascade
+}
+
+/// Shadow map layer used by the spot light at `light`
+pub(crate) fn spot_layer(light: usize) -> usize {
+    MAX_DIRECTIONAL_LIGHTS * MAX_CASCADES + light
+}
+
+/// View space distances at which each of `count` cascades between `near`
+/// and `far` ends, using the practical split scheme
+pub fn cascade_splits(near: f32, far: f32, count: usize, lambda: f32) -> Vec<f32> {
+    (1..=count)
+        .map(|i| {
+            let fraction = i as f32 / count as f32;
+            let logarithmic = near * (far / near).powf(fraction);
+            let uniform = near + (far - near) * fraction;
+            lambda * logarithmic + (1.0 - lambda) * uniform
+        })
+        .collect()
+}
+
+fn up_for(direction: Vec3) -> Vec3 {
+    if direction.normalize().abs().y > 0.99 {
+        Vec3::Z
+    } else {
+        Vec3::Y
+    }
+}
+
+/// Orthographic light matrix covering a bounding sphere around `corners`.
+/// The projection is snapped to whole texels so the shadow edges do not
+/// shimmer while the camera moves.
+pub(crate) fn directional_light_matrix(
+    corners: &[Vec3; 8],
+    direction: Vec3,
+    resolution: u32,
+) -> Mat4 {
+    let direction = direction.normalize();
+    let center = corners.iter().copied().sum::<Vec3>() / corners.len() as f32;
+    let radius = corners
+        .iter()
+        .map(|corner| corner.distance(center))
+        .fold(0.0, f32::max);
+    let radius = (radius * 16.0).ceil() / 16.0;
+
+    // leave room behind the frustum for casters outside of it
+    let view = Mat4::look_to_rh(
+        center - direction * radius * 3.0,
+        direction,
+        up_for(direction),
+    );
+    let projection =
+        Mat4::orthographic_rh(-radius, radius, -radius, radius, 0.0, radius * 4.0);
+
+    let half_resolution = resolution as f32 * 0.5;
+    let origin = (projection * view).transform_point3(Vec3::ZERO) * half_resolution;
+    let offset = (origin.round() - origin) / half_resolution;
+    Mat4::from_translation(Vec3::new(offset.x, offset.y, 0.0)) * projection * view
+}
+
+pub(crate) fn spot_light_matrix(
+    position: Vec3,
+    direction: Vec3,
+    outer_angle: f32,
+    range: f32,
+) -> Mat4 {
+    let projection =
+        Mat4::perspective_rh((outer_angle * 2.0).min(3.1), 1.0, 0.05, range);
+    let view = Mat4::look_to_rh(position, direction.normalize(), up_for(direction));
+    projection * view
+}
+
+/// Per frame light data: the uniform for the main pass and the shadow map
+/// layers that need rendering along with their light matrices
+pub(crate) struct ShadowFrame {
+    pub uniform: LightsUniform,
+    pub layers: Vec<(usize, Mat4)>,
+}
+
+pub(crate) fn prepare(
+    lights: &Lights,
+    camera: &dyn CameraView,
+    aspect_ratio: f32,
+    settings: &ShadowSettings,
+) -> ShadowFrame {
+    let cascades = settings.cascades.clamp(1, MAX_CASCADES as u32) as usize;
+    let far = camera.z_far().min(settings.max_distance);
+    let splits = cascade_splits(
+        camera.z_near(),
+        far,
+        cascades,
+        settings.cascade_split_lambda,
+    );
+
+    let mut uniform = LightsUniform {
+        ambient: lights.ambient.extend(lights.environment_intensity).into(),
+        counts: [0, 0, cascades as u32, 0],
+        cascade_splits: [0.0; 4],
+        shadow: [
+            settings.normal_bias,
+            1.0 / settings.resolution as f32,
+            settings.pcf_radius as f32,
+            0.0,
+        ],
+        directional: [DirectionalLightUniform::default(); MAX_DIRECTIONAL_LIGHTS],
+        spot: [SpotLightUniform::default(); MAX_SPOT_LIGHTS],
+        shadow_matrices: [Mat4::IDENTITY.to_cols_array_2d(); MAX_SHADOW_MAPS],
+    };
+    uniform.cascade_splits[..cascades].copy_from_slice(&splits);
+    let mut layers = Vec::new();
+
+    let directional = lights.directional.iter().take(MAX_DIRECTIONAL_LIGHTS);
+    for (i, light) in directional.enumerate() {
+        uniform.directional[i] = DirectionalLightUniform {
+            direction: light
+                .direction
+                .normalize()
+                .extend(light.casts_shadows as u32 as f32)
+                .into(),
+            color: (light.color * light.intensity).extend(1.0).into(),
+        };
+        uniform.counts[0] += 1;
+        if !light.casts_shadows {
+            continue;
+        }
+
+        let mut near = camera.z_near();
+        for (cascade, &split) in splits.iter().enumerate() {
+            let corners = camera.frustum_corners(aspect_ratio, near, split);
+            let matrix = directional_light_matrix(
+                &corners,
+                light.direction,
+                settings.resolution,
+            );
+            let layer = directional_layer(i, cascade);
+            uniform.shadow_matrices[layer] = matrix.to_cols_array_2d();
+            layers.push((layer, matrix));
+            near = split;
+        }
+    }
+
+    for (i, light) in lights.spot.iter().take(MAX_SPOT_LIGHTS).enumerate() {
+        uniform.spot[i] = SpotLightUniform {
+            position: light.position.extend(light.range).into(),
+            direction: light
+                .direction
+                .normalize()
+                .extend(light.casts_shadows as u32 as f32)
+                .into(),
+            color: (light.color * light.intensity).extend(1.0).into(),
+            cone: Vec4::new(light.inner_angle.cos(), light.outer_angle.cos(), 0.0, 0.0)
+                .into(),
+        };
+        uniform.counts[1] += 1;
+        if !light.casts_shadows {
+            continue;
+        }
+
+        let matrix = spot_light_matrix(
+            light.position,
+            light.direction,
+            light.outer_angle,
+            light.range,
+        );
+        let layer = spot_layer(i);
+        uniform.shadow_matrices[layer] = matrix.to_cols_array_2d();
+        layers.push((layer, matrix));
+    }
+
+    ShadowFrame { uniform, layers }
+}
+
+/// The shadow map texture array and everything needed to render into it
+pub(crate) struct ShadowMaps {
+    pub texture: texture::Texture,
+    pub layer_views: Vec<wgpu::TextureView>,
+    /// One light matrix per layer, each at a multiple of `uniform_stride`
+    pub pass_uniform: wgpu::Buffer,
+    pub uniform_stride: wgpu::BufferAddress,
+    pub pass_bind_group: wgpu::BindGroup,
+    pub pipeline: wgpu::RenderPipeline,
+}
+
+impl ShadowMaps {
+    pub fn new(device: &wgpu::Device, settings: &ShadowSettings) -> Self {
+        let texture = texture::create_depth_texture_array(
+            device,
+            settings.resolution,
+            MAX_SHADOW_MAPS as u32,
+            "shadow_maps",
+        );
+        let layer_views = (0..MAX_SHADOW_MAPS as u32)
+            .map(|layer| {
+                texture.texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some("shadow_map_layer"),
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: layer,
+                    array_layer_count: Some(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let matrix_size = std::mem::size_of::<Mat4>() as wgpu::BufferAddress;
+        let alignment =
+            device.limits().min_uniform_buffer_offset_alignment as wgpu::BufferAddress;
+        let uniform_stride = matrix_size.div_ceil(alignment) * alignment;
+        let pass_uniform = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow Pass Uniform Buffer"),
+            size: uniform_stride * MAX_SHADOW_MAPS as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("shadow_pass_bind_group_layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(matrix_size),
+                    },
+                    count: None,
+                }],
+            });
+        let pass_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("shadow_pass_bind_group"),
+            layout: &bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &pass_uniform,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(matrix_size),
+                }),
+            }],
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("shadow_shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!(
+                "shadow.wgsl"
+            ))),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("shadow_pipeline_layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("shadow_pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_shadow",
+                buffers: &[Vertex::desc()],
+            },
+            fragment: None,
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState {
+                    constant: settings.depth_bias,
+                    slope_scale: settings.slope_bias,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        Self {
+            texture,
+            layer_views,
+            pass_uniform,
+            uniform_stride,
+            pass_bind_group,
+            pipeline,
+        }
+    }
+
+    /// Uploads the light matrix of every layer rendered this frame
+    pub fn write_layers(&self, queue: &wgpu::Queue, layers: &[(usize, Mat4)]) {
+        for (layer, matrix) in layers {
+            queue.write_buffer(
+                &self.pass_uniform,
+                *layer as wgpu::BufferAddress * self.uniform_stride,
+                bytemuck::cast_slice(matrix.as_ref()),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{Camera, Perspective};
+    use crate::transform::Transform;
+    use glam::Quat;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() <= 1e-4 * b.abs().max(1.0), "{a} != {b}");
+    }
+
+    #[test]
+    fn cascade_splits_grow_from_near_to_far() {
+        for lambda in [0.0, 0.3, 0.75, 1.0] {
+            for count in 1..=MAX_CASCADES {
+                let splits = cascade_splits(0.1, 100.0, count, lambda);
+                assert_eq!(splits.len(), count);
+                assert!(splits[0] > 0.1, "{splits:?}");
+                assert!(splits.windows(2).all(|w| w[0] < w[1]), "{splits:?}");
+                assert_close(*splits.last().unwrap(), 100.0);
+            }
+        }
+    }
+
+    #[test]
+    fn cascade_split_lambda_blends_uniform_and_logarithmic() {
+        let uniform = cascade_splits(1.0, 1000.0, 3, 0.0);
+        for (split, expected) in uniform.iter().zip([334.0, 667.0, 1000.0]) {
+            assert_close(*split, expected);
+        }
+        let logarithmic = cascade_splits(1.0, 1000.0, 3, 1.0);
+        for (split, expected) in logarithmic.iter().zip([10.0, 100.0, 1000.0]) {
+            assert_close(*split, expected);
+        }
+    }
+
+    #[test]
+    fn cascades_contain_their_frustum_slice() {
+        let mut camera = Camera::new(
+            70.0,
+            16.0 / 9.0,
+            Perspective,
+            Transform::from_translation(Vec3::new(3.0, 2.0, -8.0)),
+        );
+        camera.pitch = -0.4;
+        let splits = cascade_splits(camera.z_near(), 50.0, 4, 0.75);
+        for direction in [
+            Vec3::new(-0.3, -1.0, 0.2),
+            Vec3::NEG_Y,
+            Vec3::new(1.0, -0.1, 0.0),
+        ] {
+            let mut near = camera.z_near();
+            for &split in &splits {
+                let corners = camera.frustum_corners(near, split);
+                let matrix = directional_light_matrix(&corners, direction, 2048);
+                for corner in corners {
+                    let clip = matrix.project_point3(corner);
+                    assert!(clip.x.abs() <= 1.0 && clip.y.abs() <= 1.0, "{clip}");
+                    assert!((0.0..=1.0).contains(&clip.z), "{clip}");
+                }
+                near = split;
+            }
+        }
+    }
+
+    #[test]
+    fn spot_lights_see_their_cone() {
+        let position = Vec3::new(1.0, 4.0, 0.0);
+        let direction = Vec3::new(0.0, -1.0, 0.5).normalize();
+        let outer_angle = 0.6;
+        let matrix = spot_light_matrix(position, direction, outer_angle, 20.0);
+
+        let center = matrix.project_point3(position + direction * 10.0);
+        assert_close(center.x, 0.0);
+        assert_close(center.y, 0.0);
+        // the cone edge at the range stays inside the map
+        let side = direction.cross(up_for(direction)).normalize();
+        let edge = Quat::from_axis_angle(side, outer_angle * 0.99) * direction;
+        let clip = matrix.project_point3(position + edge * 19.0);
+        assert!(clip.x.abs() <= 1.0 && clip.y.abs() <= 1.0, "{clip}");
+        assert!((0.0..=1.0).contains(&clip.z), "{clip}");
+        // points behind the light are not
+        let behind = matrix * (position - direction).extend(1.0);
+        assert!(behind.w < 0.0);
+    }
+}
@@ -0,0 +1,11 @@
+struct ShadowPass {
+    light_view_proj: mat4x4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> shadow_pass: ShadowPass;
+
+@vertex
+fn vs_shadow(@location(0) position: vec4<f32>) -> @builtin(position) vec4<f32> {
+    return shadow_pass.light_view_proj * position;
+}
@@ -0,0 +1,31 @@
+#include "camera.wgsl"
+
+@group(1) @binding(0)
+var sky: texture_cube<f32>;
+@group(1) @binding(1)
+var sky_sampler: sampler;
+
+struct VertexOutput {
+    // invariant so the far plane depth matches the cleared depth exactly
+    @builtin(position) @invariant position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+}
+
+// Fullscreen triangle on the far plane, so only pixels no geometry was drawn
+// to pass the depth test
+@vertex
+fn vs_sky(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    let ndc = uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0);
+    var out: VertexOutput;
+    out.position = vec4<f32>(ndc, 1.0, 1.0);
+    out.ndc = ndc;
+    return out;
+}
+
+@fragment
+fn fs_sky(in: VertexOutput) -> @location(0) vec4<f32> {
+    let world = camera.inv_rotation_view_proj * vec4<f32>(in.ndc, 1.0, 1.0);
+    let direction = world.xyz / world.w;
+    return vec4<f32>(textureSample(sky, sky_sampler, direction).rgb, 1.0);
+}
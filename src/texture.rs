@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
 
 pub struct Texture {
@@ -8,15 +10,66 @@ pub struct Texture {
 
 pub fn create_depth_texture(
     device: &wgpu::Device,
-    config: &wgpu::SurfaceConfiguration,
+    width: u32,
+    height: u32,
+    sample_count: u32,
     label: &str,
 ) -> Texture {
     let size = wgpu::Extent3d {
-        width: config.width,
-        height: config.height,
+        width,
+        height,
         depth_or_array_layers: 1,
     };
 
+    let desc = wgpu::TextureDescriptor {
+        label: Some(label),
+        size,
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format: DEPTH_FORMAT,
+        // GL can not create bindable multisampled textures
+        usage: if sample_count > 1 {
+            wgpu::TextureUsages::RENDER_ATTACHMENT
+        } else {
+            wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::TEXTURE_BINDING
+        },
+        view_formats: &[],
+    };
+
+    let texture = device.create_texture(&desc);
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = create_comparison_sampler(device);
+
+    Texture {
+        texture,
+        view,
+        sampler,
+    }
+}
+
+/// Creates a depth texture with `layers` square layers, viewed as a 2D array
+pub fn create_depth_texture_array(
+    device: &wgpu::Device,
+    resolution: u32,
+    layers: u32,
+    label: &str,
+) -> Texture {
+    // the GL backend guesses view dimensions from the layer count and takes
+    // multiples of six for cube maps, so pad those with an unused layer
+    let layers = if layers.is_multiple_of(6) {
+        layers + 1
+    } else {
+        layers
+    };
+    let size = wgpu::Extent3d {
+        width: resolution,
+        height: resolution,
+        depth_or_array_layers: layers,
+    };
+
     let desc = wgpu::TextureDescriptor {
         label: Some(label),
         size,
@@ -31,8 +84,21 @@ pub fn create_depth_texture(
 
     let texture = device.create_texture(&desc);
 
-    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+    let view = texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..Default::default()
+    });
+    let sampler = create_comparison_sampler(device);
+
+    Texture {
+        texture,
+        view,
+        sampler,
+    }
+}
+
+fn create_comparison_sampler(device: &wgpu::Device) -> wgpu::Sampler {
+    device.create_sampler(&wgpu::SamplerDescriptor {
         address_mode_u: wgpu::AddressMode::ClampToEdge,
         address_mode_v: wgpu::AddressMode::ClampToEdge,
         address_mode_w: wgpu::AddressMode::ClampToEdge,
@@ -43,6 +109,242 @@ pub fn create_depth_texture(
         lod_min_clamp: 0.0,
         lod_max_clamp: 100.0,
         ..Default::default()
+    })
+}
+
+/// Creates the multisampled color target that is resolved into the HDR
+/// target
+pub fn create_multisampled_framebuffer(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+) -> wgpu::TextureView {
+    let size = wgpu::Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+    };
+
+    let desc = wgpu::TextureDescriptor {
+        label: Some("multisampled_framebuffer"),
+        size,
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        view_formats: &[],
+    };
+
+    device
+        .create_texture(&desc)
+        .create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+/// Creates a color texture that is rendered into and sampled afterwards
+pub fn create_color_target(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+    format: wgpu::TextureFormat,
+    label: &str,
+) -> Texture {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+            | wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some(label),
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        ..Default::default()
+    });
+
+    Texture {
+        texture,
+        view,
+        sampler,
+    }
+}
+
+/// Format of color textures sampled by materials
+pub fn color_format(srgb: bool) -> wgpu::TextureFormat {
+    if srgb {
+        wgpu::TextureFormat::Rgba8UnormSrgb
+    } else {
+        wgpu::TextureFormat::Rgba8Unorm
+    }
+}
+
+/// Loads an image file into a mipmapped texture. Colors (base color,
+/// emissive) should be loaded as `srgb`, data (normals, metallic/roughness,
+/// occlusion) as linear.
+pub fn load_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    path: impl AsRef<std::path::Path>,
+    srgb: bool,
+) -> anyhow::Result<Texture> {
+    let path = path.as_ref();
+    let image = image::open(path)
+        .map_err(|e| anyhow::anyhow!("Could not load texture {path:?}: {e}"))?;
+    let label = path.to_string_lossy();
+    Ok(create_texture_from_image(
+        device, queue, &image, srgb, &label,
+    ))
+}
+
+/// Uploads `image` with a full mip chain, downsampled on the CPU
+pub fn create_texture_from_image(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    image: &image::DynamicImage,
+    srgb: bool,
+    label: &str,
+) -> Texture {
+    let (width, height) = (image.width().max(1), image.height().max(1));
+    let mip_level_count = width.max(height).ilog2() + 1;
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: color_format(srgb),
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+
+    for mip_level in 0..mip_level_count {
+        let level_width = (width >> mip_level).max(1);
+        let level_height = (height >> mip_level).max(1);
+        let level = if mip_level == 0 {
+            image.to_rgba8()
+        } else {
+            image
+                .resize_exact(
+                    level_width,
+                    level_height,
+                    image::imageops::FilterType::Triangle,
+                )
+                .to_rgba8()
+        };
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &level,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * level_width),
+                rows_per_image: Some(level_height),
+            },
+            wgpu::Extent3d {
+                width: level_width,
+                height: level_height,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = create_material_sampler(device);
+
+    Texture {
+        texture,
+        view,
+        sampler,
+    }
+}
+
+/// Creates a 1x1 texture of a single color
+pub fn create_solid_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    color: [u8; 4],
+    srgb: bool,
+    label: &str,
+) -> Texture {
+    let image = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+        1,
+        1,
+        image::Rgba(color),
+    ));
+    create_texture_from_image(device, queue, &image, srgb, label)
+}
+
+/// Repeating, trilinear sampler for material textures
+pub fn create_material_sampler(device: &wgpu::Device) -> wgpu::Sampler {
+    device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some("material_sampler"),
+        address_mode_u: wgpu::AddressMode::Repeat,
+        address_mode_v: wgpu::AddressMode::Repeat,
+        address_mode_w: wgpu::AddressMode::Repeat,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        mipmap_filter: wgpu::FilterMode::Linear,
+        ..Default::default()
+    })
+}
+
+/// Creates a cube map that can be both rendered into (one face and mip level
+/// at a time) and sampled
+pub fn create_cube_texture(
+    device: &wgpu::Device,
+    size: u32,
+    mip_level_count: u32,
+    format: wgpu::TextureFormat,
+    label: &str,
+) -> Texture {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 6,
+        },
+        mip_level_count,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+            | wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::Cube),
+        ..Default::default()
+    });
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some("cube_sampler"),
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        mipmap_filter: wgpu::FilterMode::Linear,
+        ..Default::default()
     });
 
     Texture {
@@ -51,3 +353,212 @@ pub fn create_depth_texture(
         sampler,
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RenderTargetId(pub(crate) usize);
+
+/// How the size of a `RenderTarget` follows the surface
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResizePolicy {
+    /// Keeps its size when the surface is resized
+    Fixed { width: u32, height: u32 },
+    /// The surface size times `scale`, recreated when the surface is resized
+    Surface { scale: f32 },
+}
+
+impl ResizePolicy {
+    /// The target size for a surface of `width` by `height`, at least one
+    /// pixel on each side. Sizes with a side over `max_dimension`, usually
+    /// `Limits::max_texture_dimension_2d`, are scaled down to fit, keeping
+    /// their aspect ratio.
+    pub fn size(&self, width: u32, height: u32, max_dimension: u32) -> (u32, u32) {
+        let (width, height) = match *self {
+            Self::Fixed { width, height } => (width as f32, height as f32),
+            Self::Surface { scale } => (width as f32 * scale, height as f32 * scale),
+        };
+        let max_dimension = max_dimension.max(1);
+        let fit = (max_dimension as f32 / width.max(height)).min(1.0);
+        let side = |side: f32| ((side * fit).round() as u32).clamp(1, max_dimension);
+        (side(width), side(height))
+    }
+}
+
+/// An offscreen color and depth pair that views are rendered into, see
+/// `View::with_target`. The color is in the HDR format of the scene and can
+/// be put into material textures to be sampled by later passes.
+pub struct RenderTarget {
+    color: Arc<Texture>,
+    depth: Texture,
+    /// Multisampled color resolved into `color`
+    msaa: Option<wgpu::TextureView>,
+    policy: ResizePolicy,
+    size: (u32, u32),
+    sample_count: u32,
+}
+
+impl RenderTarget {
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        policy: ResizePolicy,
+        surface: (u32, u32),
+        sample_count: u32,
+    ) -> Self {
+        let size = policy.size(
+            surface.0,
+            surface.1,
+            device.limits().max_texture_dimension_2d,
+        );
+        let color = Arc::new(create_target_color(device, size));
+        let (depth, msaa) = create_target_attachments(device, size, sample_count);
+        Self {
+            color,
+            depth,
+            msaa,
+            policy,
+            size,
+            sample_count,
+        }
+    }
+
+    /// The color texture, to be used in materials. Resizing replaces it,
+    /// the materials of the renderer are updated to the new one.
+    pub fn color(&self) -> &Arc<Texture> {
+        &self.color
+    }
+
+    pub fn depth(&self) -> &Texture {
+        &self.depth
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    /// Aspect ratio for the cameras rendering into the whole target
+    pub fn aspect_ratio(&self) -> f32 {
+        self.size.0 as f32 / self.size.1 as f32
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    pub fn policy(&self) -> ResizePolicy {
+        self.policy
+    }
+
+    pub(crate) fn set_policy(&mut self, policy: ResizePolicy) {
+        self.policy = policy;
+    }
+
+    /// Where the scene passes draw their color, resolving into `color` when
+    /// multisampled
+    pub(crate) fn color_views(
+        &self,
+    ) -> (&wgpu::TextureView, Option<&wgpu::TextureView>) {
+        match &self.msaa {
+            Some(msaa) => (msaa, Some(&self.color.view)),
+            None => (&self.color.view, None),
+        }
+    }
+
+    /// Recreates the textures that no longer match the surface size or the
+    /// sample count. Returns the previous color texture when it was
+    /// replaced.
+    pub(crate) fn update(
+        &mut self,
+        device: &wgpu::Device,
+        surface: (u32, u32),
+        sample_count: u32,
+    ) -> Option<Arc<Texture>> {
+        let size = self.policy.size(
+            surface.0,
+            surface.1,
+            device.limits().max_texture_dimension_2d,
+        );
+        let mut previous = None;
+        if size != self.size {
+            let color = Arc::new(create_target_color(device, size));
+            previous = Some(std::mem::replace(&mut self.color, color));
+        }
+        if size != self.size || sample_count != self.sample_count {
+            (self.depth, self.msaa) =
+                create_target_attachments(device, size, sample_count);
+        }
+        self.size = size;
+        self.sample_count = sample_count;
+        previous
+    }
+}
+
+fn create_target_color(device: &wgpu::Device, (width, height): (u32, u32)) -> Texture {
+    create_color_target(
+        device,
+        width,
+        height,
+        crate::post::HDR_FORMAT,
+        "render_target_color",
+    )
+}
+
+fn create_target_attachments(
+    device: &wgpu::Device,
+    (width, height): (u32, u32),
+    sample_count: u32,
+) -> (Texture, Option<wgpu::TextureView>) {
+    let depth = create_depth_texture(
+        device,
+        width,
+        height,
+        sample_count,
+        "render_target_depth",
+    );
+    let msaa = (sample_count > 1).then(|| {
+        create_multisampled_framebuffer(
+            device,
+            width,
+            height,
+            crate::post::HDR_FORMAT,
+            sample_count,
+        )
+    });
+    (depth, msaa)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn surface_sizes_are_scaled() {
+        let half = ResizePolicy::Surface { scale: 0.5 };
+        assert_eq!(half.size(800, 601, 8192), (400, 301));
+        let fixed = ResizePolicy::Fixed {
+            width: 256,
+            height: 128,
+        };
+        assert_eq!(fixed.size(800, 600, 8192), (256, 128));
+    }
+
+    #[test]
+    fn sizes_stay_within_the_texture_limits() {
+        let double = ResizePolicy::Surface { scale: 2.0 };
+        assert_eq!(double.size(3840, 2160, 4096), (4096, 2304));
+        assert_eq!(double.size(2160, 3840, 4096), (2304, 4096));
+        assert_eq!(double.size(0, 0, 4096), (1, 1));
+        assert_eq!(
+            ResizePolicy::Surface { scale: 0.0 }.size(800, 600, 4096),
+            (1, 1)
+        );
+        let fixed = ResizePolicy::Fixed {
+            width: 10_000,
+            height: 0,
+        };
+        assert_eq!(fixed.size(800, 600, 8192), (8192, 1));
+        let wide = ResizePolicy::Fixed {
+            width: 16_384,
+            height: 1024,
+        };
+        assert_eq!(wide.size(800, 600, 8192), (8192, 512));
+    }
+}
@@ -1,3 +1,4 @@
+use std::sync::Mutex;
 use std::sync::OnceLock;
 use std::sync::RwLock;
 use std::time::Duration;
@@ -5,6 +6,9 @@ use std::time::Instant;
 
 static T_PROGRAM_START: OnceLock<Instant> = OnceLock::new();
 static T_LAST_FRAME: OnceLock<RwLock<Instant>> = OnceLock::new();
+static FRAME_INTERVAL: RwLock<Option<Duration>> = RwLock::new(None);
+static T_LAST_PACED_FRAME: Mutex<Option<Instant>> = Mutex::new(None);
+static FIXED_DELTA_TIME: RwLock<Option<f32>> = RwLock::new(None);
 
 pub fn startup() {
     let ps_res = T_PROGRAM_START.set(Instant::now());
@@ -42,8 +46,95 @@ pub fn total_elapsed() -> Duration {
     prog_start.elapsed()
 }
 
-/// Returns the elapsed time in seconds since the last frame
+/// Returns the elapsed time in seconds since the last frame, or the fixed
+/// step while one is set
 pub fn delta_time() -> f32 {
-    let last_frame = T_LAST_FRAME.get().unwrap().read().unwrap();
-    last_frame.elapsed().as_secs_f32()
+    if let Some(step) = fixed_delta_time() {
+        return step;
+    }
+    let Some(last_frame) = T_LAST_FRAME.get() else {
+        log::debug!("T_LAST_FRAME was not initialized");
+        return 0.0;
+    };
+    last_frame.read().unwrap().elapsed().as_secs_f32()
+}
+
+/// The longest time `next_frame_time` waits between frames, slower caps are
+/// raised to it
+const MAX_FRAME_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits how often `next_frame_time` lets a frame through. `None` or a
+/// non-positive rate removes the cap.
+pub fn set_frame_rate_cap(frames_per_second: Option<f32>) {
+    let interval = frames_per_second
+        .filter(|fps| *fps > 0.0)
+        .map(frame_interval);
+    match FRAME_INTERVAL.write() {
+        Ok(mut frame_interval) => *frame_interval = interval,
+        Err(e) => log::error!("Could not set the frame rate cap, {}", e),
+    }
+}
+
+/// Time between frames at a positive `frames_per_second`, at most
+/// `MAX_FRAME_INTERVAL`
+fn frame_interval(frames_per_second: f32) -> Duration {
+    match Duration::try_from_secs_f32(1.0 / frames_per_second) {
+        Ok(interval) if interval <= MAX_FRAME_INTERVAL => interval,
+        _ => {
+            log::warn!(
+                "Frame rate cap {frames_per_second} is below one frame every \
+                 {MAX_FRAME_INTERVAL:?}, using that"
+            );
+            MAX_FRAME_INTERVAL
+        }
+    }
+}
+
+/// Returns the frame rate cap in frames per second, if any
+pub fn frame_rate_cap() -> Option<f32> {
+    let interval = *FRAME_INTERVAL.read().ok()?;
+    interval.map(|interval| 1.0 / interval.as_secs_f32())
+}
+
+/// Returns the instant the next frame should start at, or None when frames
+/// are not capped
+pub fn next_frame_time() -> Option<Instant> {
+    let interval = (*FRAME_INTERVAL.read().ok()?)?;
+    let last = (*T_LAST_PACED_FRAME.lock().ok()?)?;
+    Some(last + interval)
+}
+
+/// Records that a frame was started, for `next_frame_time`
+pub fn mark_frame() {
+    if let Ok(mut last) = T_LAST_PACED_FRAME.lock() {
+        *last = Some(Instant::now());
+    }
+}
+
+/// Makes `delta_time` return `seconds` regardless of how long frames take,
+/// so recordings are deterministic. `None` goes back to measured time.
+pub fn set_fixed_delta_time(seconds: Option<f32>) {
+    match FIXED_DELTA_TIME.write() {
+        Ok(mut fixed) => *fixed = seconds,
+        Err(e) => log::error!("Could not set the fixed delta time, {}", e),
+    }
+}
+
+pub fn fixed_delta_time() -> Option<f32> {
+    *FIXED_DELTA_TIME.read().ok()?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiny_frame_rate_caps_are_raised() {
+        // the first one's inverse is infinite
+        for fps in [f32::MIN_POSITIVE / 2.0, 1.0e-30, 0.001] {
+            assert_eq!(frame_interval(fps), MAX_FRAME_INTERVAL);
+        }
+        assert_eq!(frame_interval(4.0), Duration::from_millis(250));
+        assert_eq!(frame_interval(f32::INFINITY), Duration::ZERO);
+    }
 }
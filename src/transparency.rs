@@ -0,0 +1,220 @@
+//! Blending for meshes that are not fully opaque.
+//!
+//! A mesh is transparent when its material blends with `BlendMode::Alpha`,
+//! which `BlendMode::Auto` picks when the base color alpha, or for vertex
+//! colored shaders any vertex alpha, is below 1. Transparent meshes are drawn
+//! after the opaque geometry and the sky with depth writes off.
+use crate::texture::{self, Texture};
+
+/// How overlapping transparent meshes are combined
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransparencyMode {
+    /// Meshes are sorted back to front by the distance from the camera to
+    /// their bounds and alpha blended. Exact unless meshes intersect or
+    /// overlap themselves.
+    #[default]
+    Sorted,
+    /// Weighted blended order-independent transparency. Needs no sorting and
+    /// handles intersecting meshes, but only approximates the blending order.
+    WeightedBlended,
+}
+
+pub(crate) const ACCUM_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+pub(crate) const REVEALAGE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R16Float;
+
+/// Blended color targets of the weighted blended pass: color and alpha are
+/// summed, revealage is multiplied by `1 - alpha`
+pub(crate) fn oit_targets() -> [Option<wgpu::ColorTargetState>; 2] {
+    let add = wgpu::BlendComponent {
+        src_factor: wgpu::BlendFactor::One,
+        dst_factor: wgpu::BlendFactor::One,
+        operation: wgpu::BlendOperation::Add,
+    };
+    let reveal = wgpu::BlendComponent {
+        src_factor: wgpu::BlendFactor::Zero,
+        dst_factor: wgpu::BlendFactor::OneMinusSrc,
+        operation: wgpu::BlendOperation::Add,
+    };
+    [
+        Some(wgpu::ColorTargetState {
+            format: ACCUM_FORMAT,
+            blend: Some(wgpu::BlendState {
+                color: add,
+                alpha: add,
+            }),
+            write_mask: wgpu::ColorWrites::ALL,
+        }),
+        Some(wgpu::ColorTargetState {
+            format: REVEALAGE_FORMAT,
+            blend: Some(wgpu::BlendState {
+                color: reveal,
+                alpha: reveal,
+            }),
+            write_mask: wgpu::ColorWrites::ALL,
+        }),
+    ]
+}
+
+/// Accumulation and revealage targets, resolved from multisampled ones when
+/// MSAA is enabled
+pub(crate) struct OitTargets {
+    accum: Texture,
+    revealage: Texture,
+    msaa: Option<[wgpu::TextureView; 2]>,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl OitTargets {
+    pub fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        width: u32,
+        height: u32,
+        sample_count: u32,
+    ) -> Self {
+        let accum = texture::create_color_target(
+            device,
+            width,
+            height,
+            ACCUM_FORMAT,
+            "oit_accum",
+        );
+        let revealage = texture::create_color_target(
+            device,
+            width,
+            height,
+            REVEALAGE_FORMAT,
+            "oit_revealage",
+        );
+        let msaa = (sample_count > 1).then(|| {
+            [ACCUM_FORMAT, REVEALAGE_FORMAT].map(|format| {
+                texture::create_multisampled_framebuffer(
+                    device,
+                    width,
+                    height,
+                    format,
+                    sample_count,
+                )
+            })
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("oit_bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&accum.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&revealage.view),
+                },
+            ],
+        });
+
+        Self {
+            accum,
+            revealage,
+            msaa,
+            bind_group,
+        }
+    }
+
+    /// Clears revealage to 1, nothing covered yet. This is a pass of its own
+    /// because GLES drivers can only clear the first attachment of a pass.
+    pub fn clear_revealage(&self, encoder: &mut wgpu::CommandEncoder) {
+        let view = match &self.msaa {
+            Some([_, revealage]) => revealage,
+            None => &self.revealage.view,
+        };
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Clear Revealage Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+    }
+
+    /// Attachments of the weighted blended pass. Accumulation is cleared to
+    /// nothing, revealage must be cleared first with `clear_revealage`.
+    pub fn color_attachments(
+        &self,
+    ) -> [Option<wgpu::RenderPassColorAttachment<'_>>; 2] {
+        let resolved = [&self.accum.view, &self.revealage.view];
+        let load = [
+            wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+            wgpu::LoadOp::Load,
+        ];
+        std::array::from_fn(|i| {
+            let (view, resolve_target) = match &self.msaa {
+                Some(msaa) => (&msaa[i], Some(resolved[i])),
+                None => (resolved[i], None),
+            };
+            Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target,
+                ops: wgpu::Operations {
+                    load: load[i],
+                    store: wgpu::StoreOp::Store,
+                },
+            })
+        })
+    }
+}
+
+pub(crate) fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    let texture = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+            view_dimension: wgpu::TextureViewDimension::D2,
+            multisampled: false,
+        },
+        count: None,
+    };
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("oit_bind_group_layout"),
+        entries: &[texture(0), texture(1)],
+    })
+}
+
+/// Blends the averaged transparent color over the scene target
+pub(crate) fn create_composite_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+    multisample: wgpu::MultisampleState,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("oit_composite_pipeline"),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_fullscreen",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_composite",
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample,
+        multiview: None,
+    })
+}
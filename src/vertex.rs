@@ -3,14 +3,20 @@
 pub struct Vertex {
     pub position: [f32; 4],
     pub color: [f32; 4],
+    pub normal: [f32; 3],
+    pub tex_coords: [f32; 2],
 }
 
 unsafe impl bytemuck::Pod for Vertex {}
 unsafe impl bytemuck::Zeroable for Vertex {}
 
 impl Vertex {
-    const ATTRIBS: [wgpu::VertexAttribute; 2] =
-        wgpu::vertex_attr_array![0 => Float32x4, 1 => Float32x4];
+    const ATTRIBS: [wgpu::VertexAttribute; 4] = wgpu::vertex_attr_array![
+        0 => Float32x4,
+        1 => Float32x4,
+        2 => Float32x3,
+        3 => Float32x2
+    ];
 
     pub fn desc() -> wgpu::VertexBufferLayout<'static> {
         use std::mem;
@@ -0,0 +1,317 @@
+//! Several cameras rendered into one frame.
+//!
+//! `Render::render_views` draws each `View` into its `Viewport` of the
+//! frame, in order, for split screen, picture in picture or multi view
+//! layouts, or into a `RenderTarget` that materials sample afterwards. Every
+//! view has its own camera uniform and sorts its transparent meshes from its
+//! own camera, while the shadow cascades follow the first view's camera.
+use crate::camera::CameraView;
+use crate::texture::{self, RenderTargetId};
+
+/// A camera and the part of the frame or render target it is drawn to
+pub struct View<'a> {
+    pub camera: &'a dyn CameraView,
+    /// Relative to the render target when there is one
+    pub viewport: Viewport,
+    /// Draws into a render target instead of the frame. Debug shapes are
+    /// only drawn into the frame, and meshes sampling the target itself are
+    /// left out.
+    pub target: Option<RenderTargetId>,
+    /// Fills the viewport with this color before drawing. The frame and
+    /// every render target are cleared to `Render::clear_color` first, so
+    /// this is only needed for a different color or to cover earlier views.
+    pub clear_color: Option<wgpu::Color>,
+    /// Clears the depth in the viewport before drawing, so the geometry of
+    /// earlier views does not hide this one
+    pub clear_depth: bool,
+    /// Draws the camera with the aspect ratio of the viewport instead of its
+    /// own `aspect_ratio`, so the image is never stretched after a resize
+    pub fit_aspect_ratio: bool,
+}
+
+impl<'a> View<'a> {
+    /// Covers the whole frame, clears the depth and fits the camera to the
+    /// viewport
+    pub fn new(camera: &'a dyn CameraView) -> Self {
+        Self {
+            camera,
+            viewport: Viewport::FULL,
+            target: None,
+            clear_color: None,
+            clear_depth: true,
+            fit_aspect_ratio: true,
+        }
+    }
+
+    pub fn with_viewport(mut self, viewport: Viewport) -> Self {
+        self.viewport = viewport;
+        self
+    }
+
+    pub fn with_target(mut self, target: RenderTargetId) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    pub fn with_clear_color(mut self, color: wgpu::Color) -> Self {
+        self.clear_color = Some(color);
+        self
+    }
+
+    pub fn with_clear_depth(mut self, clear_depth: bool) -> Self {
+        self.clear_depth = clear_depth;
+        self
+    }
+
+    pub fn with_fit_aspect_ratio(mut self, fit: bool) -> Self {
+        self.fit_aspect_ratio = fit;
+        self
+    }
+}
+
+/// A rectangle of the frame in fractions of its size, measured from the top
+/// left corner
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Viewport {
+    pub const FULL: Self = Self::new(0.0, 0.0, 1.0, 1.0);
+
+    pub const fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Cell `column`, `row` of a grid splitting the frame evenly, like
+    /// `grid(2, 1, 1, 0)` for the right half of a split screen
+    pub fn grid(columns: u32, rows: u32, column: u32, row: u32) -> Self {
+        let (width, height) = (1.0 / columns as f32, 1.0 / rows as f32);
+        Self::new(column as f32 * width, row as f32 * height, width, height)
+    }
+
+    /// The pixels covered in a `width` by `height` frame, `None` when there
+    /// are none. Neighbouring viewports share no pixels and leave no gaps.
+    pub fn pixels(&self, width: u32, height: u32) -> Option<PixelRect> {
+        let edge = |fraction: f32, size: u32| {
+            (fraction.clamp(0.0, 1.0) * size as f32).round() as u32
+        };
+        let (left, right) = (edge(self.x, width), edge(self.x + self.width, width));
+        let (top, bottom) = (edge(self.y, height), edge(self.y + self.height, height));
+        (right > left && bottom > top).then(|| PixelRect {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+        })
+    }
+
+    /// Aspect ratio of the viewport in a `width` by `height` frame, for the
+    /// camera drawn to it
+    pub fn aspect_ratio(&self, width: u32, height: u32) -> f32 {
+        match self.pixels(width, height) {
+            Some(rect) => rect.width as f32 / rect.height as f32,
+            None => 1.0,
+        }
+    }
+}
+
+impl Default for Viewport {
+    fn default() -> Self {
+        Self::FULL
+    }
+}
+
+/// A viewport in pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl PixelRect {
+    /// Restricts drawing to the rectangle
+    pub(crate) fn apply(&self, render_pass: &mut wgpu::RenderPass) {
+        render_pass.set_viewport(
+            self.x as f32,
+            self.y as f32,
+            self.width as f32,
+            self.height as f32,
+            0.0,
+            1.0,
+        );
+        render_pass.set_scissor_rect(self.x, self.y, self.width, self.height);
+    }
+}
+
+/// Fullscreen triangles clearing the viewport of a view, since load
+/// operations always clear the whole attachment. The color is set with
+/// `set_blend_constant`.
+pub(crate) struct ClearPipelines {
+    color_depth: wgpu::RenderPipeline,
+    color: wgpu::RenderPipeline,
+    depth: wgpu::RenderPipeline,
+}
+
+impl ClearPipelines {
+    pub fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        multisample: wgpu::MultisampleState,
+    ) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("clear_shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("clear.wgsl").into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("clear_pipeline_layout"),
+            bind_group_layouts: &[],
+            push_constant_ranges: &[],
+        });
+        let create = |label, color: bool, depth: bool| {
+            let constant = wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::Constant,
+                dst_factor: wgpu::BlendFactor::Zero,
+                operation: wgpu::BlendOperation::Add,
+            };
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_clear",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_clear",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format,
+                        blend: color.then_some(wgpu::BlendState {
+                            color: constant,
+                            alpha: constant,
+                        }),
+                        write_mask: if color {
+                            wgpu::ColorWrites::ALL
+                        } else {
+                            wgpu::ColorWrites::empty()
+                        },
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: texture::DEPTH_FORMAT,
+                    depth_write_enabled: depth,
+                    depth_compare: wgpu::CompareFunction::Always,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample,
+                multiview: None,
+            })
+        };
+
+        Self {
+            color_depth: create("clear_color_depth_pipeline", true, true),
+            color: create("clear_color_pipeline", true, false),
+            depth: create("clear_depth_pipeline", false, true),
+        }
+    }
+
+    /// Clears the current viewport of the pass to `color` and the far plane
+    /// depth, either of which may be skipped
+    pub fn draw<'p>(
+        &'p self,
+        render_pass: &mut wgpu::RenderPass<'p>,
+        color: Option<wgpu::Color>,
+        depth: bool,
+    ) {
+        let pipeline = match (color, depth) {
+            (Some(_), true) => &self.color_depth,
+            (Some(_), false) => &self.color,
+            (None, true) => &self.depth,
+            (None, false) => return,
+        };
+        render_pass.set_pipeline(pipeline);
+        if let Some(color) = color {
+            render_pass.set_blend_constant(color);
+        }
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How often each pixel of a `width` by `height` frame is covered by a
+    /// `columns` by `rows` grid
+    fn coverage(columns: u32, rows: u32, width: u32, height: u32) -> Vec<u32> {
+        let mut covered = vec![0; (width * height) as usize];
+        for row in 0..rows {
+            for column in 0..columns {
+                let viewport = Viewport::grid(columns, rows, column, row);
+                let Some(rect) = viewport.pixels(width, height) else {
+                    continue;
+                };
+                for y in rect.y..rect.y + rect.height {
+                    for x in rect.x..rect.x + rect.width {
+                        covered[(y * width + x) as usize] += 1;
+                    }
+                }
+            }
+        }
+        covered
+    }
+
+    #[test]
+    fn grid_cells_cover_every_pixel_once() {
+        for (width, height) in [(7, 5), (101, 33), (1, 1), (2, 3)] {
+            let covered = coverage(2, 2, width, height);
+            assert!(covered.iter().all(|&n| n == 1), "{width}x{height}");
+        }
+        for size in 1..64 {
+            let covered = coverage(3, 3, size, size + 1);
+            assert!(covered.iter().all(|&n| n == 1), "{size}x{}", size + 1);
+        }
+    }
+
+    #[test]
+    fn viewports_are_clamped_to_the_frame() {
+        let overhanging = Viewport::new(-0.5, 0.5, 1.0, 1.0);
+        assert_eq!(
+            overhanging.pixels(100, 50),
+            Some(PixelRect {
+                x: 0,
+                y: 25,
+                width: 50,
+                height: 25,
+            })
+        );
+        assert_eq!(Viewport::new(1.5, 0.0, 0.5, 1.0).pixels(100, 50), None);
+    }
+
+    #[test]
+    fn empty_viewports_have_no_pixels() {
+        let empty = Viewport::new(0.25, 0.25, 0.0, 0.5);
+        assert_eq!(empty.pixels(100, 100), None);
+        assert_eq!(empty.aspect_ratio(100, 100), 1.0);
+        // too small to cover a pixel
+        let tiny = Viewport::new(0.0, 0.0, 0.001, 1.0);
+        assert_eq!(tiny.pixels(100, 100), None);
+        assert_eq!(tiny.aspect_ratio(100, 100), 1.0);
+        assert_eq!(Viewport::FULL.pixels(0, 0), None);
+
+        assert_eq!(Viewport::grid(2, 1, 1, 0).aspect_ratio(200, 100), 1.0);
+    }
+}
@@ -0,0 +1,201 @@
+//! Creating the window and switching it between windowed and fullscreen.
+//!
+//! `WindowConfig` describes the window an application starts with, and
+//! `toggle_fullscreen`, `grab_cursor` and `release_cursor` change it while
+//! the application runs.
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+use winit::dpi::{LogicalSize, Size};
+use winit::event_loop::EventLoopWindowTarget;
+use winit::monitor::MonitorHandle;
+use winit::window::{CursorGrabMode, Fullscreen, Icon, Window, WindowBuilder};
+
+/// How a fullscreen window covers its monitor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FullscreenMode {
+    /// A borderless window the size of the monitor, which keeps the desktop
+    /// resolution and switches quickly
+    Borderless,
+    /// Takes over the monitor with its largest video mode
+    Exclusive,
+}
+
+/// Which monitor a fullscreen window goes to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MonitorSelection {
+    #[default]
+    Primary,
+    /// Index into the available monitors, falling back to the primary one
+    /// when there are fewer
+    Index(usize),
+}
+
+#[derive(Clone, Debug)]
+pub struct WindowConfig {
+    pub title: String,
+    /// Size of the window when it is not fullscreen
+    pub size: Size,
+    pub resizable: bool,
+    /// Starts fullscreen in this mode, windowed when `None`
+    pub fullscreen: Option<FullscreenMode>,
+    pub monitor: MonitorSelection,
+    /// Applied once the window is created. Platforms without `Locked`
+    /// confine the cursor instead, and the other way around.
+    pub cursor_grab: CursorGrabMode,
+    pub cursor_visible: bool,
+    /// An image file for the window icon, where the platform shows one
+    pub icon: Option<PathBuf>,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            title: "rust_graphics".to_owned(),
+            size: Size::Logical(LogicalSize::new(800.0, 600.0)),
+            resizable: true,
+            fullscreen: None,
+            monitor: MonitorSelection::default(),
+            cursor_grab: CursorGrabMode::None,
+            cursor_visible: true,
+            icon: None,
+        }
+    }
+}
+
+impl WindowConfig {
+    pub fn with_title(mut self, title: impl Into<String>) -> Self {
+        self.title = title.into();
+        self
+    }
+
+    pub fn with_size(mut self, size: impl Into<Size>) -> Self {
+        self.size = size.into();
+        self
+    }
+
+    pub fn with_resizable(mut self, resizable: bool) -> Self {
+        self.resizable = resizable;
+        self
+    }
+
+    pub fn with_fullscreen(mut self, fullscreen: Option<FullscreenMode>) -> Self {
+        self.fullscreen = fullscreen;
+        self
+    }
+
+    pub fn with_monitor(mut self, monitor: MonitorSelection) -> Self {
+        self.monitor = monitor;
+        self
+    }
+
+    pub fn with_cursor_grab(mut self, mode: CursorGrabMode) -> Self {
+        self.cursor_grab = mode;
+        self
+    }
+
+    pub fn with_cursor_visible(mut self, visible: bool) -> Self {
+        self.cursor_visible = visible;
+        self
+    }
+
+    pub fn with_icon(mut self, path: impl Into<PathBuf>) -> Self {
+        self.icon = Some(path.into());
+        self
+    }
+
+    /// Creates the window. A cursor grab the platform refuses is logged
+    /// instead of failing.
+    pub fn build<T>(
+        &self,
+        target: &EventLoopWindowTarget<T>,
+    ) -> anyhow::Result<Window> {
+        let monitor = match self.monitor {
+            MonitorSelection::Primary => None,
+            MonitorSelection::Index(index) => target.available_monitors().nth(index),
+        }
+        .or_else(|| target.primary_monitor())
+        .or_else(|| target.available_monitors().next());
+        let icon = self.icon.as_deref().map(load_icon).transpose()?;
+
+        let window = WindowBuilder::new()
+            .with_title(&self.title)
+            .with_inner_size(self.size)
+            .with_resizable(self.resizable)
+            .with_fullscreen(self.fullscreen.and_then(|mode| fullscreen(mode, monitor)))
+            .with_window_icon(icon)
+            .build(target)
+            .context("Could not create the window")?;
+
+        if self.cursor_grab != CursorGrabMode::None {
+            if let Err(e) = grab_cursor(&window, self.cursor_grab) {
+                log::warn!("{e:#}");
+            }
+        }
+        window.set_cursor_visible(self.cursor_visible);
+        Ok(window)
+    }
+}
+
+/// The fullscreen state for `mode` on `monitor`, `None` for exclusive
+/// fullscreen without a monitor or video mode to use
+pub fn fullscreen(
+    mode: FullscreenMode,
+    monitor: Option<MonitorHandle>,
+) -> Option<Fullscreen> {
+    match mode {
+        FullscreenMode::Borderless => Some(Fullscreen::Borderless(monitor)),
+        FullscreenMode::Exclusive => monitor?
+            .video_modes()
+            .max_by_key(|video_mode| {
+                let size = video_mode.size();
+                (
+                    size.width * size.height,
+                    video_mode.bit_depth(),
+                    video_mode.refresh_rate_millihertz(),
+                )
+            })
+            .map(Fullscreen::Exclusive),
+    }
+}
+
+/// Switches between windowed and fullscreen in `mode` on the monitor the
+/// window is on, for a shortcut like Alt+Enter
+pub fn toggle_fullscreen(window: &Window, mode: FullscreenMode) {
+    if window.fullscreen().is_some() {
+        window.set_fullscreen(None);
+    } else {
+        window.set_fullscreen(fullscreen(mode, window.current_monitor()));
+    }
+}
+
+/// Grabs the cursor, trying the other grabbing mode when the platform does
+/// not support `mode`: X11 and Windows only confine the cursor, macOS only
+/// locks it
+pub fn grab_cursor(window: &Window, mode: CursorGrabMode) -> anyhow::Result<()> {
+    let fallback = match mode {
+        CursorGrabMode::Locked => CursorGrabMode::Confined,
+        CursorGrabMode::Confined => CursorGrabMode::Locked,
+        CursorGrabMode::None => CursorGrabMode::None,
+    };
+    window
+        .set_cursor_grab(mode)
+        .or_else(|_| window.set_cursor_grab(fallback))
+        .with_context(|| format!("Could not grab the cursor with {mode:?}"))
+}
+
+/// Lets the cursor leave the window and shows it again
+pub fn release_cursor(window: &Window) {
+    if let Err(e) = window.set_cursor_grab(CursorGrabMode::None) {
+        log::warn!("Could not release the cursor: {e}");
+    }
+    window.set_cursor_visible(true);
+}
+
+fn load_icon(path: &Path) -> anyhow::Result<Icon> {
+    let image = image::open(path)
+        .with_context(|| format!("Could not read the window icon {}", path.display()))?
+        .to_rgba8();
+    let (width, height) = image.dimensions();
+    Icon::from_rgba(image.into_raw(), width, height)
+        .with_context(|| format!("{} is not a usable window icon", path.display()))
+}
@@ -0,0 +1,84 @@
+//! `run_headless` drives an `App` like `run` does, without a window, so apps
+//! can be tested. Skipped without a software adapter.
+use glam::Vec3;
+use rust_graphics::app::{self, App, AppConfig, Context};
+use rust_graphics::render::NoAdapter;
+use rust_graphics::view::View;
+use winit::dpi::PhysicalSize;
+
+#[derive(Default)]
+struct Counter {
+    inits: u32,
+    updates: u32,
+    renders: u32,
+    /// Size of the frame captured on the second render
+    captured: Option<(u32, u32)>,
+    exit_after: Option<u32>,
+}
+
+impl App for Counter {
+    fn init(&mut self, ctx: &mut Context) -> anyhow::Result<()> {
+        self.inits += 1;
+        ctx.camera.transform.translation = Vec3::new(0.0, 0.0, -3.0);
+        Ok(())
+    }
+
+    fn update(&mut self, ctx: &mut Context) {
+        self.updates += 1;
+        if self.exit_after == Some(self.updates) {
+            ctx.exit();
+        }
+    }
+
+    fn render(&mut self, ctx: &mut Context) -> Result<(), wgpu::SurfaceError> {
+        self.renders += 1;
+        if self.renders == 2 {
+            ctx.render.capture_frame();
+        }
+        ctx.render.render_views(&[View::new(&*ctx.camera)], &[])?;
+        if let Some(capture) = ctx.render.take_capture() {
+            self.captured = Some(capture.unwrap().dimensions());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn headless_apps_run_every_hook() {
+    let Some(app) = run(Counter::default(), 3) else {
+        return;
+    };
+    assert_eq!(app.inits, 1);
+    assert_eq!(app.updates, 3);
+    assert_eq!(app.renders, 3);
+    assert_eq!(app.captured, Some((96, 64)));
+}
+
+#[test]
+fn headless_apps_can_exit() {
+    let counter = Counter {
+        exit_after: Some(2),
+        ..Default::default()
+    };
+    let Some(app) = run(counter, 10) else {
+        return;
+    };
+    assert_eq!(app.updates, 2);
+    assert_eq!(app.renders, 1);
+}
+
+/// Runs `app` in a small window's size on the software adapter, `None`
+/// without one
+fn run(app: Counter, frames: u32) -> Option<Counter> {
+    let mut config = AppConfig::default();
+    config.window.size = PhysicalSize::new(96, 64).into();
+    config.render.force_fallback_adapter = true;
+    match app::run_headless(app, config, frames) {
+        Ok(app) => Some(app),
+        Err(e) if e.is::<NoAdapter>() => {
+            eprintln!("{e:#}, skipping");
+            None
+        }
+        Err(e) => panic!("{e:#}"),
+    }
+}
@@ -0,0 +1,186 @@
+//! Golden image tests: every asset is rendered headless on a software adapter
+//! and compared against `tests/golden/<asset>.png`.
+//!
+//! Run with `GOLDEN_BLESS=1` to write new references instead of comparing.
+//! On a mismatch the rendered frame and a diff image are written to
+//! `target/tmp/golden/`. Without a software adapter the tests are skipped.
+use glam::{Quat, Vec3, Vec4};
+use image::{Rgba, RgbaImage};
+use rust_graphics::camera::{Camera, Perspective};
+use rust_graphics::material::PbrMaterial;
+use rust_graphics::mesh::{LoadOptions, Mesh};
+use rust_graphics::render::{NoAdapter, Render};
+use rust_graphics::transform::Transform;
+use std::path::{Path, PathBuf};
+
+const WIDTH: u32 = 256;
+const HEIGHT: u32 = 256;
+/// Per pixel YIQ color difference, as a fraction of the largest possible one
+const PIXEL_THRESHOLD: f32 = 0.1;
+/// Fraction of pixels that may differ, for rasterization differences
+/// between drivers
+const MAX_DIFFERENT_PIXELS: f32 = 0.005;
+
+#[test]
+fn golden_cube() {
+    check("cube");
+}
+
+#[test]
+fn golden_suzanne() {
+    check("suzanne");
+}
+
+#[test]
+fn golden_teapot() {
+    check("teapot");
+}
+
+#[test]
+fn golden_alligator() {
+    check("alligator");
+}
+
+#[test]
+fn golden_untitled() {
+    check("untitled");
+}
+
+fn check(asset: &str) {
+    let Some(rendered) = render_asset(asset) else {
+        eprintln!("No software adapter available, skipping the {asset} golden test");
+        return;
+    };
+
+    let reference_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{asset}.png"));
+    if std::env::var_os("GOLDEN_BLESS").is_some() {
+        rendered.save(&reference_path).unwrap();
+        return;
+    }
+    let reference = match image::open(&reference_path) {
+        Ok(reference) => reference.to_rgba8(),
+        Err(e) => panic!(
+            "Could not read {}: {e}. Run with GOLDEN_BLESS=1 to create it.",
+            reference_path.display()
+        ),
+    };
+
+    let (different, diff) = compare(&reference, &rendered);
+    let allowed = (MAX_DIFFERENT_PIXELS * (WIDTH * HEIGHT) as f32) as u32;
+    if different > allowed {
+        let out_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("golden");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let actual_path = out_dir.join(format!("{asset}.actual.png"));
+        let diff_path = out_dir.join(format!("{asset}.diff.png"));
+        rendered.save(&actual_path).unwrap();
+        diff.save(&diff_path).unwrap();
+        panic!(
+            "{asset}: {different} pixels differ from the reference (allowed {allowed}), \
+             see {} and {}",
+            actual_path.display(),
+            diff_path.display()
+        );
+    }
+}
+
+/// Renders one frame of `assets/<asset>.obj`, fitted into view, or `None`
+/// when there is no software adapter
+fn render_asset(asset: &str) -> Option<RgbaImage> {
+    let mut render = match pollster::block_on(
+        Render::headless(WIDTH, HEIGHT)
+            .force_fallback_adapter(true)
+            .build(),
+    ) {
+        Ok(render) => render,
+        Err(e) if e.is::<NoAdapter>() => {
+            eprintln!("{e:#}");
+            return None;
+        }
+        Err(e) => panic!("Could not build the renderer: {e:#}"),
+    };
+
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("assets")
+        .join(format!("{asset}.obj"));
+    let material =
+        render.add_material(PbrMaterial::new(Vec4::new(0.8, 0.8, 0.8, 1.0), 0.0, 0.5));
+    let mut mesh = Mesh::load(path, LoadOptions::default())
+        .unwrap()
+        .with_material(material);
+    fit_to_unit_box(&mut mesh);
+
+    let mut camera = Camera::new(
+        60.0,
+        WIDTH as f32 / HEIGHT as f32,
+        Perspective,
+        Transform::from_translation(Vec3::new(0.0, 2.0, -3.5)),
+    );
+    camera.pitch = -(2.0_f32 / 3.5).atan();
+
+    render.capture_frame();
+    render.render(&camera, &[mesh]).unwrap();
+    Some(render.take_capture().unwrap().unwrap())
+}
+
+/// Centers the mesh on the origin, scales its largest extent to 2 and turns
+/// it a little so three sides are visible
+fn fit_to_unit_box(mesh: &mut Mesh) {
+    let (min, max) = mesh.vertices.iter().fold(
+        (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+        |(min, max), vertex| {
+            let position = Vec3::from_slice(&vertex.position);
+            (min.min(position), max.max(position))
+        },
+    );
+    let scale = 2.0 / (max - min).max_element();
+    let rotation = Quat::from_rotation_y(30.0_f32.to_radians());
+    mesh.transform.scale = Vec3::splat(scale);
+    mesh.transform.rotation = rotation;
+    mesh.transform.translation = rotation * -(min + max) * 0.5 * scale;
+}
+
+/// Counts the pixels whose perceptual difference is above the threshold
+/// and draws them in red over a faded copy of the reference
+fn compare(reference: &RgbaImage, rendered: &RgbaImage) -> (u32, RgbaImage) {
+    if reference.dimensions() != rendered.dimensions() {
+        panic!(
+            "Reference is {:?} but the frame is {:?}",
+            reference.dimensions(),
+            rendered.dimensions()
+        );
+    }
+    // largest possible `color_delta`, between black and white
+    let max_delta = 35215.0 * PIXEL_THRESHOLD * PIXEL_THRESHOLD;
+    let mut different = 0;
+    let diff = RgbaImage::from_fn(reference.width(), reference.height(), |x, y| {
+        let a = reference.get_pixel(x, y);
+        let b = rendered.get_pixel(x, y);
+        if color_delta(a, b) > max_delta {
+            different += 1;
+            Rgba([255, 0, 0, 255])
+        } else {
+            let gray = (luma(a) * 0.25 + 191.0) as u8;
+            Rgba([gray, gray, gray, 255])
+        }
+    });
+    (different, diff)
+}
+
+fn luma(pixel: &Rgba<u8>) -> f32 {
+    let [r, g, b, _] = pixel.0.map(f32::from);
+    r * 0.2988953 + g * 0.5866225 + b * 0.1144822
+}
+
+/// Squared difference in the YIQ color space, weighted by how visible each
+/// channel is (Kotsarenko and Ramos 2010)
+fn color_delta(a: &Rgba<u8>, b: &Rgba<u8>) -> f32 {
+    let [r1, g1, b1, _] = a.0.map(f32::from);
+    let [r2, g2, b2, _] = b.0.map(f32::from);
+    let (r, g, b) = (r1 - r2, g1 - g2, b1 - b2);
+    let y = r * 0.2988953 + g * 0.5866225 + b * 0.1144822;
+    let i = r * 0.595978 - g * 0.2741761 - b * 0.3218019;
+    let q = r * 0.2114702 - g * 0.5226171 + b * 0.3111469;
+    0.5053 * y * y + 0.299 * i * i + 0.1957 * q * q
+}
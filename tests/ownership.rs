@@ -0,0 +1,128 @@
+//! `Render` holds its windows through an `Arc` and borrows nothing, so it can
+//! be moved, stored in other structs and dropped before or after the windows
+//! it presents to.
+//!
+//! The window test is skipped without a display, and every test is skipped
+//! without an adapter.
+use glam::Vec3;
+use rust_graphics::camera::{Camera, Perspective};
+use rust_graphics::render::{NoAdapter, OutputId, Render, RenderBuilder};
+use rust_graphics::transform::Transform;
+use rust_graphics::view::View;
+use std::sync::Arc;
+use winit::dpi::PhysicalSize;
+use winit::event_loop::EventLoop;
+use winit::window::WindowBuilder;
+
+/// Stores a renderer like an application would, without a lifetime
+struct Viewer {
+    render: Render,
+}
+
+#[test]
+fn renderers_and_windows_drop_in_any_order() {
+    let Some(event_loop) = event_loop() else {
+        eprintln!("No display available, skipping the drop order test");
+        return;
+    };
+    let window = || {
+        let window = WindowBuilder::new()
+            .with_visible(false)
+            .with_inner_size(PhysicalSize::new(64, 64))
+            .build(&event_loop)
+            .unwrap();
+        Arc::new(window)
+    };
+
+    // the renderer is dropped before the window
+    let main = window();
+    let Some(mut render) = build(Render::builder(main.clone())) else {
+        return;
+    };
+    draw(&mut render, OutputId::PRIMARY);
+    drop(render);
+    drop(main);
+
+    // the caller drops the window first, the renderer keeps it alive
+    let main = window();
+    let Some(render) = build(Render::builder(main.clone())) else {
+        return;
+    };
+    drop(main);
+    let mut viewer = Box::new(Viewer { render });
+    draw(&mut viewer.render, OutputId::PRIMARY);
+
+    // the same for a second window, which is removed before the renderer
+    // is dropped
+    let preview = window();
+    let id = viewer.render.add_window(preview.clone()).unwrap();
+    drop(preview);
+    draw(&mut viewer.render, id);
+    viewer.render.remove_window(id).unwrap();
+    draw(&mut viewer.render, OutputId::PRIMARY);
+    drop(viewer);
+}
+
+#[test]
+fn headless_renderers_can_be_moved() {
+    let Some(render) = build(Render::headless(64, 64)) else {
+        return;
+    };
+    let mut viewers: Vec<Viewer> = vec![Viewer { render }];
+    let mut viewer = viewers.pop().unwrap();
+    viewer.render.capture_frame();
+    draw(&mut viewer.render, OutputId::PRIMARY);
+    assert!(viewer.render.take_capture().unwrap().is_ok());
+}
+
+/// An event loop that may be created on a test thread, `None` without a
+/// display
+#[cfg(any(
+    windows,
+    all(unix, not(any(target_os = "macos", target_os = "android")))
+))]
+fn event_loop() -> Option<EventLoop<()>> {
+    #[cfg(windows)]
+    use winit::platform::windows::EventLoopBuilderExtWindows;
+    #[cfg(unix)]
+    use winit::platform::x11::EventLoopBuilderExtX11;
+
+    winit::event_loop::EventLoopBuilder::new()
+        .with_any_thread(true)
+        .build()
+        .ok()
+}
+
+/// Only the main thread may create an event loop on the other platforms
+#[cfg(not(any(
+    windows,
+    all(unix, not(any(target_os = "macos", target_os = "android")))
+)))]
+fn event_loop() -> Option<EventLoop<()>> {
+    None
+}
+
+fn build(builder: RenderBuilder) -> Option<Render> {
+    match pollster::block_on(builder.force_fallback_adapter(true).build()) {
+        Ok(render) => Some(render),
+        Err(e) if e.is::<NoAdapter>() => {
+            eprintln!("{e:#}, skipping");
+            None
+        }
+        Err(e) => panic!("Could not build the renderer: {e:#}"),
+    }
+}
+
+/// Renders one frame of `output`. Hidden windows may report outdated
+/// surfaces, which are not what these tests check.
+fn draw(render: &mut Render, output: OutputId) {
+    let camera = Camera::new(
+        60.0,
+        1.0,
+        Perspective,
+        Transform::from_translation(Vec3::new(0.0, 0.0, -3.0)),
+    );
+    if let Err(e) = render.render_output(output, &[View::new(&camera)], &[]) {
+        eprintln!("Skipped a frame: {e:?}");
+    }
+}
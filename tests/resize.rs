@@ -0,0 +1,71 @@
+//! Resizing recreates every size dependent texture of an output, from
+//! multisampled attachments to the post-processing targets, and zero sizes
+//! only skip frames. Skipped without a software adapter.
+use glam::{Vec3, Vec4};
+use rust_graphics::camera::{Camera, Perspective};
+use rust_graphics::material::PbrMaterial;
+use rust_graphics::mesh::Mesh;
+use rust_graphics::post::PostChain;
+use rust_graphics::render::{NoAdapter, OutputId, Render};
+use rust_graphics::transform::Transform;
+use rust_graphics::vertex::Vertex;
+use rust_graphics::view::View;
+use winit::dpi::PhysicalSize;
+
+#[test]
+fn resized_outputs_render_at_the_new_size() {
+    let builder = Render::headless(64, 48)
+        .force_fallback_adapter(true)
+        .sample_count(4)
+        .post_chain(PostChain::default());
+    let mut render = match pollster::block_on(builder.build()) {
+        Ok(render) => render,
+        Err(e) if e.is::<NoAdapter>() => {
+            eprintln!("{e:#}, skipping");
+            return;
+        }
+        Err(e) => panic!("Could not build the renderer: {e:#}"),
+    };
+    let material = render.add_material(PbrMaterial::new(Vec4::ONE, 0.0, 0.5));
+    let meshes = [quad().with_material(material)];
+    let camera = Camera::new(
+        60.0,
+        64.0 / 48.0,
+        Perspective,
+        Transform::from_translation(Vec3::new(0.0, 0.0, -3.0)),
+    );
+    let views = [View::new(&camera)];
+
+    render.resize_output(OutputId::PRIMARY, PhysicalSize::new(0, 0));
+    assert!(!render.is_drawable());
+    render.capture_frame();
+    render.render_views(&views, &meshes).unwrap();
+    assert!(render.take_capture().is_none());
+
+    render.resize_output(OutputId::PRIMARY, PhysicalSize::new(96, 40));
+    assert!(render.is_drawable());
+    assert_eq!(render.size(), PhysicalSize::new(96, 40));
+    render.capture_frame();
+    render.render_views(&views, &meshes).unwrap();
+    let frame = render.take_capture().unwrap().unwrap();
+    assert_eq!(frame.dimensions(), (96, 40));
+    // the quad is drawn in the middle, the corners show the background
+    assert_ne!(frame.get_pixel(48, 20), frame.get_pixel(0, 0));
+}
+
+/// A white square facing the camera
+fn quad() -> Mesh {
+    let vertex = |x: f32, y: f32| Vertex {
+        position: [x, y, 0.0, 1.0],
+        color: [1.0; 4],
+        normal: [0.0, 0.0, -1.0],
+        tex_coords: [0.0; 2],
+    };
+    let vertices = [
+        vertex(-1.0, -1.0),
+        vertex(1.0, -1.0),
+        vertex(1.0, 1.0),
+        vertex(-1.0, 1.0),
+    ];
+    Mesh::new(&vertices, &[0, 1, 2, 0, 2, 3, 0, 2, 1, 0, 3, 2])
+}